@@ -0,0 +1,256 @@
+//! Load generator - many lightweight clients speaking p2pmsg protocol against one node
+
+#[macro_use]
+extern crate log;
+
+use futures::{SinkExt, StreamExt};
+use p2pmsg_lib::error::Error;
+use p2pmsg_lib::protocol::codec::MsgCodec;
+use p2pmsg_lib::protocol::id::RawId;
+use p2pmsg_lib::protocol::message::{Message, PeerInfo, PROTOCOL_VERSION};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::Decoder;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Pause before reconnecting, so failing node is not hammered by reconnects
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy)]
+enum Behavior {
+    /// Stays connected, just answers pings
+    Idle,
+    /// Sends raw frames at given rate
+    Chatty,
+    /// Breaks protocol in various ways and reconnects
+    Malicious,
+}
+
+impl std::str::FromStr for Behavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(Behavior::Idle),
+            "chatty" => Ok(Behavior::Chatty),
+            "malicious" => Ok(Behavior::Malicious),
+            _ => Err(format!("Invalid behavior {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(author, about = "Load generator - many lightweight clients against one node")]
+struct Args {
+    /// Address of node under test
+    target: SocketAddr,
+    /// Number of clients
+    #[structopt(short, long, default_value = "100")]
+    clients: usize,
+    /// What clients do, can be given several times to mix behaviors, clients are assigned round robin
+    #[structopt(short, long, default_value = "chatty", possible_values = &["idle", "chatty", "malicious"], number_of_values = 1)]
+    behavior: Vec<Behavior>,
+    /// Messages per second sent by each chatty client
+    #[structopt(short, long, default_value = "10")]
+    rate: f64,
+    /// Size of raw frame data in bytes
+    #[structopt(short, long, default_value = "100")]
+    size: usize,
+    /// Raw protocol of sent frames
+    #[structopt(long, default_value = "loadgen")]
+    protocol: String,
+    /// Test duration in seconds
+    #[structopt(short, long, default_value = "10")]
+    duration: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    connected: AtomicUsize,
+    connects: AtomicU64,
+    sent: AtomicU64,
+    bytes: AtomicU64,
+    received: AtomicU64,
+    errors: AtomicU64,
+    /// Connections closed by node, expected for malicious clients
+    rejected: AtomicU64,
+}
+
+impl Stats {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+fn hello(target: SocketAddr) -> Message {
+    Message::Hello {
+        msg: "loadgen".into(),
+        info: PeerInfo {
+            id: RawId::random().into(),
+            addrs: vec![],
+            name: Some("loadgen".into()),
+            uses_nat: false,
+            udp_port: None,
+        },
+        observed_addr: target,
+        version: Some(PROTOCOL_VERSION),
+        timestamp: None,
+    }
+}
+
+/// Behaves well until connection is closed
+async fn run_session(args: &Args, behavior: Behavior, stats: &Stats) -> Result<(), Error> {
+    let stream = TcpStream::connect(args.target).await?;
+    let mut framed = MsgCodec::new().framed(stream);
+    match framed.next().await {
+        Some(Ok(Message::Hello { .. })) => (),
+        Some(Ok(m)) => return Err(format!("Expected Hello, got {}", m.kind()).into()),
+        Some(Err(e)) => return Err(e),
+        None => return Err("Closed before Hello".into()),
+    }
+    framed.send(hello(args.target)).await?;
+    Stats::add(&stats.connects, 1);
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+    let (mut sink, mut stream) = framed.split();
+    let (pong_tx, mut pong_rx) = tokio::sync::mpsc::unbounded_channel();
+    let receiving = async move {
+        while let Some(msg) = stream.next().await {
+            Stats::add(&stats.received, 1);
+            match msg? {
+                Message::Ping => {
+                    pong_tx.send(Message::Pong).ok();
+                }
+                Message::PingSeq { seq } => {
+                    pong_tx.send(Message::PongSeq { seq }).ok();
+                }
+                Message::Terminate => break,
+                _ => (),
+            }
+        }
+        Ok::<_, Error>(())
+    };
+    let sending = async move {
+        let interval = match behavior {
+            Behavior::Chatty if args.rate > 0.0 => Duration::from_secs_f64(1.0 / args.rate),
+            _ => Duration::from_secs(3600),
+        };
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                pong = pong_rx.recv() => match pong {
+                    Some(pong) => sink.send(pong).await?,
+                    None => break,
+                },
+                _ = ticker.tick() => {
+                    let msg = Message::Raw {
+                        protocol: args.protocol.clone(),
+                        data: vec![0; args.size],
+                    };
+                    sink.send(msg).await?;
+                    Stats::add(&stats.sent, 1);
+                    Stats::add(&stats.bytes, args.size as u64);
+                }
+            }
+        }
+        Ok::<_, Error>(())
+    };
+    let res = tokio::select! {
+        r = receiving => r,
+        r = sending => r,
+    };
+    stats.connected.fetch_sub(1, Ordering::Relaxed);
+    res
+}
+
+/// One protocol violation per connection, node should close connection
+async fn run_malicious(args: &Args, attempt: usize, stats: &Stats) -> Result<(), Error> {
+    let mut stream = TcpStream::connect(args.target).await?;
+    Stats::add(&stats.connects, 1);
+    let data: Vec<u8> = match attempt % 3 {
+        0 => b"{not json\n".to_vec(),
+        1 => b"\"Ping\"\n".to_vec(),
+        // never finishes handshake
+        _ => b"{\"Hel".to_vec(),
+    };
+    // node can close connection before we write everything
+    let _ = stream.write_all(&data).await;
+    Stats::add(&stats.sent, 1);
+    let mut buf = vec![0; 4096];
+    loop {
+        match tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await {
+            Ok(0) | Err(_) => return Ok(()),
+            Ok(_) => Stats::add(&stats.received, 1),
+        }
+    }
+}
+
+async fn run_client(args: Arc<Args>, behavior: Behavior, stats: Arc<Stats>) {
+    let mut attempt = 0;
+    loop {
+        let res = match behavior {
+            Behavior::Malicious => run_malicious(&args, attempt, &stats).await,
+            _ => run_session(&args, behavior, &stats).await,
+        };
+        match res {
+            Ok(()) => Stats::add(&stats.rejected, 1),
+            Err(e) => {
+                debug!("Client error: {}", e);
+                Stats::add(&stats.errors, 1)
+            }
+        }
+        attempt += 1;
+        tokio::time::delay_for(RECONNECT_DELAY).await;
+    }
+}
+
+fn report(stats: &Stats, elapsed: Duration, prev_sent: u64) -> u64 {
+    let sent = stats.sent.load(Ordering::Relaxed);
+    println!(
+        "{:>5.1}s connected {:>5}  sent {:>8} ({:>7.0}/s)  received {:>8}  connects {:>6}  closed by node {:>6}  errors {:>6}",
+        elapsed.as_secs_f64(),
+        stats.connected.load(Ordering::Relaxed),
+        sent,
+        (sent - prev_sent) as f64 / REPORT_INTERVAL.as_secs_f64(),
+        stats.received.load(Ordering::Relaxed),
+        stats.connects.load(Ordering::Relaxed),
+        stats.rejected.load(Ordering::Relaxed),
+        stats.errors.load(Ordering::Relaxed),
+    );
+    sent
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let args = Arc::new(Args::from_args());
+    let stats = Arc::new(Stats::default());
+    let start = Instant::now();
+    for i in 0..args.clients {
+        let behavior = args.behavior[i % args.behavior.len()];
+        tokio::spawn(run_client(args.clone(), behavior, stats.clone()));
+    }
+    let duration = Duration::from_secs(args.duration);
+    let mut ticker = tokio::time::interval(REPORT_INTERVAL);
+    ticker.tick().await;
+    let mut prev_sent = 0;
+    while start.elapsed() < duration {
+        ticker.tick().await;
+        prev_sent = report(&stats, start.elapsed(), prev_sent);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let sent = stats.sent.load(Ordering::Relaxed);
+    println!(
+        "Total: {} messages ({} bytes) in {:.1}s, {:.0} msg/s, {} errors",
+        sent,
+        stats.bytes.load(Ordering::Relaxed),
+        elapsed,
+        sent as f64 / elapsed,
+        stats.errors.load(Ordering::Relaxed)
+    );
+}
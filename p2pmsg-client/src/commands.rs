@@ -0,0 +1,549 @@
+use p2pmsg_lib::client::{IdMismatchPolicy, PeerState, PeerSummary};
+use p2pmsg_lib::error::Error;
+use p2pmsg_lib::idle::IdlePolicy;
+use p2pmsg_lib::invite::{txt_record_name, Invite};
+use p2pmsg_lib::{
+    cancel_pending, connect_peer, list_peers, my_id, my_invite, parked_peers, pending,
+    public_addr, send_text, set_contact_id_mismatch_policy, set_peer_idle_policy,
+    subscribe_events_filtered,
+};
+use p2pmsg_lib::petnames::Petnames;
+use p2pmsg_lib::resolver::resolve;
+use p2pmsg_lib::protocol::codec::read_buffers_size;
+use p2pmsg_lib::protocol::id::FriendlyId;
+use p2pmsg_lib::supervisor::running_tasks;
+use p2pmsg_lib::uptime::uptime_summaries;
+use p2pmsg_lib::telemetry::{
+    dropped_counts, flush_stats, frame_sizes, protocol_error_counts, shed_connections,
+    SIZE_BUCKETS,
+};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+
+const HELP: &str = "Commands:
+  peers [--json]            list connected peers
+  name <peer> <petname>     give peer (id, address or name) local petname
+  unname <peer>             remove petname
+  names [prefix]            list petnames
+  addr                      our public address as observed by peers
+  resolve <peer>            addresses where peer (id or name) can be dialed
+  invite [name]             print invite for others, optionally with suggested name
+  join <invite>             connect to peer from invite, petname it with suggested name
+  say <peer> <text>         send chat message, received ones are ChatReceived events
+  dns-record <user@domain>  DNS TXT record to publish, so others can find us by this address
+  topology [--json]         known topology (us, connected peers, rtts) as graphviz DOT or JSON
+  pending <peer>            messages sent over UDP, which peer did not acknowledge yet
+  cancel <peer> <seq>       stop retransmitting pending message
+  idle <peer> <policy>      idle policy of peer - keep, park, close or default
+  parked                    peers parked as idle, they are dialed again when messaged
+  mismatch <peer> <policy>  when contact presents other id - disconnect, warn or default
+  uptime                    how much of the time peers seen in last week were connected
+  stats                     counters of dropped messages, errors, tasks, writes and buffers
+  sizes                     histogram of frame sizes by message type
+  wait <duration>           pause, duration like 500ms, 5s or 1m
+  watch <duration> [filter] print node events for duration, optionally only matching filter
+                            like: type == SlowConsumer || reason != ConnectionClosing
+  expect-connected <peer> [timeout]
+                            fail if peer is not connected within timeout (default 5s)
+  help                      this help";
+
+struct Commands {
+    petnames: Petnames,
+}
+
+pub async fn command_loop(petnames: Petnames) -> Result<(), Error> {
+    let mut commands = Commands { petnames };
+    let mut lines = BufReader::new(stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Err(e) = commands.execute(&line).await {
+            eprintln!("Error: {}", e);
+        }
+    }
+    debug!("Input closed, no more commands");
+    Ok(())
+}
+
+/// Executes commands from file, one per line, empty lines and lines starting with # are skipped.
+/// Stops on first failed command.
+pub async fn run_script(petnames: Petnames, path: &Path) -> Result<(), Error> {
+    let mut commands = Commands { petnames };
+    let script = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Cannot read script {:?}: {}", path, e))?;
+    for (n, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("> {}", line);
+        commands
+            .execute(line)
+            .await
+            .map_err(|e| format!("{}:{}: {}", path.display(), n + 1, e))?;
+    }
+    Ok(())
+}
+
+const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+const EXPECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => s.split_at(pos),
+        None => (s, "s"),
+    };
+    let num: u64 = num.parse().map_err(|_| format!("Invalid duration {}", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(num)),
+        "s" => Ok(Duration::from_secs(num)),
+        "m" => Ok(Duration::from_secs(num * 60)),
+        _ => Err(format!("Invalid duration unit in {}, use ms, s or m", s).into()),
+    }
+}
+
+impl Commands {
+    async fn execute(&mut self, line: &str) -> Result<(), Error> {
+        let mut args = line.split_whitespace();
+        match args.next() {
+            None => Ok(()),
+            Some("peers") => {
+                let json = match args.next() {
+                    None => false,
+                    Some("--json") => true,
+                    Some(a) => return Err(format!("Unknown argument {}", a).into()),
+                };
+                let mut peers = list_peers().await;
+                for p in peers.iter_mut() {
+                    p.name = self.petnames.display_name(&p.id);
+                }
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&peers)?);
+                } else {
+                    print_peers(&peers);
+                }
+                Ok(())
+            }
+            Some("name") => {
+                let (peer, name) = match (args.next(), args.next()) {
+                    (Some(p), Some(n)) => (p, n),
+                    _ => return Err("Usage: name <peer> <petname>".into()),
+                };
+                let id = self.resolve_peer(peer).await?;
+                self.petnames.set(id, name)
+            }
+            Some("unname") => {
+                let peer = args.next().ok_or("Usage: unname <peer>")?;
+                match self.petnames.remove(peer)? {
+                    Some(id) => println!("Removed petname of {}", id),
+                    None => println!("No such petname"),
+                }
+                Ok(())
+            }
+            Some("names") => {
+                let prefix = args.next().unwrap_or("");
+                for (name, id) in self.petnames.list() {
+                    if name.starts_with(prefix) {
+                        println!("{}  {}", name, id);
+                    }
+                }
+                Ok(())
+            }
+            Some("addr") => {
+                match public_addr() {
+                    Some(a) => println!(
+                        "{} ports {:?}, agreed by {} peers (confidence {:.0}%)",
+                        a.ip,
+                        a.ports,
+                        a.observers,
+                        a.confidence * 100.0
+                    ),
+                    None => println!("Public address is not known yet"),
+                }
+                Ok(())
+            }
+            Some("resolve") => {
+                let peer = args.next().ok_or("Usage: resolve <peer>")?;
+                let id = self.resolve_peer(peer).await?;
+                let addrs = resolve(&id).await;
+                if addrs.is_empty() {
+                    println!("No known address of {}", id);
+                }
+                for a in addrs {
+                    println!("{}", a);
+                }
+                Ok(())
+            }
+            Some("invite") => {
+                let name = args.next().map(String::from);
+                match my_invite(name) {
+                    Some(invite) => println!("{}", invite),
+                    None => println!("Not listening on any address, others cannot connect to us"),
+                }
+                Ok(())
+            }
+            Some("say") => {
+                let peer = args.next().ok_or("Usage: say <peer> <text>")?;
+                let text = args.collect::<Vec<_>>().join(" ");
+                if text.is_empty() {
+                    return Err("Usage: say <peer> <text>".into());
+                }
+                let id = self.resolve_peer(peer).await?;
+                send_text(&id, text).await
+            }
+            Some("dns-record") => {
+                let address = args.next().ok_or("Usage: dns-record <user@domain>")?;
+                let name = txt_record_name(address)?;
+                match my_invite(None) {
+                    Some(invite) => println!("{} TXT \"{}\"", name, invite.to_txt_record()),
+                    None => println!("Not listening on any address, others cannot connect to us"),
+                }
+                Ok(())
+            }
+            Some("join") => {
+                let invite: Invite = args.next().ok_or("Usage: join <invite>")?.parse()?;
+                if let Some(name) = invite.name {
+                    if self.petnames.petname(&invite.id).is_none() {
+                        self.petnames.set(invite.id.clone(), &name)?;
+                    }
+                }
+                println!("Connecting to {}", invite.id);
+                connect_peer(invite.addrs, Some(invite.id))
+            }
+            Some("topology") => {
+                let json = match args.next() {
+                    None | Some("--dot") => false,
+                    Some("--json") => true,
+                    Some(a) => return Err(format!("Unknown argument {}", a).into()),
+                };
+                let node = my_id().ok_or("Node is not running")?;
+                let mut peers = list_peers().await;
+                for p in peers.iter_mut() {
+                    p.name = self.petnames.display_name(&p.id);
+                }
+                if json {
+                    let topology = serde_json::json!({ "node": node, "peers": peers });
+                    println!("{}", serde_json::to_string_pretty(&topology)?);
+                } else {
+                    print_dot(&node, &peers);
+                }
+                Ok(())
+            }
+            Some("pending") => {
+                let peer = args.next().ok_or("Usage: pending <peer>")?;
+                let p = self
+                    .find_connected(peer)
+                    .await
+                    .ok_or_else(|| format!("Peer {} is not connected", peer))?;
+                let messages = pending(p.addrs[0]).await;
+                if messages.is_empty() {
+                    println!("No pending messages");
+                }
+                for m in messages {
+                    println!(
+                        "{:>6} {:<10} {:>8.1}s retries {}",
+                        m.seq,
+                        m.kind,
+                        m.age.as_secs_f64(),
+                        m.retries
+                    );
+                }
+                Ok(())
+            }
+            Some("cancel") => {
+                let (peer, seq) = match (args.next(), args.next().map(str::parse::<u64>)) {
+                    (Some(p), Some(Ok(s))) => (p, s),
+                    _ => return Err("Usage: cancel <peer> <seq>".into()),
+                };
+                let p = self
+                    .find_connected(peer)
+                    .await
+                    .ok_or_else(|| format!("Peer {} is not connected", peer))?;
+                if cancel_pending(p.addrs[0], seq).await {
+                    println!("Cancelled {}", seq);
+                } else {
+                    println!("Message {} is not pending", seq);
+                }
+                Ok(())
+            }
+            Some("idle") => {
+                let (peer, policy) = match (args.next(), args.next()) {
+                    (Some(p), Some(policy)) => (p, policy),
+                    _ => return Err("Usage: idle <peer> <keep|park|close|default>".into()),
+                };
+                let policy = match policy {
+                    "default" => None,
+                    p => Some(p.parse::<IdlePolicy>()?),
+                };
+                let id = self.resolve_peer(peer).await?;
+                set_peer_idle_policy(id, policy);
+                Ok(())
+            }
+            Some("mismatch") => {
+                let (peer, policy) = match (args.next(), args.next()) {
+                    (Some(p), Some(policy)) => (p, policy),
+                    _ => return Err("Usage: mismatch <peer> <disconnect|warn|default>".into()),
+                };
+                let policy = match policy {
+                    "default" => None,
+                    p => Some(p.parse::<IdMismatchPolicy>()?),
+                };
+                let id = self.resolve_peer(peer).await?;
+                set_contact_id_mismatch_policy(id, policy);
+                Ok(())
+            }
+            Some("parked") => {
+                let parked = parked_peers();
+                if parked.is_empty() {
+                    println!("No parked peers");
+                }
+                for id in parked {
+                    let name = self.petnames.display_name(&id).unwrap_or_else(|| "-".into());
+                    println!("{}  {}", id, name);
+                }
+                Ok(())
+            }
+            Some("uptime") => {
+                let summaries = uptime_summaries();
+                if summaries.is_empty() {
+                    println!("No peers seen in last week");
+                }
+                for u in summaries {
+                    let name = self.petnames.display_name(&u.id).unwrap_or_else(|| "-".into());
+                    println!(
+                        "{}  {:<16} day {:>5.1}%  week {:>5.1}%",
+                        u.id,
+                        name,
+                        u.day * 100.0,
+                        u.week * 100.0
+                    );
+                }
+                Ok(())
+            }
+            Some("stats") => {
+                for (reason, count) in dropped_counts() {
+                    println!("dropped {:<20} {}", reason, count);
+                }
+                for (kind, count) in protocol_error_counts() {
+                    println!("errors  {:<20} {}", kind, count);
+                }
+                for (task, count) in running_tasks() {
+                    println!("tasks   {:<20} {}", task, count);
+                }
+                let f = flush_stats();
+                println!(
+                    "writes  {} with {} frames, mean {:.0} us, max {} us",
+                    f.flushes,
+                    f.frames,
+                    f.mean_us(),
+                    f.max_us
+                );
+                println!("read buffers {} bytes", read_buffers_size());
+                println!("shed connections {}", shed_connections());
+                Ok(())
+            }
+            Some("sizes") => {
+                print_sizes();
+                Ok(())
+            }
+            Some("wait") => {
+                let duration = parse_duration(args.next().ok_or("Usage: wait <duration>")?)?;
+                tokio::time::delay_for(duration).await;
+                Ok(())
+            }
+            Some("watch") => {
+                let duration =
+                    parse_duration(args.next().ok_or("Usage: watch <duration> [filter]")?)?;
+                let filter = args.collect::<Vec<_>>().join(" ");
+                let mut events = subscribe_events_filtered(&filter)?;
+                let watching = async {
+                    while let Some(event) = events.recv().await {
+                        println!("{}", serde_json::to_string(&event)?);
+                    }
+                    Ok::<_, Error>(())
+                };
+                match tokio::time::timeout(duration, watching).await {
+                    Ok(res) => res,
+                    Err(_) => Ok(()),
+                }
+            }
+            Some("expect-connected") => {
+                let peer = args
+                    .next()
+                    .ok_or("Usage: expect-connected <peer> [timeout]")?;
+                let timeout = match args.next() {
+                    Some(t) => parse_duration(t)?,
+                    None => DEFAULT_EXPECT_TIMEOUT,
+                };
+                let start = Instant::now();
+                loop {
+                    if let Some(p) = self.find_connected(peer).await {
+                        println!("Peer {} is connected", p.id);
+                        return Ok(());
+                    }
+                    if start.elapsed() >= timeout {
+                        return Err(format!("Peer {} not connected within {:?}", peer, timeout).into());
+                    }
+                    tokio::time::delay_for(EXPECT_POLL_INTERVAL).await;
+                }
+            }
+            Some("help") => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            Some(cmd) => Err(format!("Unknown command {}, try help", cmd).into()),
+        }
+    }
+
+    /// Connected peer given by address, id or petname
+    async fn find_connected(&self, peer: &str) -> Option<PeerSummary> {
+        let peers = list_peers().await;
+        if let Ok(addr) = peer.parse::<SocketAddr>() {
+            return peers.into_iter().find(|p| p.addrs.contains(&addr));
+        }
+        let id = self.petnames.resolve(peer)?;
+        peers.into_iter().find(|p| p.id == id)
+    }
+
+    /// Peer can be given by id, petname (or its unique prefix) or address of connected peer
+    async fn resolve_peer(&self, peer: &str) -> Result<FriendlyId, Error> {
+        if let Ok(addr) = peer.parse::<SocketAddr>() {
+            return list_peers()
+                .await
+                .into_iter()
+                .find(|p| p.addrs.contains(&addr))
+                .map(|p| p.id)
+                .ok_or_else(|| format!("No peer connected from {}", addr).into());
+        }
+        match self.petnames.resolve(peer) {
+            Some(id) => Ok(id),
+            None => {
+                let candidates = self.petnames.complete(peer);
+                if candidates.is_empty() {
+                    Err(format!("Unknown peer {}", peer).into())
+                } else {
+                    Err(format!("Ambiguous peer {}, could be {}", peer, candidates.join(", ")).into())
+                }
+            }
+        }
+    }
+}
+
+fn format_age(since: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let secs = now.saturating_sub(since);
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+fn print_sizes() {
+    let sizes = frame_sizes();
+    if sizes.is_empty() {
+        println!("No frames yet");
+        return;
+    }
+    let mut header = format!("{:<14} {:<4} {:>8} {:>8} {:>8}", "TYPE", "DIR", "COUNT", "AVG", "MAX");
+    for b in SIZE_BUCKETS.iter() {
+        header.push_str(&format!(" {:>7}", format!("<={}", b)));
+    }
+    header.push_str(&format!(" {:>7}", "larger"));
+    println!("{}", header);
+    for (kind, dir, h) in sizes {
+        let mut line = format!(
+            "{:<14} {:<4} {:>8} {:>8.0} {:>8}",
+            kind,
+            format!("{:?}", dir),
+            h.count,
+            h.mean(),
+            h.max
+        );
+        for c in h.buckets.iter() {
+            line.push_str(&format!(" {:>7}", c));
+        }
+        println!("{}", line);
+    }
+}
+
+fn print_dot(node: &FriendlyId, peers: &[PeerSummary]) {
+    println!("graph p2pmsg {{");
+    println!("    \"{}\" [label=\"{}\\n(this node)\", shape=box];", node, short_id(node));
+    for p in peers {
+        let label = match p.name {
+            Some(ref name) => format!("{}\\n{}", name, short_id(&p.id)),
+            None => short_id(&p.id),
+        };
+        println!("    \"{}\" [label=\"{}\"];", p.id, label);
+        let mut attrs = vec![];
+        if let Some(rtt) = p.rtt_ms {
+            attrs.push(format!("label=\"{:.1}ms\"", rtt));
+        }
+        if p.state != PeerState::Connected {
+            attrs.push("style=dashed".to_string());
+        }
+        println!("    \"{}\" -- \"{}\" [{}];", node, p.id, attrs.join(", "));
+    }
+    println!("}}");
+}
+
+fn short_id(id: &FriendlyId) -> String {
+    id.to_string().chars().take(8).collect()
+}
+
+pub fn print_peers(peers: &[PeerSummary]) {
+    if peers.is_empty() {
+        println!("No connected peers");
+        return;
+    }
+    let header = ["ID", "NAME", "ADDRESS", "STATE", "RTT", "IN", "OUT", "SINCE"];
+    let rows: Vec<[String; 8]> = peers
+        .iter()
+        .map(|p| {
+            [
+                p.id.to_string(),
+                p.name.clone().unwrap_or_else(|| "-".into()),
+                p.addrs
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                match p.state {
+                    PeerState::Connected => "connected".into(),
+                    PeerState::Unstable => format!("unstable ({:.1})", p.suspicion),
+                    PeerState::Unresponsive => "unresponsive".into(),
+                    PeerState::Stalled => "stalled".into(),
+                },
+                p.rtt_ms
+                    .map(|r| format!("{:.1}ms", r))
+                    .unwrap_or_else(|| "-".into()),
+                p.bytes_in.to_string(),
+                p.bytes_out.to_string(),
+                format_age(p.since),
+            ]
+        })
+        .collect();
+
+    let mut widths = [0usize; 8];
+    for (i, h) in header.iter().enumerate() {
+        widths[i] = rows.iter().map(|r| r[i].len()).fold(h.len(), usize::max);
+    }
+    let print_row = |cols: Vec<&str>| {
+        let line: Vec<String> = cols
+            .iter()
+            .zip(widths.iter())
+            .map(|(c, w)| format!("{:<width$}", c, width = w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header.to_vec());
+    for r in &rows {
+        print_row(r.iter().map(String::as_str).collect());
+    }
+}
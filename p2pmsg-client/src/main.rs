@@ -1,68 +1,449 @@
 #[macro_use]
-extern crate clap;
-#[macro_use]
 extern crate log;
 
 use p2pmsg_lib::error::Error;
-use p2pmsg_lib::run_client;
+use p2pmsg_lib::health::{self, run_health_server};
+use p2pmsg_lib::identity;
+use p2pmsg_lib::petnames::Petnames;
+use p2pmsg_lib::rpc::run_rpc_server;
+use p2pmsg_lib::systemd;
+use p2pmsg_lib::uptime;
+use p2pmsg_lib::webhook::add_webhook;
+use p2pmsg_lib::client::ClientConfig;
+use p2pmsg_lib::{run_client, shutdown};
+use std::path::Path;
+use std::time::Duration;
+
+use cmd::{Command, RunArgs};
+
+mod commands;
+mod remote;
 
 mod cmd {
-    use clap::{App, Arg};
+    use p2pmsg_lib::client::{IdMismatchPolicy, SlowConsumerPolicy};
+    use p2pmsg_lib::idle::IdlePolicy;
+    use p2pmsg_lib::listener::ListenerConfig;
+    use p2pmsg_lib::rpc::RpcConfig;
+    use p2pmsg_lib::webhook::WebhookConfig;
     use std::net::SocketAddr;
-    use std::str::FromStr;
-    use std::fmt::Debug;
+    use std::path::PathBuf;
+    use structopt::StructOpt;
+
+    #[derive(Debug, StructOpt)]
+    #[structopt(author, about)]
+    pub struct Args {
+        /// Directory for identity and petnames [default: ~/.p2pmsg], use different one for each local node
+        #[structopt(long, global = true, parse(from_os_str))]
+        data_dir: Option<PathBuf>,
+        /// Log more, -v info, -vv debug, -vvv trace (default is from RUST_LOG)
+        #[structopt(short, long, global = true, parse(from_occurrences))]
+        pub verbose: u8,
+        #[structopt(subcommand)]
+        command: Option<Command>,
+    }
+
+    #[derive(Debug, StructOpt)]
+    pub enum Command {
+        /// Runs node and reads commands from stdin (default, when no subcommand is given)
+        Run(RunArgs),
+        /// Sends raw frame through running node, via its RPC endpoint
+        Send(SendArgs),
+        /// Lists peers connected to running node, via its RPC endpoint
+        Peers(PeersArgs),
+        /// Prints id of this node, creates new identity if there is none
+        Identity,
+        /// Prints id of this node and petnames as JSON
+        Export,
+        /// Checks data directory and that node can listen on configured addresses, changes nothing
+        Doctor(RunArgs),
+    }
+
+    #[derive(Debug, StructOpt)]
+    pub struct RpcClientArgs {
+        /// Address of RPC endpoint of running node (its --rpc-addr)
+        #[structopt(long)]
+        pub rpc_addr: SocketAddr,
+        /// API key for RPC endpoint
+        #[structopt(long, env = "P2PMSG_RPC_KEY", hide_env_values = true)]
+        pub rpc_key: String,
+    }
+
+    #[derive(Debug, StructOpt)]
+    pub struct SendArgs {
+        #[structopt(flatten)]
+        pub rpc: RpcClientArgs,
+        /// Peer address or id
+        pub peer: String,
+        /// Raw protocol
+        pub protocol: String,
+        /// Text to send
+        pub data: String,
+    }
+
+    #[derive(Debug, StructOpt)]
+    pub struct PeersArgs {
+        #[structopt(flatten)]
+        pub rpc: RpcClientArgs,
+        /// Print peers as JSON
+        #[structopt(long)]
+        pub json: bool,
+    }
+
+    #[derive(Debug, StructOpt)]
+    pub struct RunArgs {
+        /// Port to listen on localhost
+        #[structopt(short, long, default_value = "12345")]
+        port: u16,
+        /// Additional listener as address[,max=N][,local], e.g. 0.0.0.0:9000,max=50
+        #[structopt(long, number_of_values = 1)]
+        listen: Vec<ListenerConfig>,
+        /// Bootstrap peer, they are dialed in random order
+        #[structopt(long = "peer", number_of_values = 1)]
+        pub peers: Vec<SocketAddr>,
+        /// Dial bootstrap peers until we have this many connections
+        #[structopt(long, default_value = "3")]
+        pub bootstrap_target: usize,
+        /// Number of most used peers to keep connected, 0 to disable
+        #[structopt(long = "prewarm", default_value = "5")]
+        pub prewarm_peers: usize,
+        /// Discover peers on LAN by UDP multicast, needs --listen on non-loopback address
+        #[structopt(long)]
+        pub discovery: bool,
+        /// Offer UDP transport for small latency-sensitive messages, on port of first listener
+        #[structopt(long)]
+        pub udp: bool,
+        /// What to do with peer, which stops reading our messages
+        #[structopt(long, default_value = "disconnect", possible_values = &["wait", "drop", "disconnect"])]
+        pub slow_consumer: SlowConsumerPolicy,
+        /// What to do with connection without application messages for --idle-timeout
+        #[structopt(long, default_value = "keep", possible_values = &["keep", "park", "close"])]
+        pub idle_policy: IdlePolicy,
+        /// Minutes without application messages, after which connection is idle
+        #[structopt(long, default_value = "10")]
+        pub idle_timeout: u64,
+        /// Maximum number of concurrent handshakes of incoming connections
+        #[structopt(long, default_value = "64")]
+        pub max_handshakes: usize,
+        /// Reject incoming connections as busy, when this many are waiting for handshake
+        #[structopt(long, default_value = "256")]
+        pub shed_handshakes: usize,
+        /// What to do, when dialed contact presents other id than expected
+        #[structopt(long, default_value = "disconnect", possible_values = &["disconnect", "warn"])]
+        pub id_mismatch: IdMismatchPolicy,
+        /// Outbound only mode - do not listen, just connect to peers
+        #[structopt(long, conflicts_with = "listen")]
+        no_listen: bool,
+        /// Address for HTTP /healthz and /readyz endpoints, e.g. 0.0.0.0:8080
+        #[structopt(long)]
+        pub health_addr: Option<SocketAddr>,
+        /// Address for HTTP endpoint sending messages and listing peers, requires API key
+        #[structopt(long)]
+        pub rpc_addr: Option<SocketAddr>,
+        /// API keys accepted by RPC endpoint, comma separated in environment variable
+        #[structopt(long = "rpc-key", env = "P2PMSG_RPC_KEYS", hide_env_values = true, number_of_values = 1, use_delimiter = true)]
+        rpc_keys: Vec<String>,
+        /// Requests per minute per API key of RPC endpoint
+        #[structopt(long, default_value = "60")]
+        rpc_rate_limit: u32,
+        /// POST events and messages to this URL (http:// only)
+        #[structopt(long)]
+        webhook: Option<String>,
+        /// Filter of events posted to webhook, all events are posted by default
+        #[structopt(long, default_value = "")]
+        webhook_events: String,
+        /// Post frames of this raw protocol to webhook, can be given several times
+        #[structopt(long, requires = "webhook", number_of_values = 1)]
+        webhook_protocol: Vec<String>,
+        /// Secret for signing webhook posts
+        #[structopt(long, env = "P2PMSG_WEBHOOK_SECRET", hide_env_values = true)]
+        webhook_secret: Option<String>,
+        /// Do not read commands from stdin, for running as a service without terminal
+        #[structopt(long)]
+        pub no_stdin: bool,
+        /// Execute commands from file instead of stdin and exit, fails on first failed command
+        #[structopt(long, conflicts_with = "no-stdin", parse(from_os_str))]
+        pub script: Option<PathBuf>,
+    }
+
+    impl RunArgs {
+        pub fn listeners(&self) -> Vec<ListenerConfig> {
+            if self.no_listen {
+                return vec![];
+            }
+            let localhost = SocketAddr::from(([127, 0, 0, 1], self.port));
+            let mut listeners = vec![ListenerConfig::new(localhost)];
+            listeners.extend(self.listen.iter().cloned());
+            listeners
+        }
+
+        pub fn rpc(&self) -> Option<RpcConfig> {
+            self.rpc_addr.map(|addr| RpcConfig {
+                addr,
+                api_keys: self.rpc_keys.clone(),
+                rate_limit: self.rpc_rate_limit,
+            })
+        }
+
+        pub fn webhook(&self) -> Option<WebhookConfig> {
+            self.webhook.as_ref().map(|url| WebhookConfig {
+                url: url.clone(),
+                events: Some(self.webhook_events.clone()),
+                protocols: self.webhook_protocol.clone(),
+                secret: self.webhook_secret.clone(),
+            })
+        }
+    }
+
+    fn default_data_dir() -> PathBuf {
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(PathBuf::from)
+            .unwrap_or_default()
+            .join(".p2pmsg")
+    }
 
-    #[derive(Debug)]
-    pub struct Config {
-        pub port: u16,
-        pub peers: Option<Vec<SocketAddr>>,
+    impl Args {
+        pub fn data_dir(&self) -> PathBuf {
+            self.data_dir.clone().unwrap_or_else(default_data_dir)
+        }
+
+        pub fn command(self) -> Command {
+            self.command
+                .unwrap_or_else(|| Command::Run(RunArgs::from_iter(&["run"])))
+        }
+    }
+
+    pub fn parse_args() -> Args {
+        Args::from_args()
     }
+}
 
-    fn validator<T>(s: String) -> Result<(), String> 
-     where T:FromStr, <T as FromStr>::Err :Debug
+async fn wait_for_signal() -> Result<(), Error> {
+    #[cfg(unix)]
     {
-        s.parse()
-            .map(|_i: T| ())
-            .map_err(|e| format!("{:?}",e))
-    }
-
-    fn args_parser<'a, 'b>() -> App<'a, 'b> {
-        app_from_crate!()
-            .arg(
-                Arg::with_name("port")
-                    .short("p")
-                    .long("port")
-                    .takes_value(true)
-                    .validator(validator::<u16>)
-                    .default_value("12345"),
-            )
-            .arg(
-                Arg::with_name("peer")
-                    .long("peer")
-                    .takes_value(true)
-                    .multiple(true)
-                    .validator(validator::<SocketAddr>),
-            )
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r?,
+            _ = term.recv() => debug!("Got SIGTERM"),
+        }
     }
+    #[cfg(windows)]
+    {
+        let mut ctrl_break = tokio::signal::windows::ctrl_break()?;
+        tokio::select! {
+            r = tokio::signal::ctrl_c() => r?,
+            _ = ctrl_break.recv() => debug!("Got Ctrl-Break"),
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
 
-    pub fn parse_args<'a>() -> Config {
-        let args = args_parser().get_matches();
-        let port: u16 = args.value_of("port").unwrap().parse().unwrap();
-        let peers = args
-            .values_of("peer")
-            .map(|peers| peers.map(|p| p.parse().unwrap()).collect());
+/// Resolves when process is asked to terminate (Ctrl-C, SIGTERM, Ctrl-Break)
+async fn shutdown_signal() {
+    if let Err(e) = wait_for_signal().await {
+        error!("Cannot listen for termination signals: {}", e);
+        std::future::pending::<()>().await
+    }
+}
 
-        Config { port, peers }
+fn init_logging(verbose: u8) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match verbose {
+        0 => (),
+        1 => {
+            builder.filter_level(log::LevelFilter::Info);
+        }
+        2 => {
+            builder.filter_level(log::LevelFilter::Debug);
+        }
+        _ => {
+            builder.filter_level(log::LevelFilter::Trace);
+        }
     }
+    builder.init()
 }
 
+/// Checks which can be done without running node, prints result of each and fails if any failed
+fn doctor(data_dir: &Path, args: &RunArgs) -> Result<(), Error> {
+    let mut failed = 0;
+    let mut check = |what: String, res: Result<String, Error>| match res {
+        Ok(detail) => println!("OK    {}: {}", what, detail),
+        Err(e) => {
+            failed += 1;
+            println!("FAIL  {}: {}", what, e)
+        }
+    };
+    check(
+        "identity".into(),
+        identity::load(&data_dir.join("identity")).map(|id| match id {
+            Some(id) => id.to_string(),
+            None => "none yet, will be created on first run".into(),
+        }),
+    );
+    check(
+        "petnames".into(),
+        Petnames::load(&data_dir.join("petnames.json"))
+            .map(|p| format!("{} names", p.list().len())),
+    );
+    let listeners = args.listeners();
+    if listeners.is_empty() {
+        check("listen".into(), Ok("outbound only mode".into()));
+    }
+    for l in &listeners {
+        check(
+            format!("listen {}", l.addr),
+            std::net::TcpListener::bind(l.addr)
+                .map(|_| "can bind".into())
+                .map_err(|e| e.into()),
+        );
+    }
+    if args.udp {
+        match listeners.first() {
+            Some(l) => check(
+                format!("udp {}", l.addr),
+                std::net::UdpSocket::bind(l.addr)
+                    .map(|_| "can bind".into())
+                    .map_err(|e| e.into()),
+            ),
+            None => check("udp".into(), Err("UDP transport needs a listener".into())),
+        }
+    }
+    if args.peers.is_empty() && !args.discovery {
+        check("peers".into(), Ok("no bootstrap peers, waiting for others to connect".into()));
+    }
+    if failed > 0 {
+        Err(format!("{} checks failed", failed).into())
+    } else {
+        Ok(())
+    }
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    let cfg = cmd::parse_args();
-    env_logger::init();
-    info!("Program arguments {:?}", &cfg);
-    run_client(cfg.port, cfg.peers).await
+fn main() -> Result<(), Error> {
+    // must run before runtime starts its threads
+    systemd::init();
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(run())
+}
 
-    //Ok(())
+async fn run() -> Result<(), Error> {
+    let args = cmd::parse_args();
+    init_logging(args.verbose);
+    info!("Program arguments {:?}", &args);
+    let data_dir = args.data_dir();
+    let cfg = match args.command() {
+        Command::Run(cfg) => cfg,
+        Command::Identity => {
+            let id = identity::load_or_create(&data_dir.join("identity"))?;
+            println!("{}", id);
+            return Ok(());
+        }
+        Command::Export => {
+            let id = identity::load(&data_dir.join("identity"))?;
+            let petnames = Petnames::load(&data_dir.join("petnames.json"))?;
+            let petnames: Vec<_> = petnames
+                .list()
+                .into_iter()
+                .map(|(name, id)| serde_json::json!({ "name": name, "id": id }))
+                .collect();
+            let export = serde_json::json!({
+                "id": id.map(|id| id.to_string()),
+                "petnames": petnames,
+            });
+            println!("{}", serde_json::to_string_pretty(&export)?);
+            return Ok(());
+        }
+        Command::Send(args) => {
+            return remote::send(
+                args.rpc.rpc_addr,
+                &args.rpc.rpc_key,
+                &args.peer,
+                &args.protocol,
+                &args.data,
+            )
+            .await
+        }
+        Command::Peers(args) => {
+            let mut peers = remote::peers(args.rpc.rpc_addr, &args.rpc.rpc_key).await?;
+            let petnames = Petnames::load(&data_dir.join("petnames.json"))?;
+            for p in peers.iter_mut() {
+                p.name = petnames.display_name(&p.id);
+            }
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&peers)?);
+            } else {
+                commands::print_peers(&peers);
+            }
+            return Ok(());
+        }
+        Command::Doctor(cfg) => return doctor(&data_dir, &cfg),
+    };
+    let id = identity::load_or_create(&data_dir.join("identity"))?;
+    let petnames = Petnames::load(&data_dir.join("petnames.json"))?;
+    uptime::load_uptime(&data_dir.join("uptime.json"))?;
+    health::set_storage_dir(&data_dir);
+    if let Some(webhook) = cfg.webhook() {
+        add_webhook(webhook)?;
+    }
+    let health_addr = cfg.health_addr;
+    let listeners = cfg.listeners();
+    let health = async move {
+        if let Some(addr) = health_addr {
+            run_health_server(addr)
+                .await
+                .unwrap_or_else(|e| error!("Health server error: {}", e))
+        }
+    };
+    let rpc_config = cfg.rpc();
+    let rpc = async move {
+        if let Some(config) = rpc_config {
+            run_rpc_server(config)
+                .await
+                .unwrap_or_else(|e| error!("RPC server error: {}", e))
+        }
+    };
+    let no_stdin = cfg.no_stdin;
+    let script = cfg.script;
+    // completes only when script finishes, node keeps running after stdin is closed
+    let commands = async move {
+        if let Some(path) = script {
+            return commands::run_script(petnames, &path).await;
+        }
+        if !no_stdin {
+            commands::command_loop(petnames)
+                .await
+                .unwrap_or_else(|e| error!("Error reading commands: {}", e))
+        }
+        std::future::pending().await
+    };
+    let client_config = ClientConfig {
+        bootstrap_target: cfg.bootstrap_target,
+        prewarm_peers: cfg.prewarm_peers,
+        discovery: cfg.discovery,
+        udp: cfg.udp,
+        slow_consumer: cfg.slow_consumer,
+        idle_policy: cfg.idle_policy,
+        idle_timeout: Duration::from_secs(cfg.idle_timeout * 60),
+        max_handshakes: cfg.max_handshakes,
+        shed_handshakes: cfg.shed_handshakes,
+        id_mismatch: cfg.id_mismatch,
+        ..ClientConfig::new(listeners, cfg.peers)
+    };
+    let node = async {
+        let (res, _, _) = tokio::join!(run_client(client_config, id), health, rpc);
+        res
+    };
+    tokio::select! {
+        res = node => res,
+        res = commands => {
+            shutdown().await;
+            res
+        }
+        _ = shutdown_signal() => {
+            shutdown().await;
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,77 @@
+//! Minimal client of RPC endpoint of running node, used by `send` and `peers` subcommands
+
+use p2pmsg_lib::client::PeerSummary;
+use p2pmsg_lib::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Makes HTTP request to RPC endpoint, returns body of successful response
+async fn request(
+    addr: SocketAddr,
+    key: &str,
+    method: &str,
+    path: &str,
+    body: Option<String>,
+) -> Result<String, Error> {
+    let body = body.unwrap_or_default();
+    let req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        key,
+        body.len(),
+        body
+    );
+    let exchange = async {
+        let mut socket = TcpStream::connect(addr).await?;
+        socket.write_all(req.as_bytes()).await?;
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await?;
+        Ok::<_, Error>(response)
+    };
+    let response = tokio::time::timeout(TIMEOUT, exchange)
+        .await
+        .map_err(|_| format!("RPC endpoint {} did not respond", addr))??;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(i) => (&response[..i], &response[i + 4..]),
+        None => return Err("invalid RPC response".into()),
+    };
+    let status: u16 = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or("invalid RPC response")?;
+    if status != 200 {
+        let msg = serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|v| v["error"].as_str().map(String::from))
+            .unwrap_or_else(|| body.to_string());
+        return Err(format!("RPC error {}: {}", status, msg).into());
+    }
+    Ok(body.to_string())
+}
+
+/// Sends raw frame through running node, `peer` is address or id
+pub async fn send(
+    addr: SocketAddr,
+    key: &str,
+    peer: &str,
+    protocol: &str,
+    data: &str,
+) -> Result<(), Error> {
+    let body = serde_json::json!({ "peer": peer, "protocol": protocol, "data": data });
+    request(addr, key, "POST", "/send", Some(body.to_string())).await?;
+    Ok(())
+}
+
+/// Peers connected to running node
+pub async fn peers(addr: SocketAddr, key: &str) -> Result<Vec<PeerSummary>, Error> {
+    let body = request(addr, key, "GET", "/peers", None).await?;
+    Ok(serde_json::from_str(&body)?)
+}
@@ -0,0 +1,52 @@
+/* C API of p2pmsg node, library must be built with feature ffi */
+#ifndef P2PMSG_H
+#define P2PMSG_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define P2PMSG_OK 0
+#define P2PMSG_INVALID_ARGUMENT 1
+#define P2PMSG_NOT_RUNNING 2
+#define P2PMSG_ALREADY_STARTED 3
+#define P2PMSG_FAILED 4
+
+#define P2PMSG_EVENT_MESSAGE 0
+#define P2PMSG_EVENT_MESSAGE_DROPPED 1
+#define P2PMSG_EVENT_SLOW_CONSUMER 2
+#define P2PMSG_EVENT_PEER_COMPATIBILITY 3
+#define P2PMSG_EVENT_PEER_CONNECTED 4
+#define P2PMSG_EVENT_PEER_DISCONNECTED 5
+#define P2PMSG_EVENT_ID_MISMATCH 6
+#define P2PMSG_EVENT_CHAT 7
+
+/* pointers are valid only during callback */
+typedef struct {
+    int kind;
+    const char *peer;     /* NULL if event is not related to a peer */
+    const char *protocol; /* NULL if not P2PMSG_EVENT_MESSAGE */
+    const uint8_t *data;  /* NULL if not P2PMSG_EVENT_MESSAGE */
+    size_t data_len;
+    const char *detail;   /* event as JSON */
+} P2pmsgEvent;
+
+typedef void (*P2pmsgEventCallback)(const P2pmsgEvent *event, void *user_data);
+
+/* listen and peers are comma separated addresses, can be NULL, node can be started once per process */
+int p2pmsg_start(const char *data_dir, const char *listen, const char *peers);
+int p2pmsg_stop(void);
+/* blocks until sent, must not be called from callback */
+int p2pmsg_send(const char *peer, const char *protocol, const uint8_t *data, size_t len);
+int p2pmsg_listen_protocol(const char *protocol);
+/* callback is called from node thread, NULL removes it */
+int p2pmsg_set_event_callback(P2pmsgEventCallback cb, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,387 @@
+//! Bots - application logic reacting to frames of raw protocols, commands, chat and timers.
+//! Each bot runs in its own tasks, errors and panics of its handlers are logged and affect
+//! neither node nor other bots. Messages sent by bot are rate limited.
+//! `CommandRouter` is ready made bot dispatching commands to handlers.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::RecvError;
+
+use crate::client::{self, Node};
+use crate::clock;
+use crate::error::Error;
+use crate::events::NodeEvent;
+use crate::protocol::id::FriendlyId;
+use crate::protocol::message::Message;
+use crate::ratelimit::TokenBucket;
+
+/// Messages per minute, which bot can send by default
+pub const DEFAULT_BOT_RATE_LIMIT: u32 = 60;
+
+pub trait Bot: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Raw protocols, which frames are delivered to bot
+    fn protocols(&self) -> Vec<String>;
+
+    /// Frame, which is not a command
+    fn on_message<'a>(
+        &'a self,
+        _ctx: &'a BotContext,
+        _peer: SocketAddr,
+        _protocol: &'a str,
+        _data: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(future::ok(()))
+    }
+
+    /// Text frame starting with `/`, e.g. `/weather Prague` is command `weather` with
+    /// arguments `["Prague"]`
+    fn on_command<'a>(
+        &'a self,
+        _ctx: &'a BotContext,
+        _peer: SocketAddr,
+        _command: &'a str,
+        _args: &'a [String],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(future::ok(()))
+    }
+
+    /// Whether chat messages received by node are delivered to `on_chat`
+    fn receives_chat(&self) -> bool {
+        false
+    }
+
+    /// Chat message received by node, which bot was added to
+    fn on_chat<'a>(
+        &'a self,
+        _ctx: &'a BotContext,
+        _from: &'a FriendlyId,
+        _body: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(future::ok(()))
+    }
+
+    /// Period of `on_timer` calls, None if bot has no timer
+    fn timer(&self) -> Option<Duration> {
+        None
+    }
+
+    fn on_timer<'a>(&'a self, _ctx: &'a BotContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(future::ok(()))
+    }
+}
+
+/// Node API available to bot handlers
+pub struct BotContext {
+    name: String,
+    node: Node,
+    limiter: Mutex<TokenBucket>,
+}
+
+impl BotContext {
+    fn check_rate(&self) -> Result<(), Error> {
+        if self.limiter.lock().unwrap().allow(clock::now()) {
+            Ok(())
+        } else {
+            Err(format!("Bot {} exceeded its rate limit", self.name).into())
+        }
+    }
+
+    /// Sends raw frame to connected peer, fails if bot exceeded its rate limit
+    pub async fn send(&self, peer: SocketAddr, protocol: &str, data: Vec<u8>) -> Result<(), Error> {
+        self.check_rate()?;
+        let msg = Message::Raw {
+            protocol: protocol.into(),
+            data,
+        };
+        self.node.send(peer, msg).await
+    }
+
+    /// Sends chat message to peer, fails if bot exceeded its rate limit
+    pub async fn send_text(&self, to: &FriendlyId, body: String) -> Result<(), Error> {
+        self.check_rate()?;
+        self.node.send_text(to, body).await
+    }
+}
+
+fn parse_command(data: &[u8]) -> Option<(String, Vec<String>)> {
+    let text = std::str::from_utf8(data).ok()?.trim().strip_prefix('/')?;
+    let mut words = text.split_whitespace().map(String::from);
+    let command = words.next()?;
+    Some((command, words.collect()))
+}
+
+/// Handler can panic also when creating its future
+async fn run_handler<'a, F>(bot: &str, handler: F)
+where
+    F: FnOnce() -> BoxFuture<'a, Result<(), Error>>,
+{
+    let res = match std::panic::catch_unwind(AssertUnwindSafe(handler)) {
+        Ok(fut) => AssertUnwindSafe(fut).catch_unwind().await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => warn!("Bot {} handler failed: {}", bot, e),
+        Err(_) => error!("Bot {} handler panicked", bot),
+    }
+}
+
+/// Starts bot on default node with limit of sent messages per minute.
+/// Must be called within runtime.
+pub fn add_bot(bot: Arc<dyn Bot>, rate_limit: u32) -> Result<(), Error> {
+    add_bot_to(&client::default_node(), bot, rate_limit)
+}
+
+/// Starts bot on given node, bot's tasks are stopped with the node
+pub fn add_bot_to(node: &Node, bot: Arc<dyn Bot>, rate_limit: u32) -> Result<(), Error> {
+    let protocols = bot
+        .protocols()
+        .iter()
+        .map(|p| node.register_raw_protocol(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let ctx = Arc::new(BotContext {
+        name: bot.name().into(),
+        node: node.clone(),
+        limiter: Mutex::new(TokenBucket::new(rate_limit, clock::now())),
+    });
+    let supervisor = node.supervisor();
+    for mut raw in protocols {
+        let (bot, ctx) = (bot.clone(), ctx.clone());
+        supervisor.spawn("bot", async move {
+            while let Some((peer, data)) = raw.recv().await {
+                let name = bot.name();
+                match parse_command(&data) {
+                    Some((command, args)) => {
+                        run_handler(name, || bot.on_command(&ctx, peer, &command, &args)).await
+                    }
+                    None => {
+                        run_handler(name, || bot.on_message(&ctx, peer, raw.name(), &data)).await
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+    if bot.receives_chat() {
+        let (bot, ctx) = (bot.clone(), ctx.clone());
+        let mut events = node.subscribe_events();
+        supervisor.spawn("bot chat", async move {
+            loop {
+                match events.recv().await {
+                    Ok(NodeEvent::ChatReceived { from, body, .. }) => {
+                        run_handler(bot.name(), || bot.on_chat(&ctx, &from, &body)).await
+                    }
+                    Ok(_) => (),
+                    Err(RecvError::Lagged(n)) => warn!("Bot {} missed {} events", bot.name(), n),
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        });
+    }
+    if let Some(period) = bot.timer() {
+        supervisor.spawn("bot timer", async move {
+            let mut ticker = tokio::time::interval(period);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                run_handler(bot.name(), || bot.on_timer(&ctx)).await
+            }
+        });
+    }
+    Ok(())
+}
+
+pub type CommandHandler =
+    Arc<dyn Fn(SocketAddr, Vec<String>) -> BoxFuture<'static, Result<String, Error>> + Send + Sync>;
+
+/// Bot routing commands to registered handlers and replying with their results on the same
+/// protocol. Generates `/help`, commands can be disabled for particular peers.
+pub struct CommandRouter {
+    name: String,
+    protocol: String,
+    /// Command name -> (help, handler), sorted for help
+    commands: BTreeMap<String, (String, CommandHandler)>,
+    disabled: Mutex<HashSet<SocketAddr>>,
+}
+
+impl CommandRouter {
+    pub fn new(name: &str, protocol: &str) -> Self {
+        CommandRouter {
+            name: name.into(),
+            protocol: protocol.into(),
+            commands: BTreeMap::new(),
+            disabled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Adds command, handler returns text of reply
+    pub fn command<F>(mut self, name: &str, help: &str, handler: F) -> Self
+    where
+        F: Fn(SocketAddr, Vec<String>) -> BoxFuture<'static, Result<String, Error>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.commands
+            .insert(name.into(), (help.into(), Arc::new(handler)));
+        self
+    }
+
+    /// Commands of disabled peer are ignored
+    pub fn set_enabled(&self, peer: SocketAddr, enabled: bool) {
+        let mut disabled = self.disabled.lock().unwrap();
+        if enabled {
+            disabled.remove(&peer);
+        } else {
+            disabled.insert(peer);
+        }
+    }
+
+    pub fn help(&self) -> String {
+        let mut help = String::from("/help - this help");
+        for (name, (text, _)) in &self.commands {
+            help.push_str(&format!("\n/{} - {}", name, text));
+        }
+        help
+    }
+
+    /// Reply to command, None if command is ignored
+    async fn route(&self, peer: SocketAddr, command: &str, args: &[String]) -> Option<String> {
+        if self.disabled.lock().unwrap().contains(&peer) {
+            return None;
+        }
+        if command == "help" {
+            return Some(self.help());
+        }
+        let handler = match self.commands.get(command) {
+            Some((_, h)) => h.clone(),
+            None => return Some(format!("Unknown command /{}, try /help", command)),
+        };
+        Some(match handler(peer, args.to_vec()).await {
+            Ok(reply) => reply,
+            Err(e) => format!("/{} failed: {}", command, e),
+        })
+    }
+}
+
+impl Bot for CommandRouter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn protocols(&self) -> Vec<String> {
+        vec![self.protocol.clone()]
+    }
+
+    fn on_command<'a>(
+        &'a self,
+        ctx: &'a BotContext,
+        peer: SocketAddr,
+        command: &'a str,
+        args: &'a [String],
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            match self.route(peer, command, args).await {
+                Some(reply) => ctx.send(peer, &self.protocol, reply.into_bytes()).await,
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    struct TestBot(mpsc::UnboundedSender<Vec<u8>>);
+
+    impl Bot for TestBot {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn protocols(&self) -> Vec<String> {
+            vec!["test_bot".into()]
+        }
+
+        fn on_message<'a>(
+            &'a self,
+            _ctx: &'a BotContext,
+            _peer: SocketAddr,
+            _protocol: &'a str,
+            data: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            let _ = self.0.send(data.to_vec());
+            Box::pin(future::ok(()))
+        }
+
+        fn on_command<'a>(
+            &'a self,
+            _ctx: &'a BotContext,
+            _peer: SocketAddr,
+            command: &'a str,
+            _args: &'a [String],
+        ) -> BoxFuture<'a, Result<(), Error>> {
+            match command {
+                "panic" => panic!("bot panic"),
+                _ => Box::pin(future::err("unknown command".into())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bot_isolation() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let node = Node::new();
+        add_bot_to(&node, Arc::new(TestBot(tx)), DEFAULT_BOT_RATE_LIMIT).unwrap();
+        let peer: SocketAddr = "127.0.0.1:7901".parse().unwrap();
+        node.raw().dispatch(peer, "test_bot".into(), b"/panic".to_vec());
+        node.raw().dispatch(peer, "test_bot".into(), b"/other".to_vec());
+        node.raw().dispatch(peer, "test_bot".into(), b"hello".to_vec());
+        assert_eq!(b"hello".to_vec(), rx.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_command_router() {
+        let router = CommandRouter::new("router", "test_router").command(
+            "echo",
+            "repeats arguments",
+            |_, args| Box::pin(future::ok(args.join(" "))),
+        );
+        let peer: SocketAddr = "127.0.0.1:7902".parse().unwrap();
+        let args = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(Some("a b".into()), router.route(peer, "echo", &args).await);
+        assert_eq!(
+            Some("/help - this help\n/echo - repeats arguments".into()),
+            router.route(peer, "help", &[]).await
+        );
+        assert!(router
+            .route(peer, "nope", &[])
+            .await
+            .unwrap()
+            .starts_with("Unknown command"));
+        router.set_enabled(peer, false);
+        assert_eq!(None, router.route(peer, "echo", &args).await);
+        router.set_enabled(peer, true);
+        assert!(router.route(peer, "echo", &args).await.is_some());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Some(("weather".to_string(), vec!["Prague".to_string()])),
+            parse_command(b" /weather  Prague\n")
+        );
+        assert_eq!(Some(("help".to_string(), vec![])), parse_command(b"/help"));
+        assert_eq!(None, parse_command(b"hello /help"));
+        assert_eq!(None, parse_command(b"/"));
+        assert_eq!(None, parse_command(&[b'/', 0xff]));
+    }
+}
@@ -1,215 +1,2391 @@
 use futures::{future, stream::StreamExt};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
 use tokio::sync::{mpsc, RwLock, oneshot};
 use tokio_util::codec::Decoder;
 
+use crate::clock;
+use crate::events::{self, EventListener, Events, NodeEvent};
+#[cfg(feature = "discovery")]
+use crate::discovery::{run_discovery, Beacon, CAP_LISTENING};
 use crate::error::Error;
-use crate::protocol::codec::MsgCodec;
-use crate::protocol::message::Message;
+use crate::filter::{self, FilteredEvents};
+use crate::health::{HealthStatus, ListenerHealth};
+use crate::invite::Invite;
+use crate::raw::{self, RawProtocol, RawRouter};
+use crate::resolver::Resolvers;
+use crate::signaling::{self, Signaling, SignalingSlot};
+use crate::supervisor::{self, Supervisor};
+use crate::udp::{self, UdpTransport};
+use crate::uptime;
+use crate::telemetry::{
+    log_protocol_error_summary, record_drop, record_flush, record_protocol_error, record_shed,
+    DropReason,
+    ProtocolErrorKind,
+};
+use crate::systemd;
+use crate::protocol::codec::{MsgCodec, TrafficStats};
+use crate::protocol::id::{FriendlyId, RawId};
+use crate::listener::{
+    ConnectionGuard, HandshakeLimiter, HandshakeSlot, Listener, ListenerConfig,
+    DEFAULT_MAX_HANDSHAKES, DEFAULT_SHED_HANDSHAKES,
+};
+use crate::observed::{ObservedAddrs, PublicAddr};
+use crate::prewarm::Prewarmer;
+use crate::idle::{IdlePolicy, IdleReaper, DEFAULT_IDLE_TIMEOUT};
+use crate::protocol::message::{ErrorCode, Message, PeerInfo, PING_SEQ_VERSION, PROTOCOL_VERSION};
+use crate::protocol::state::ConnectionState;
+use crate::phi::{PhiAccrual, PHI_DEAD, PHI_UNSTABLE};
+use crate::rtt::RttEstimator;
 use futures::{join, prelude::*};
-use std::time::Instant;
 use future::Either;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct PeerInfo {
-    id: String,
-    addr: SocketAddr,
-    name: String,
-    uses_nat: bool,
+/// Interval between keepalive pings sent to each connected peer
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(200);
+/// How often we check, that most used peers are connected
+pub const PREWARM_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_PREWARM_PEERS: usize = 5;
+/// How many bootstrap peers are dialed at once
+pub const DEFAULT_BOOTSTRAP_PARALLEL: usize = 3;
+/// Bootstrap peers are dialed until we have this many connections
+pub const DEFAULT_BOOTSTRAP_TARGET: usize = 3;
+/// How often we check, that we have enough connections, and dial more bootstrap peers
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(60);
+/// Peer must send its Hello within this time after connection
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Peer is considered slow consumer, when send to it makes no progress for this time
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Batches of messages waiting for peer's writer task, peer is slow consumer, when queue
+/// stays full for STALL_TIMEOUT
+pub const WRITE_QUEUE_SIZE: usize = 64;
+/// With wait policy, how long send waits for stalled peer, before peer is disconnected
+pub const MAX_SLOW_CONSUMER_WAIT: Duration = Duration::from_secs(60);
+/// With wait policy, how many sends can wait for stalled peer, before peer is disconnected
+const MAX_WAITING_BATCHES: usize = WRITE_QUEUE_SIZE;
+/// Larger difference of peer's clock from ours is reported
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// What to do with peer, which stopped reading our messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Keep waiting until peer reads, up to MAX_SLOW_CONSUMER_WAIT and MAX_WAITING_BATCHES,
+    /// then disconnect it
+    Wait,
+    /// Drop application messages to peer until it catches up, control messages are still sent
+    DropLowPriority,
+    Disconnect,
 }
 
-#[allow(dead_code)]
-pub struct ActivePeer {
-    //last_ping_ts: Instant,
-    //last_ping_id: [u8; 32],
+impl std::str::FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(SlowConsumerPolicy::Wait),
+            "drop" => Ok(SlowConsumerPolicy::DropLowPriority),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(format!("Invalid slow consumer policy {}, use wait, drop or disconnect", s)),
+        }
+    }
+}
+
+/// Which connected peers receive broadcast
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastScope {
+    All,
+    /// Only peers connected from local network, for announcements, which should not leave it
+    Local,
+}
+
+/// Address is in local network - private, link-local or loopback
+pub fn is_local_addr(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local_addr(IpAddr::V4(ip)),
+            // unique local fc00::/7 and link-local fe80::/10
+            None => {
+                ip.is_loopback()
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// What to do, when dialed peer presents different id than expected (known) one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdMismatchPolicy {
+    Disconnect,
+    /// Report mismatch and keep connection
+    Warn,
+}
+
+impl std::str::FromStr for IdMismatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(IdMismatchPolicy::Disconnect),
+            "warn" => Ok(IdMismatchPolicy::Warn),
+            _ => Err(format!("Invalid id mismatch policy {}, use disconnect or warn", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub listeners: Vec<ListenerConfig>,
+    /// Bootstrap peers, dialed in random order until there is enough connections
+    pub peers: Vec<SocketAddr>,
+    pub bootstrap_parallel: usize,
+    pub bootstrap_target: usize,
+    /// How many most used peers to keep connected, 0 disables pre-warming
+    pub prewarm_peers: usize,
+    /// Discover peers on LAN by multicast beacons
+    pub discovery: bool,
+    /// Offer UDP transport on port of first listener
+    pub udp: bool,
+    pub slow_consumer: SlowConsumerPolicy,
+    /// What to do with connections without application messages for idle_timeout
+    pub idle_policy: IdlePolicy,
+    pub idle_timeout: Duration,
+    /// Maximum number of concurrent handshakes of incoming connections
+    pub max_handshakes: usize,
+    /// Incoming connections are rejected as busy, when this many are waiting for handshake
+    pub shed_handshakes: usize,
+    /// Default policy for contacts presenting unexpected id
+    pub id_mismatch: IdMismatchPolicy,
+}
+
+impl ClientConfig {
+    pub fn new(listeners: Vec<ListenerConfig>, peers: Vec<SocketAddr>) -> Self {
+        ClientConfig {
+            listeners,
+            peers,
+            bootstrap_parallel: DEFAULT_BOOTSTRAP_PARALLEL,
+            bootstrap_target: DEFAULT_BOOTSTRAP_TARGET,
+            prewarm_peers: DEFAULT_PREWARM_PEERS,
+            discovery: false,
+            udp: false,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+            idle_policy: IdlePolicy::Keep,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+            shed_handshakes: DEFAULT_SHED_HANDSHAKES,
+            id_mismatch: IdMismatchPolicy::Disconnect,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PeerState {
+    Connected,
+    /// Keepalives from peer are late or our ping was not answered within RTO
+    Unstable,
+    /// Keepalives from peer are missing for so long, that it is most likely dead
+    Unresponsive,
+    /// Peer does not read our messages
+    Stalled,
+}
+
+/// Snapshot of connected peer, as returned by `list_peers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerSummary {
+    pub id: FriendlyId,
+    /// Display name - not filled by library, applications can set it e.g. from Petnames
+    pub name: Option<String>,
+    pub addrs: Vec<SocketAddr>,
+    pub state: PeerState,
+    /// Phi accrual suspicion level, that peer failed - 0 is fine, from 8 peer is considered dead
+    pub suspicion: f64,
+    pub rtt_ms: Option<f64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Biggest capacity of connection's read buffer
+    #[serde(default)]
+    pub read_buffer_max: u64,
+    /// Unix timestamp (seconds) when connection was established
+    pub since: u64,
+}
+
+/// Slow consumer state of peer, shared by its queue and writer task
+struct Stall {
     adr: SocketAddr,
-    //info: PeerInfo,
+    policy: SlowConsumerPolicy,
+    events: Events,
+    /// Set when peer makes no progress reading our messages, until writer catches up
+    since: std::sync::Mutex<Option<Instant>>,
+    /// Sends waiting for space in queue (wait policy)
+    waiting: AtomicUsize,
+    /// Waiting for peer exceeded limits, it is to be disconnected
+    gave_up: AtomicBool,
+}
+
+/// Counts send waiting for stalled peer, also when send is cancelled
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Stall {
+    fn since(&self) -> Option<Instant> {
+        *self.since.lock().unwrap()
+    }
+
+    fn mark(&self) {
+        let mut since = self.since.lock().unwrap();
+        if since.is_none() {
+            warn!("Peer {} is not reading our messages", self.adr);
+            *since = Some(clock::now());
+            self.events.emit(NodeEvent::SlowConsumer {
+                peer: self.adr,
+                disconnected: self.policy == SlowConsumerPolicy::Disconnect,
+            });
+        }
+    }
+
+    fn give_up(&self) {
+        if !self.gave_up.swap(true, Ordering::Relaxed) {
+            warn!("Peer {} is not reading our messages, disconnecting", self.adr);
+            self.events.emit(NodeEvent::SlowConsumer {
+                peer: self.adr,
+                disconnected: true,
+            });
+        }
+    }
+
+    fn clear(&self) {
+        *self.since.lock().unwrap() = None
+    }
+}
+
+/// Sending side of peer's writer task, it is cloned, so messages are queued without
+/// holding lock of all connections
+#[derive(Clone)]
+struct PeerQueue {
+    batches: mpsc::Sender<Vec<Message>>,
+    stall: Arc<Stall>,
+}
+
+impl PeerQueue {
+    /// Queues messages, which are written and flushed together, so small messages
+    /// are coalesced into fewer writes
+    async fn send_all(mut self, mut msgs: Vec<Message>) -> Result<(), Error> {
+        let adr = self.stall.adr;
+        if self.stall.since().is_some() && self.stall.policy == SlowConsumerPolicy::DropLowPriority {
+            let n = msgs.len();
+            msgs.retain(Message::is_control);
+            for _ in msgs.len()..n {
+                record_drop(&self.stall.events, DropReason::SlowConsumer, Some(adr));
+            }
+            if msgs.is_empty() {
+                return Err(format!("Peer {} is not reading", adr).into());
+            }
+        }
+        // waits for writer like for write itself, but without holding lock of connections
+        let msgs = match self.batches.send_timeout(msgs, STALL_TIMEOUT).await {
+            Ok(()) => return Ok(()),
+            Err(mpsc::error::SendTimeoutError::Timeout(msgs)) => msgs,
+            Err(mpsc::error::SendTimeoutError::Closed(_)) => {
+                return Err(format!("Connection to {} is closed", adr).into())
+            }
+        };
+        self.stall.mark();
+        let msgs = match self.stall.policy {
+            SlowConsumerPolicy::Wait => match self.wait(msgs).await {
+                Ok(()) => return Ok(()),
+                Err(mpsc::error::SendTimeoutError::Timeout(msgs)) => {
+                    self.stall.give_up();
+                    msgs
+                }
+                Err(mpsc::error::SendTimeoutError::Closed(_)) => {
+                    return Err(format!("Connection to {} is closed", adr).into())
+                }
+            },
+            _ => msgs,
+        };
+        for _ in msgs {
+            record_drop(&self.stall.events, DropReason::SlowConsumer, Some(adr));
+        }
+        Err(format!("Peer {} is not reading", adr).into())
+    }
+
+    /// Waits for stalled peer within limits of wait policy
+    async fn wait(
+        &mut self,
+        msgs: Vec<Message>,
+    ) -> Result<(), mpsc::error::SendTimeoutError<Vec<Message>>> {
+        let stall = self.stall.clone();
+        let waiting = stall.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&stall.waiting);
+        if waiting >= MAX_WAITING_BATCHES || stall.gave_up.load(Ordering::Relaxed) {
+            return Err(mpsc::error::SendTimeoutError::Timeout(msgs));
+        }
+        self.batches.send_timeout(msgs, MAX_SLOW_CONSUMER_WAIT).await
+    }
+
+    /// Peer should be disconnected due to slow consumer policy
+    fn should_disconnect(&self) -> bool {
+        self.stall.gave_up.load(Ordering::Relaxed)
+            || (self.stall.since().is_some() && self.stall.policy == SlowConsumerPolicy::Disconnect)
+    }
+}
+
+/// Writer task of connection
+struct WriterHandle {
+    batches: mpsc::Sender<Vec<Message>>,
+    stall: Arc<Stall>,
+    close: oneshot::Sender<()>,
+}
+
+async fn write_batch(writer: &mut PeerWriter, batch: Vec<Message>) -> Result<(), Error> {
+    for m in batch {
+        writer.feed(m).await?;
+    }
+    writer.flush().await
+}
+
+/// Writes queued messages to peer in its own task, so peer, which does not read, blocks
+/// nobody else. When connection is closed, remaining queued messages and Terminate are
+/// written and writer is passed back to connection loop via `terminator`. Same happens
+/// (without Terminate) when write fails.
+fn spawn_writer(
+    supervisor: &Supervisor,
+    adr: SocketAddr,
+    mut writer: PeerWriter,
     terminator: ActivePeerTerminator,
-    writer: PeerWriter
+    connections: &OpenConnections,
+) -> WriterHandle {
+    let (batches, mut queue) = mpsc::channel(WRITE_QUEUE_SIZE);
+    let (close, mut closed) = oneshot::channel();
+    let stall = Arc::new(Stall {
+        adr,
+        policy: connections.slow_consumer_policy(),
+        events: connections.events.clone(),
+        since: std::sync::Mutex::new(None),
+        waiting: AtomicUsize::new(0),
+        gave_up: AtomicBool::new(false),
+    });
+    let handle = WriterHandle {
+        batches,
+        stall: stall.clone(),
+        close,
+    };
+    supervisor.spawn("writer", async move {
+        let mut closing = false;
+        loop {
+            let batch = tokio::select! {
+                batch = queue.recv() => match batch {
+                    Some(b) => b,
+                    None => break,
+                },
+                c = &mut closed => {
+                    closing = c.is_ok();
+                    break;
+                }
+            };
+            let frames = batch.len();
+            let started = clock::now();
+            let write = write_batch(&mut writer, batch);
+            futures::pin_mut!(write);
+            let res = match tokio::time::timeout(STALL_TIMEOUT, &mut write).await {
+                Ok(res) => res,
+                Err(_) => {
+                    stall.mark();
+                    tokio::select! {
+                        res = &mut write => res,
+                        c = &mut closed => {
+                            closing = c.is_ok();
+                            break;
+                        }
+                    }
+                }
+            };
+            match res {
+                Ok(()) => {
+                    stall.clear();
+                    record_flush(frames, clock::elapsed(started));
+                }
+                Err(e) => {
+                    debug!("Cannot write to {}: {}", adr, e);
+                    break;
+                }
+            }
+        }
+        if closing {
+            queue.close();
+            let finish = async {
+                while let Ok(batch) = queue.try_recv() {
+                    write_batch(&mut writer, batch).await?;
+                }
+                writer.send(Message::Terminate).await
+            };
+            // peer might not be reading (slow consumer), so do not wait forever
+            match tokio::time::timeout(STALL_TIMEOUT, finish).await {
+                Ok(Err(e)) => error!("Cannot send final message {}", e),
+                Err(_) => debug!("Peer {} does not read final message", adr),
+                Ok(Ok(())) => (),
+            }
+        }
+        // connection loop might be already finished
+        terminator.send(writer).ok();
+        Ok(())
+    });
+    handle
+}
+
+pub struct ActivePeer {
+    last_ping_ts: Option<Instant>,
+    /// Sequence number of last ping
+    ping_seq: u32,
+    /// Peer echoes ping sequence numbers
+    seq_pings: bool,
+    /// Outstanding ping was sent after previous one timed out, so pong without
+    /// sequence number cannot be timed (Karn's rule)
+    ping_ambiguous: bool,
+    rtt: RttEstimator,
+    phi: PhiAccrual,
+    stats: Arc<TrafficStats>,
+    since: SystemTime,
+    /// Last application message sent or received
+    last_used: Instant,
+    adr: SocketAddr,
+    info: PeerInfo,
+    /// Peer's UDP address, if both sides use UDP transport
+    udp: Option<SocketAddr>,
+    queue: PeerQueue,
+    /// Asks writer task to close connection
+    close: oneshot::Sender<()>,
 }
 
 impl ActivePeer {
+    /// Peer should be disconnected due to slow consumer policy
+    fn should_disconnect(&self) -> bool {
+        self.queue.should_disconnect()
+    }
 
-    async fn send(&mut self, m: Message) -> Result<(), Error>{
-        self.writer.send(m).await
+    /// Ping to send, None if outstanding ping still can be answered
+    fn keepalive(&mut self) -> Option<Message> {
+        if let Some(ts) = self.last_ping_ts {
+            if clock::elapsed(ts) < self.rtt.rto() {
+                // previous ping still can be answered
+                return None;
+            }
+            self.rtt.on_timeout();
+            self.ping_ambiguous = true;
+            debug!(
+                "Ping to {} timed out, new rto {:?}, loss {:.3}",
+                self.adr,
+                self.rtt.rto(),
+                self.rtt.loss()
+            );
+        }
+        self.last_ping_ts = Some(clock::now());
+        self.ping_seq = self.ping_seq.wrapping_add(1);
+        Some(if self.seq_pings {
+            Message::PingSeq { seq: self.ping_seq }
+        } else {
+            Message::Ping
+        })
+    }
+
+    /// Pong with echoed sequence number, or None for peers older than `PING_SEQ_VERSION`
+    fn pong_received(&mut self, seq: Option<u32>) {
+        let ts = match self.last_ping_ts {
+            Some(ts) => ts,
+            None => {
+                debug!("Unsolicited pong from {}", self.adr);
+                return;
+            }
+        };
+        if seq.is_some_and(|seq| seq != self.ping_seq) {
+            debug!("Late pong from {}, ignored", self.adr);
+            return;
+        }
+        self.last_ping_ts = None;
+        let ambiguous = seq.is_none() && self.ping_ambiguous;
+        self.ping_ambiguous = false;
+        if ambiguous {
+            debug!("Pong from {} may answer timed out ping, not used for rtt", self.adr);
+            return;
+        }
+        self.rtt.on_sample(clock::elapsed(ts));
+        debug!(
+            "Peer {} rtt {:?}, rto {:?}, quality {:.3}",
+            self.adr,
+            self.rtt.srtt(),
+            self.rtt.rto(),
+            self.rtt.quality()
+        );
+    }
+
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    pub fn state(&self) -> PeerState {
+        if self.queue.stall.since().is_some() {
+            return PeerState::Stalled;
+        }
+        let phi = self.suspicion();
+        if phi >= PHI_DEAD {
+            return PeerState::Unresponsive;
+        }
+        match self.last_ping_ts {
+            Some(ts) if clock::elapsed(ts) >= self.rtt.rto() => PeerState::Unstable,
+            _ if phi >= PHI_UNSTABLE => PeerState::Unstable,
+            _ => PeerState::Connected,
+        }
+    }
+
+    /// Suspicion level, that peer failed, based on arrival times of its keepalives
+    pub fn suspicion(&self) -> f64 {
+        self.phi.phi(clock::now())
     }
 
-    fn close(self) -> Result<(),Error> {
-        self.terminator.send(self.writer).map_err(|_| "cannot send via oneshot channel".into())
+    /// Connection address and addresses advertised by peer
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = vec![self.adr];
+        for a in self.listening_addrs() {
+            if !addrs.contains(&a) {
+                addrs.push(a)
+            }
+        }
+        addrs
+    }
+
+    /// Addresses advertised by peer, where it can be dialed
+    fn listening_addrs(&self) -> Vec<SocketAddr> {
+        self.info
+            .addrs
+            .iter()
+            .map(|a| {
+                let mut a = *a;
+                // peer listening on all interfaces is reachable on address we see it
+                if a.ip().is_unspecified() {
+                    a.set_ip(self.adr.ip())
+                }
+                a
+            })
+            .collect()
+    }
+
+    pub fn summary(&self) -> PeerSummary {
+        PeerSummary {
+            id: self.info.id.clone(),
+            name: None,
+            addrs: self.addrs(),
+            state: self.state(),
+            suspicion: self.suspicion(),
+            rtt_ms: self.rtt.srtt().map(|d| d.as_secs_f64() * 1000.0),
+            bytes_in: self.stats.bytes_in(),
+            bytes_out: self.stats.bytes_out(),
+            read_buffer_max: self.stats.read_buffer_max(),
+            since: self
+                .since
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    fn close(self) -> Result<(), Error> {
+        self.close.send(()).map_err(|_| "writer of connection already finished".into())
     }
 }
 
 type PeerWriter =
     futures::stream::SplitSink<tokio_util::codec::Framed<tokio::net::TcpStream, MsgCodec>, Message>;
+type PeerReader =
+    futures::stream::SplitStream<tokio_util::codec::Framed<tokio::net::TcpStream, MsgCodec>>;
 type ActivePeerTerminator = oneshot::Sender<PeerWriter>;
 
+fn shutdown_connection(writer: PeerWriter, reader: PeerReader) {
+    if let Ok(s) = writer.reunite(reader) {
+        s.get_ref()
+            .shutdown(std::net::Shutdown::Both)
+            .unwrap_or_else(|e| debug!("cannot shutdown socket {}", e));
+    } else {
+        error!("error in reunite!")
+    }
+}
 
+async fn reject(mut writer: PeerWriter, reader: PeerReader, code: ErrorCode, detail: &str) {
+    let msg = Message::ProtocolError {
+        code,
+        detail: detail.into(),
+    };
+    if let Err(e) = writer.send(msg).await {
+        error!("Cannot send protocol error {}", e);
+    }
+    shutdown_connection(writer, reader)
+}
+
+
+
+fn stalled_peers(sinks: &HashMap<SocketAddr, ActivePeer>) -> Vec<SocketAddr> {
+    sinks
+        .values()
+        .filter(|p| p.should_disconnect())
+        .map(|p| p.adr)
+        .collect()
+}
 
 #[derive(Clone)]
 pub struct OpenConnections {
     sinks: Arc<RwLock<HashMap<SocketAddr, ActivePeer>>>,
+    prewarm: Arc<std::sync::Mutex<Prewarmer>>,
+    slow_consumer: Arc<std::sync::Mutex<SlowConsumerPolicy>>,
+    idle: Arc<std::sync::Mutex<IdleReaper>>,
+    /// Default policy and policies of particular contacts
+    id_mismatch: Arc<std::sync::Mutex<(IdMismatchPolicy, HashMap<FriendlyId, IdMismatchPolicy>)>>,
+    raw: RawRouter,
+    udp: UdpTransport,
+    events: Events,
+    resolvers: Arc<Resolvers>,
 }
 
 impl OpenConnections {
     pub fn new() -> Self {
+        let events = Events::default();
         OpenConnections {
             sinks: Arc::new(RwLock::new(HashMap::new())),
+            prewarm: Arc::new(std::sync::Mutex::new(Prewarmer::new(0))),
+            slow_consumer: Arc::new(std::sync::Mutex::new(SlowConsumerPolicy::Disconnect)),
+            idle: Arc::new(std::sync::Mutex::new(IdleReaper::new(
+                IdlePolicy::Keep,
+                DEFAULT_IDLE_TIMEOUT,
+            ))),
+            id_mismatch: Arc::new(std::sync::Mutex::new((
+                IdMismatchPolicy::Disconnect,
+                HashMap::new(),
+            ))),
+            raw: RawRouter::new(events.clone()),
+            udp: UdpTransport::default(),
+            events,
+            resolvers: Arc::new(Resolvers::new()),
+        }
+    }
+
+    pub fn set_slow_consumer_policy(&self, policy: SlowConsumerPolicy) {
+        *self.slow_consumer.lock().unwrap() = policy
+    }
+
+    fn slow_consumer_policy(&self) -> SlowConsumerPolicy {
+        *self.slow_consumer.lock().unwrap()
+    }
+
+    pub fn set_id_mismatch_policy(&self, policy: IdMismatchPolicy) {
+        self.id_mismatch.lock().unwrap().0 = policy
+    }
+
+    pub fn set_contact_id_mismatch_policy(&self, id: FriendlyId, policy: Option<IdMismatchPolicy>) {
+        let contacts = &mut self.id_mismatch.lock().unwrap().1;
+        match policy {
+            Some(p) => contacts.insert(id, p),
+            None => contacts.remove(&id),
+        };
+    }
+
+    pub fn id_mismatch_policy(&self, expected: &FriendlyId) -> IdMismatchPolicy {
+        let policies = self.id_mismatch.lock().unwrap();
+        policies.1.get(expected).copied().unwrap_or(policies.0)
+    }
+
+    /// Closes connections to peers stalled by slow consumer policy
+    async fn disconnect_stalled(&self, peers: Vec<SocketAddr>) {
+        for addr in peers {
+            if let Some(p) = self.remove(&addr).await {
+                info!("Disconnecting slow peer {}", addr);
+                p.close()
+                    .unwrap_or_else(|e| error!("cannot close connection to {}: {}", addr, e));
+            }
+        }
+    }
+
+    pub fn set_prewarm_peers(&self, limit: usize) {
+        *self.prewarm.lock().unwrap() = Prewarmer::new(limit)
+    }
+
+    pub fn set_idle_policy(&self, policy: IdlePolicy, timeout: Duration) {
+        self.idle.lock().unwrap().set_policy(policy, timeout)
+    }
+
+    pub fn set_peer_idle_policy(&self, id: FriendlyId, policy: Option<IdlePolicy>) {
+        self.idle.lock().unwrap().set_peer_policy(id, policy)
+    }
+
+    pub fn parked_peers(&self) -> Vec<FriendlyId> {
+        self.idle.lock().unwrap().parked()
+    }
+
+    /// Disconnects peers without application messages according to idle policy
+    async fn reap_idle(&self) {
+        let now = clock::now();
+        let peers: Vec<_> = self
+            .sinks
+            .read()
+            .await
+            .values()
+            .map(|p| (p.adr, p.info.id.clone(), p.last_used))
+            .collect();
+        let due: Vec<_> = {
+            let idle = self.idle.lock().unwrap();
+            peers
+                .into_iter()
+                .filter_map(|(addr, id, last_used)| idle.due(&id, last_used, now).map(|p| (addr, p)))
+                .collect()
+        };
+        for (addr, policy) in due {
+            if let Some(p) = self.remove(&addr).await {
+                let id = p.info.id.clone();
+                if policy == IdlePolicy::Park {
+                    info!("Parking idle peer {} ({})", id, addr);
+                } else {
+                    info!("Closing idle connection to {} ({})", id, addr);
+                    self.resolvers.address_book().remove(&id);
+                }
+                self.idle.lock().unwrap().reaped(id, policy);
+                p.close()
+                    .unwrap_or_else(|e| error!("cannot close connection to {}: {}", addr, e));
+            }
         }
     }
 
-    pub async fn add_new(&self, peer: SocketAddr, writer: PeerWriter, terminator: ActivePeerTerminator) {
+    fn record_use(&self, peer: &mut ActivePeer) {
+        peer.last_used = clock::now();
+        self.prewarm
+            .lock()
+            .unwrap()
+            .record_use(&peer.info.id, peer.addrs())
+    }
+
+    /// Records application message received from peer
+    pub async fn message_received(&self, from: &SocketAddr) {
+        if let Some(p) = self.sinks.write().await.get_mut(from) {
+            self.record_use(p)
+        }
+    }
+
+    pub async fn is_connected(&self, id: &FriendlyId) -> bool {
+        self.sinks.read().await.values().any(|p| &p.info.id == id)
+    }
+
+    pub async fn count(&self) -> usize {
+        self.sinks.read().await.len()
+    }
+
+    pub async fn is_connected_to(&self, addr: &SocketAddr) -> bool {
+        self.sinks.read().await.contains_key(addr)
+    }
+
+    /// Id of peer connected from given address
+    pub async fn id_of(&self, addr: &SocketAddr) -> Option<FriendlyId> {
+        self.sinks.read().await.get(addr).map(|p| p.info.id.clone())
+    }
+
+    /// Connection address of peer with given id
+    pub async fn addr_of(&self, id: &FriendlyId) -> Option<SocketAddr> {
+        self.sinks
+            .read()
+            .await
+            .values()
+            .find(|p| &p.info.id == id)
+            .map(|p| p.adr)
+    }
+
+    /// Most used peers, which are not connected now and were not disconnected as idle
+    pub async fn prewarm_candidates(&self) -> Vec<(FriendlyId, Vec<SocketAddr>)> {
+        let connected: HashSet<FriendlyId> = self
+            .sinks
+            .read()
+            .await
+            .values()
+            .map(|p| p.info.id.clone())
+            .collect();
+        let mut candidates = self.prewarm.lock().unwrap().candidates(&connected);
+        let idle = self.idle.lock().unwrap();
+        candidates.retain(|(id, _)| !idle.is_reaped(id));
+        candidates
+    }
+
+    async fn add_new(
+        &self,
+        peer: SocketAddr,
+        info: PeerInfo,
+        version: Option<u32>,
+        writer: WriterHandle,
+        stats: Arc<TrafficStats>,
+    ) {
+        let udp = info
+            .udp_port
+            .filter(|_| self.udp.is_running())
+            .map(|port| SocketAddr::new(peer.ip(), port));
+        if let Some(udp_addr) = udp {
+            self.udp.add_peer(peer, udp_addr)
+        }
         let mut sinks = self.sinks.write().await;
-        sinks.insert(peer.clone(), ActivePeer{adr: peer, writer, terminator});
+        let active = ActivePeer {
+            adr: peer,
+            info,
+            udp,
+            queue: PeerQueue {
+                batches: writer.batches,
+                stall: writer.stall,
+            },
+            close: writer.close,
+            last_ping_ts: None,
+            ping_seq: 0,
+            seq_pings: version.is_some_and(|v| v >= PING_SEQ_VERSION),
+            ping_ambiguous: false,
+            rtt: RttEstimator::new(),
+            phi: PhiAccrual::new(KEEPALIVE_INTERVAL),
+            stats,
+            since: SystemTime::now(),
+            last_used: clock::now(),
+        };
+        self.resolvers
+            .address_book()
+            .add(active.info.id.clone(), active.listening_addrs());
+        uptime::peer_connected(active.info.id.clone());
+        self.idle.lock().unwrap().connected(&active.info.id);
+        self.raw.open_inbox(peer);
+        self.events.emit(NodeEvent::PeerConnected {
+            peer,
+            id: active.info.id.clone(),
+        });
+        sinks.insert(peer, active);
     }
 
     pub async fn remove(&self, peer: &SocketAddr) -> Option<ActivePeer> {
         let mut sinks = self.sinks.write().await;
-        sinks.remove(peer)
+        let removed = sinks.remove(peer);
+        if let Some(ref p) = removed {
+            if let Some(ref udp_addr) = p.udp {
+                self.udp.remove_peer(udp_addr)
+            }
+            uptime::peer_disconnected(&p.info.id);
+            self.raw.close_inbox(peer);
+            self.events.emit(NodeEvent::PeerDisconnected {
+                peer: *peer,
+                id: p.info.id.clone(),
+            });
+        }
+        removed
+    }
+
+    /// Peer's UDP address, if UDP transport is negotiated with it
+    pub async fn udp_addr(&self, peer: &SocketAddr) -> Option<SocketAddr> {
+        self.sinks.read().await.get(peer).and_then(|p| p.udp)
+    }
+
+    /// Closes all connections, each peer is sent Terminate message
+    pub async fn close_all(&self) {
+        let peers: Vec<ActivePeer> = self.sinks.write().await.drain().map(|(_, p)| p).collect();
+        for p in peers {
+            if let Some(ref udp_addr) = p.udp {
+                self.udp.remove_peer(udp_addr)
+            }
+            uptime::peer_disconnected(&p.info.id);
+            self.raw.close_inbox(&p.adr);
+            self.events.emit(NodeEvent::PeerDisconnected {
+                peer: p.adr,
+                id: p.info.id.clone(),
+            });
+            let adr = p.adr;
+            p.close()
+                .unwrap_or_else(|e| error!("cannot close connection to {}: {}", adr, e));
+        }
     }
 
     pub async fn send(&self, to: SocketAddr, msg: Message) -> Result<(), Error> {
-        match OPEN_CONNECTION.sinks.write().await.get_mut(&to) {
-            Some(s) => s.send(msg).await,
-            None => Err(format!("Connection to {} is not available ", &to).into()),
+        self.send_all(to, vec![msg]).await
+    }
+
+    pub async fn send_all(&self, to: SocketAddr, msgs: Vec<Message>) -> Result<(), Error> {
+        let queue = match self.sinks.write().await.get_mut(&to) {
+            Some(s) => {
+                if msgs.iter().any(|m| !m.is_control()) {
+                    self.record_use(s)
+                }
+                s.queue.clone()
+            }
+            None => {
+                record_drop(&self.events, DropReason::UnknownPeer, Some(to));
+                return Err(format!("Connection to {} is not available ", &to).into());
+            }
+        };
+        let disconnect = queue.clone();
+        let res = queue.send_all(msgs).await;
+        if disconnect.should_disconnect() {
+            self.disconnect_stalled(vec![to]).await;
+        }
+        res
+    }
+
+    /// Sends message to all connected peers except excluded ones, returns number of peers
+    /// message was sent to
+    pub async fn broadcast(
+        &self,
+        msg: Message,
+        except: &[SocketAddr],
+        scope: BroadcastScope,
+    ) -> usize {
+        let recipients: Vec<_> = {
+            let mut sinks = self.sinks.write().await;
+            sinks
+                .iter_mut()
+                .filter(|(a, _)| {
+                    !except.contains(a) && (scope == BroadcastScope::All || is_local_addr(a.ip()))
+                })
+                .map(|(addr, p)| {
+                    if !msg.is_control() {
+                        self.record_use(p)
+                    }
+                    (*addr, p.queue.clone())
+                })
+                .collect()
+        };
+        let mut sent = 0;
+        for (addr, queue) in recipients {
+            match queue.send_all(vec![msg.clone()]).await {
+                Ok(()) => sent += 1,
+                Err(e) => error!("Broadcast to {} failed: {}", addr, e),
+            }
+        }
+        let stalled = stalled_peers(&*self.sinks.read().await);
+        self.disconnect_stalled(stalled).await;
+        sent
+    }
+
+    /// Pings all peers, which do not have outstanding ping
+    pub async fn keepalive(&self) {
+        let pings: Vec<_> = self
+            .sinks
+            .write()
+            .await
+            .values_mut()
+            .filter_map(|p| p.keepalive().map(|ping| (p.queue.clone(), ping)))
+            .collect();
+        for (queue, ping) in pings {
+            queue
+                .send_all(vec![ping])
+                .await
+                .unwrap_or_else(|e| error!("Ping send error {}", e));
+        }
+        let stalled = stalled_peers(&*self.sinks.read().await);
+        self.disconnect_stalled(stalled).await;
+        self.reap_idle().await;
+    }
+
+    pub async fn list_peers(&self) -> Vec<PeerSummary> {
+        let sinks = self.sinks.read().await;
+        let mut peers: Vec<_> = sinks.values().map(ActivePeer::summary).collect();
+        peers.sort_by_key(|p| p.since);
+        peers
+    }
+
+    pub async fn pong_received(&self, from: &SocketAddr, seq: Option<u32>) {
+        if let Some(p) = self.sinks.write().await.get_mut(from) {
+            p.pong_received(seq)
         }
     }
+
+    pub async fn heartbeat(&self, from: &SocketAddr) {
+        if let Some(p) = self.sinks.write().await.get_mut(from) {
+            p.phi.heartbeat(clock::now())
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Reports peer with different protocol version or clock too far from ours
+fn check_compatibility(
+    events: &Events,
+    peer: SocketAddr,
+    id: &FriendlyId,
+    version: Option<u32>,
+    timestamp: Option<u64>,
+) {
+    // includes one way delay of Hello, which should be much smaller than tolerated skew
+    let skew_ms = timestamp.map(|ts| ts as i64 - unix_millis() as i64);
+    let skewed = skew_ms.is_some_and(|s| u128::from(s.unsigned_abs()) >= MAX_CLOCK_SKEW.as_millis());
+    if skewed || version != Some(PROTOCOL_VERSION) {
+        warn!(
+            "Peer {} ({}) protocol version {:?} (ours {}), clock skew {:?} ms",
+            id, peer, version, PROTOCOL_VERSION, skew_ms
+        );
+        events.emit(NodeEvent::PeerCompatibility {
+            peer,
+            id: id.clone(),
+            version,
+            skew_ms,
+        });
+    }
 }
 
 async fn handle_connection(
+    node: Node,
+    my_info: PeerInfo,
     socket: TcpStream,
     mut tx: tokio::sync::mpsc::Sender<(Message, std::net::SocketAddr)>,
+    guard: Option<ConnectionGuard>,
+    handshake: Option<HandshakeSlot>,
+    expected: Option<FriendlyId>,
 ) {
     let peer = socket.peer_addr().unwrap();
     info!("Connected by client {:?}", peer);
-    let (mut writer, mut reader) = MsgCodec::new().framed(socket).split();
+    let supervisor = node.supervisor.clone();
+    let codec = MsgCodec::new();
+    let stats = codec.stats();
+    let (mut writer, mut reader) = codec.framed(socket).split();
     let my_hello = Message::Hello {
         msg: "Hello from me".into(),
+        info: node.advertised_info(my_info),
+        observed_addr: peer,
+        version: Some(PROTOCOL_VERSION),
+        timestamp: Some(unix_millis()),
     };
     let (terminator, mut terminator_receiver) = oneshot::channel();
 
     let receiving_loop_future = async move {
+        // keeps listener slot until connection ends
+        let _guard = guard;
+        let mut handshake = handshake;
+        if let Some(ref mut slot) = handshake {
+            slot.acquire().await;
+        }
         match writer.send(my_hello).await {
             Ok(()) => {
-                match reader.next().await {
-                    Some(Ok(Message::Hello { msg })) => {
-                        debug!("Client {} connected with hello message {}", peer, msg);
-                        OPEN_CONNECTION.add_new(peer, writer, terminator).await;
+                let mut state = ConnectionState::AwaitingHello;
+                let first = match tokio::time::timeout(HANDSHAKE_TIMEOUT, reader.next()).await {
+                    Err(_) => {
+                        record_protocol_error(ProtocolErrorKind::HandshakeTimeout, peer, &"no Hello");
+                        reject(writer, reader, ErrorCode::InvalidHandshake, "handshake timeout").await;
+                        return;
+                    }
+                    Ok(Some(Ok(m))) => m,
+                    Ok(Some(Err(e))) => {
+                        record_protocol_error(ProtocolErrorKind::InvalidHandshake, peer, &e);
+                        reject(writer, reader, ErrorCode::InvalidHandshake, "expected Hello").await;
+                        return;
+                    }
+                    Ok(None) => {
+                        debug!("Client {} closed connection before handshake", peer);
+                        return;
                     }
-                    _ => error!("invalid handshake"),
                 };
-
-                
-
-                 loop {
-
-                    match future::select(reader.next(), &mut terminator_receiver).await {
-                        Either::Left((Some(m), _)) => match m {
-                            Ok(m) => {
-                                if let Err(_) = tx.send((m, peer.clone())).await {
-                                    error!("internal error in incoming channel");
+                if let Err(v) = state.on_received(&first) {
+                    record_protocol_error(ProtocolErrorKind::InvalidHandshake, peer, &v);
+                    reject(writer, reader, ErrorCode::InvalidHandshake, v.detail).await;
+                    return;
+                }
+                match first {
+                    Message::Hello {
+                        msg,
+                        info,
+                        observed_addr,
+                        version,
+                        timestamp,
+                    } => {
+                        debug!(
+                            "Client {} ({}) connected with hello message {}, sees us as {}",
+                            peer, info.id, msg, observed_addr
+                        );
+                        if let Some(expected) = expected {
+                            if expected != info.id {
+                                record_protocol_error(
+                                    ProtocolErrorKind::UnexpectedId,
+                                    peer,
+                                    &format!("has id {}, but {} was expected", info.id, expected),
+                                );
+                                let policy = node.connections.id_mismatch_policy(&expected);
+                                let disconnect = policy == IdMismatchPolicy::Disconnect;
+                                node.connections.events.emit(NodeEvent::IdMismatch {
+                                    peer,
+                                    expected,
+                                    actual: info.id.clone(),
+                                    disconnected: disconnect,
+                                });
+                                if disconnect {
+                                    reject(writer, reader, ErrorCode::InvalidHandshake, "unexpected peer id")
+                                        .await;
+                                    return;
                                 }
                             }
-                            
-                            Err(e) => error!("error in incoming stream {}", e)
                         }
+                        check_compatibility(&node.connections.events, peer, &info.id, version, timestamp);
+                        node.observed
+                            .lock()
+                            .unwrap()
+                            .record(peer.ip(), observed_addr);
+                        let writer = spawn_writer(
+                            &node.supervisor,
+                            peer,
+                            writer,
+                            terminator,
+                            &node.connections,
+                        );
+                        node.connections.add_new(peer, info, version, writer, stats).await;
+                        drop(handshake.take());
+                        state = ConnectionState::Established;
+                    }
+                    Message::ProtocolError { code, detail } => {
+                        error!("Client {} rejected connection: {} - {}", peer, code, detail);
+                        return;
+                    }
+                    _ => unreachable!("state machine accepts only Hello or ProtocolError"),
+                };
 
-                        Either::Left((None, _)) => break,
-                        Either::Right((Ok(mut writer), _)) => {
-                            if let Err(e) = writer.send(Message::Terminate).await {
-                                error!("Cannot send final message {}", e);
-                            };
+                // connection closed while waiting for peer's inbox
+                let mut closing = None;
+                loop {
+                    let event = match closing.take() {
+                        Some(c) => Either::Right(c),
+                        None => match future::select(reader.next(), &mut terminator_receiver).await {
+                            Either::Left((m, _)) => Either::Left(m),
+                            Either::Right((c, _)) => Either::Right(c),
+                        },
+                    };
+                    match event {
+                        Either::Left(Some(m)) => match m {
+                            Ok(m) => match state.on_received(&m) {
+                                Ok(new_state) => {
+                                    let deliver = state.delivers_messages();
+                                    state = new_state;
+                                    if !deliver {
+                                        record_drop(&node.connections.events, DropReason::ConnectionClosing, Some(peer));
+                                    } else {
+                                        if let Message::Raw { .. } = m {
+                                            // stops reading from peer, while its inbox is full
+                                            let reserving = node.raw().reserve(peer);
+                                            futures::pin_mut!(reserving);
+                                            let reserved =
+                                                future::select(reserving, &mut terminator_receiver).await;
+                                            if let Either::Right((c, _)) = reserved {
+                                                record_drop(&node.connections.events, DropReason::ConnectionClosing, Some(peer));
+                                                closing = Some(c);
+                                                continue;
+                                            }
+                                        }
+                                        if tx.send((m, peer)).await.is_err() {
+                                            error!("internal error in incoming channel");
+                                            record_drop(&node.connections.events, DropReason::QueueOverflow, Some(peer));
+                                        }
+                                    }
+                                }
+                                Err(v) => {
+                                    record_protocol_error(ProtocolErrorKind::Violation, peer, &v);
+                                    record_drop(&node.connections.events, DropReason::ProtocolViolation, Some(peer));
+                                    state = state.on_local_close();
+                                    close_with_error(&node, peer, ErrorCode::ProtocolViolation, v.detail)
+                                        .await;
+                                }
+                            },
 
-                            if let Ok(s) =  writer.reunite(reader) {
-                                s.get_ref().shutdown(std::net::Shutdown::Both).unwrap_or_else(|e| error!("cannot shutdown socket {}", e));
-                            } else {
-                                error!("error in reunite!")
+                            Err(e) => {
+                                record_protocol_error(ProtocolErrorKind::Malformed, peer, &e);
+                                record_drop(&node.connections.events, DropReason::Malformed, Some(peer));
                             }
+                        }
+
+                        Either::Left(None) => {
+                            state = state.on_stream_end();
+                            break;
+                        }
+                        Either::Right(Ok(writer)) => {
+                            state = state.on_local_close();
+                            shutdown_connection(writer, reader);
                             break
                         }
-                        Either::Right((Err(e), _)) => {
+                        Either::Right(Err(e)) => {
                             error!("terminator error {}", e);
                             break
                         }
 
                     }
                 }
-                    
 
-                let _p = OPEN_CONNECTION.remove(&peer).await;
-                
-                debug!("Connection done for {}", peer);
+                let _p = node.connections.remove(&peer).await;
+
+                debug!("Connection done for {} in state {:?}", peer, state);
             }
             Err(e) => error!("error sending hello message {}", e),
         }
     };
 
-    tokio::spawn(receiving_loop_future);
+    supervisor.spawn("connection", async move {
+        receiving_loop_future.await;
+        Ok(())
+    });
+}
+
+async fn accept_loop(
+    node: Node,
+    mut listener: Listener,
+    my_info: PeerInfo,
+    tx: tokio::sync::mpsc::Sender<(Message, std::net::SocketAddr)>,
+    handshakes: HandshakeLimiter,
+) {
+    let mut health = ListenerHealth::new(node.health.clone());
+    loop {
+        let accepted = listener.accept().await;
+        if accepted.is_ok() {
+            health.accepted()
+        }
+        match accepted {
+            Ok((socket, peer)) => match listener.admit(&peer) {
+                Ok(guard) => match handshakes.admit() {
+                    Some(slot) => {
+                        let (guard, slot) = (Some(guard), Some(slot));
+                        handle_connection(node.clone(), my_info.clone(), socket, tx.clone(), guard, slot, None)
+                            .await
+                    }
+                    None => {
+                        debug!("Shedding connection from {}, too many pending handshakes", peer);
+                        record_shed();
+                        let (writer, reader) = MsgCodec::new().framed(socket).split();
+                        node.supervisor.spawn("reject", async move {
+                            reject(writer, reader, ErrorCode::Busy, "too many pending handshakes").await;
+                            Ok(())
+                        });
+                    }
+                },
+                Err((code, detail)) => {
+                    info!("Rejecting connection from {}: {}", peer, detail);
+                    let (writer, reader) = MsgCodec::new().framed(socket).split();
+                    node.supervisor.spawn("reject", async move {
+                        reject(writer, reader, code, detail).await;
+                        Ok(())
+                    });
+                }
+            },
+            Err(e) => {
+                health.failed();
+                error!("error accepting incoming stream: {}", e)
+            }
+        }
+    }
+}
+
+/// Sends ProtocolError to established connection and closes it
+async fn close_with_error(node: &Node, peer: SocketAddr, code: ErrorCode, detail: &str) {
+    let msg = Message::ProtocolError {
+        code,
+        detail: detail.into(),
+    };
+    node.connections
+        .send(peer, msg)
+        .await
+        .unwrap_or_else(|e| error!("Cannot send protocol error {}", e));
+    if let Some(ap) = node.connections.remove(&peer).await {
+        ap.close()
+            .unwrap_or_else(|e| error!("cannot close writer: {}", e));
+    }
+}
+
+type IncomingSender = mpsc::Sender<(Message, SocketAddr)>;
+
+fn shuffle<T>(items: &mut [T]) {
+    use std::hash::{BuildHasher, Hasher};
+    let state = std::collections::hash_map::RandomState::new();
+    for i in (1..items.len()).rev() {
+        let mut h = state.build_hasher();
+        h.write_usize(i);
+        let j = (h.finish() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Connects to peer, trying given addresses in order,
+/// if `expected` id is given, peer must present it in Hello
+async fn connect(
+    node: Node,
+    addrs: Vec<SocketAddr>,
+    my_info: PeerInfo,
+    tx: tokio::sync::mpsc::Sender<(Message, std::net::SocketAddr)>,
+    expected: Option<FriendlyId>,
+) -> Result<(), Error> {
+    let mut last_error: Error = "no address to connect to".into();
+    for addr in addrs {
+        match TcpStream::connect(&addr).await {
+            Ok(socket) => {
+                handle_connection(node, my_info, socket, tx, None, None, expected).await;
+                return Ok(());
+            }
+            Err(e) => {
+                debug!("Connect error to {}: {}", addr, e);
+                last_error = format!("cannot connect to {}: {}", addr, e).into();
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Node instance - owns its connections, observed addresses, dialing state, tasks,
+/// raw protocols, signaling channel, bots, events, health status, resolvers and UDP
+/// transport, so more nodes can run in one process.
+///
+/// Uptime tracking and telemetry are still process-wide. Default node is used by free
+/// functions of this module and run by `run_client`, RPC endpoint, webhooks and health
+/// endpoints have variants for other nodes.
+#[derive(Clone)]
+pub struct Node {
+    connections: OpenConnections,
+    observed: Arc<std::sync::Mutex<ObservedAddrs>>,
+    supervisor: Supervisor,
+    signaling: SignalingSlot,
+    /// Ends `run`, set while node is running
+    stop: Arc<std::sync::Mutex<Option<oneshot::Sender<()>>>>,
+    /// Our info and incoming channel, set when node is running, so we can dial on demand
+    dialer: Arc<std::sync::RwLock<Option<(PeerInfo, IncomingSender)>>>,
+    health: Arc<HealthStatus>,
 }
 
 lazy_static! {
-    static ref OPEN_CONNECTION: OpenConnections = OpenConnections::new();
-}
-
-pub async fn run_client(port: u16, peers: Option<Vec<SocketAddr>>) -> Result<(), Error> {
-    info!("Started client on port {}", port);
-    let (tx, mut rx) = mpsc::channel(1024);
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    let mut server = TcpListener::bind(&addr).await?;
-
-    let tx2 = tx.clone();
-    let server_loop = server
-        .incoming()
-        .filter_map(|s| {
-            future::ready(
-                s.map_err(|e| error!("error accepting incoming stream: {}", e))
-                    .ok(),
-            )
+    static ref DEFAULT_NODE: Node = Node::with_supervisor(supervisor::global());
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Node::with_supervisor(Supervisor::new())
+    }
+
+    fn with_supervisor(supervisor: Supervisor) -> Self {
+        Node {
+            connections: OpenConnections::new(),
+            observed: Arc::new(std::sync::Mutex::new(ObservedAddrs::new())),
+            supervisor,
+            signaling: Arc::new(std::sync::Mutex::new(None)),
+            stop: Arc::new(std::sync::Mutex::new(None)),
+            dialer: Arc::new(std::sync::RwLock::new(None)),
+            health: Arc::new(HealthStatus::default()),
+        }
+    }
+
+    pub(crate) fn raw(&self) -> &RawRouter {
+        &self.connections.raw
+    }
+
+    /// Registers raw protocol on this node, see `raw::register_raw_protocol`
+    pub fn register_raw_protocol(&self, name: &str) -> Result<RawProtocol, Error> {
+        raw::register(self, name, true)
+    }
+
+    /// Registers raw protocol on this node, see `raw::register_raw_protocol_with_ack`
+    pub fn register_raw_protocol_with_ack(&self, name: &str) -> Result<RawProtocol, Error> {
+        raw::register(self, name, false)
+    }
+
+    pub(crate) fn signaling(&self) -> &SignalingSlot {
+        &self.signaling
+    }
+
+    /// Opens signaling channel of this node, see `signaling::open_signaling`
+    pub fn open_signaling(&self) -> Result<Signaling, Error> {
+        signaling::open(self)
+    }
+
+    pub(crate) fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    /// Subscribes to events of this node, slow subscribers lose oldest events
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<NodeEvent> {
+        self.connections.events.subscribe()
+    }
+
+    /// Subscribes to events of this node matching filter, see `filter::subscribe_events_filtered`
+    pub fn subscribe_events_filtered(&self, filter: &str) -> Result<FilteredEvents, Error> {
+        filter::filtered(self.subscribe_events(), filter)
+    }
+
+    /// Delivers events of this node to listener until node is shut down, see
+    /// `events::add_event_listener`
+    pub fn add_event_listener(&self, listener: Arc<dyn EventListener>) {
+        events::listen(&self.supervisor, &self.connections.events, listener)
+    }
+
+    /// Health state of this node, reported by health endpoints
+    pub fn health(&self) -> Arc<HealthStatus> {
+        self.health.clone()
+    }
+
+    /// Address book and other resolvers used to dial peers by id
+    pub fn resolvers(&self) -> &Resolvers {
+        &self.connections.resolvers
+    }
+
+    /// Number of running tasks of this node by name
+    pub fn running_tasks(&self) -> Vec<(&'static str, usize)> {
+        self.supervisor.running_tasks()
+    }
+
+    fn dialer(&self) -> Result<(PeerInfo, IncomingSender), Error> {
+        self.dialer
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| "Client is not running".into())
+    }
+
+    /// Our public address, as agreed by peers
+    pub fn public_addr(&self) -> Option<PublicAddr> {
+        self.observed.lock().unwrap().public_addr()
+    }
+
+    /// Adds public address to advertised addresses - public IP with our listening ports
+    fn advertised_info(&self, mut info: PeerInfo) -> PeerInfo {
+        if let Some(public) = self.public_addr() {
+            let local: Vec<SocketAddr> = info
+                .addrs
+                .iter()
+                .filter(|a| !a.ip().is_loopback())
+                .cloned()
+                .collect();
+            // for listeners on unspecified address we cannot tell
+            let specific: Vec<_> = local.iter().filter(|a| !a.ip().is_unspecified()).collect();
+            info.uses_nat = !specific.is_empty() && !specific.iter().any(|a| a.ip() == public.ip);
+            for a in local {
+                let a = SocketAddr::new(public.ip, a.port());
+                if !info.addrs.contains(&a) {
+                    info.addrs.push(a)
+                }
+            }
+        }
+        info
+    }
+
+    /// Starts connecting to peer on given addresses, if `expected` id is given
+    /// connection is accepted only if peer presents this id
+    pub fn connect_peer(&self, addrs: Vec<SocketAddr>, expected: Option<FriendlyId>) -> Result<(), Error> {
+        let (my_info, tx) = self.dialer()?;
+        if let Some(ref id) = expected {
+            self.resolvers().address_book().add(id.clone(), addrs.clone());
+        }
+        let connecting = connect(self.clone(), addrs, my_info, tx, expected);
+        self.supervisor.spawn("connect", connecting);
+        Ok(())
+    }
+
+    /// Sends message to peer with given id, if peer is not connected, its addresses are
+    /// resolved and it is dialed first
+    pub async fn send_to(&self, id: &FriendlyId, msg: Message) -> Result<(), Error> {
+        let addr = match self.connections.addr_of(id).await {
+            Some(addr) => addr,
+            None => {
+                let addrs = self.resolvers().resolve(id).await;
+                if addrs.is_empty() {
+                    return Err(format!("Cannot resolve address of {}", id).into());
+                }
+                let (my_info, tx) = self.dialer()?;
+                connect(self.clone(), addrs, my_info, tx, Some(id.clone())).await?;
+                // handshake completes in connection task
+                let start = clock::now();
+                loop {
+                    if let Some(addr) = self.connections.addr_of(id).await {
+                        break addr;
+                    }
+                    if clock::elapsed(start) >= HANDSHAKE_TIMEOUT {
+                        return Err(format!("Handshake with {} did not complete", id).into());
+                    }
+                    tokio::time::delay_for(Duration::from_millis(20)).await;
+                }
+            }
+        };
+        self.connections.send(addr, msg).await
+    }
+
+    /// Sends chat message to peer with given id, dialing it if needed like `send_to`
+    pub async fn send_text(&self, to: &FriendlyId, body: String) -> Result<(), Error> {
+        let from = self.my_id().ok_or("Client is not running")?;
+        let msg = Message::Chat {
+            from,
+            to: Some(to.clone()),
+            body,
+            ts: unix_millis(),
+        };
+        self.send_to(to, msg).await
+    }
+
+    /// Id of this node, None if node is not running
+    pub fn my_id(&self) -> Option<FriendlyId> {
+        self.dialer.read().unwrap().as_ref().map(|(i, _)| i.id.clone())
+    }
+
+    /// Invite for this node - our id and addresses where we can be reached,
+    /// `None` if node is not running or does not listen on any usable address
+    pub fn my_invite(&self, name: Option<String>) -> Option<Invite> {
+        let info = self.dialer.read().unwrap().as_ref().map(|(i, _)| i.clone())?;
+        let info = self.advertised_info(info);
+        let addrs: Vec<SocketAddr> = info
+            .addrs
+            .into_iter()
+            .filter(|a| !a.ip().is_unspecified())
+            .collect();
+        if addrs.is_empty() {
+            return None;
+        }
+        Some(Invite {
+            id: info.id,
+            addrs,
+            name,
         })
-        .for_each(move |socket| handle_connection(socket, tx.clone()));
-
-    let receiving_loop = async {
-        while let Some((msg, peer)) = rx.next().await {
-            debug!("Received message {:#?} from {:?}", msg, peer);
-            use self::Message::*;
-            match msg {
-                Hello { .. } => {
-                    error!("should not receive hello here");
-                }
-                Ping => OPEN_CONNECTION
-                    .send(peer, Pong)
-                    .await
-                    .unwrap_or_else(|e| error!("Pong send error {}", e)),
-                Pong => {
-                    info!("Got Pong");
-                }
-                Terminate => {
-                    info!("Got Terminate");
-                    if let Some(ap) = OPEN_CONNECTION.remove(&peer).await {
-                        ap.close()
-                            .unwrap_or_else(|e| error!("cannot close writer: {}", e));
-                    };
+    }
+
+    /// Sets idle policy of peer, overriding default one from ClientConfig, None reverts to default
+    pub fn set_peer_idle_policy(&self, id: FriendlyId, policy: Option<IdlePolicy>) {
+        self.connections.set_peer_idle_policy(id, policy)
+    }
+
+    /// Sets policy for contact presenting other id than `id` when dialed, overriding default one
+    /// from ClientConfig, None reverts to default
+    pub fn set_contact_id_mismatch_policy(&self, id: FriendlyId, policy: Option<IdMismatchPolicy>) {
+        self.connections.set_contact_id_mismatch_policy(id, policy)
+    }
+
+    /// Peers, which connections were parked as idle, they can be dialed by id when needed
+    pub fn parked_peers(&self) -> Vec<FriendlyId> {
+        self.connections.parked_peers()
+    }
+
+    /// Lists peers currently connected to this node
+    pub async fn list_peers(&self) -> Vec<PeerSummary> {
+        self.connections.list_peers().await
+    }
+
+    /// Sends message to connected peer - it is queued for peer's writer, waits while queue
+    /// is full according to slow consumer policy
+    pub async fn send(&self, to: SocketAddr, msg: Message) -> Result<(), Error> {
+        self.connections.send(to, msg).await
+    }
+
+    /// Sends messages to peer in one write, it is more efficient than sending them one by one
+    pub async fn send_all(&self, to: SocketAddr, msgs: Vec<Message>) -> Result<(), Error> {
+        self.connections.send_all(to, msgs).await
+    }
+
+    /// Sends message to connected peers in scope except given ones, returns number of peers
+    /// it was sent to
+    pub async fn broadcast(&self, msg: Message, except: &[SocketAddr], scope: BroadcastScope) -> usize {
+        self.connections.broadcast(msg, except, scope).await
+    }
+
+    /// Sends message to connected peer over UDP transport, if negotiated with peer,
+    /// suitable for small latency-sensitive messages. Falls back to TCP connection,
+    /// so there is no ordering between messages sent by `send_fast` and `send`.
+    pub async fn send_fast(&self, to: SocketAddr, msg: Message) -> Result<(), Error> {
+        if let Some(udp_addr) = self.connections.udp_addr(&to).await {
+            match self.connections.udp.send(udp_addr, msg.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!("Cannot send to {} over UDP, using TCP: {}", to, e),
+            }
+        }
+        self.connections.send(to, msg).await
+    }
+
+    /// Messages sent to peer by `send_fast`, which were not acknowledged yet. Only UDP
+    /// transport tracks messages - `send` over TCP completes, when message is queued for peer's
+    /// writer, it is not retransmitted.
+    pub async fn pending(&self, peer: SocketAddr) -> Vec<udp::PendingMessage> {
+        match self.connections.udp_addr(&peer).await {
+            Some(udp_addr) => self.connections.udp.pending(&udp_addr),
+            None => vec![],
+        }
+    }
+
+    /// Stops retransmitting pending message to peer, returns false if it is not pending anymore
+    pub async fn cancel_pending(&self, peer: SocketAddr, seq: u64) -> bool {
+        match self.connections.udp_addr(&peer).await {
+            Some(udp_addr) => self.connections.udp.cancel_pending(&udp_addr, seq),
+            None => false,
+        }
+    }
+
+    /// Gracefully closes connections of this node and stops its tasks, `run` then returns
+    /// and node can be run again. Other nodes in process are not affected.
+    pub async fn shutdown(&self) {
+        self.connections.close_all().await;
+        // give connection tasks chance to send Terminate
+        tokio::time::delay_for(SHUTDOWN_GRACE).await;
+        self.supervisor.stop_all();
+        *self.dialer.write().unwrap() = None;
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            stop.send(()).ok();
+        }
+    }
+
+    /// Announces us and dials nodes found on LAN
+    #[cfg(feature = "discovery")]
+    async fn discover_peers(&self, id: RawId, my_info: PeerInfo, tx: IncomingSender) {
+        let my_id = my_info.id.clone();
+        let port = my_info
+            .addrs
+            .iter()
+            .find(|a| !a.ip().is_loopback())
+            .or_else(|| my_info.addrs.first())
+            .map(|a| a.port());
+        let beacon = port.map(|port| Beacon {
+            id,
+            port,
+            capabilities: CAP_LISTENING,
+        });
+        let (found_tx, mut found_rx) = mpsc::channel(16);
+        self.supervisor.spawn_restartable("discovery", move || {
+            run_discovery(beacon.clone(), found_tx.clone())
+        });
+        // discovery task keeps sender, so this ends only with node
+        while let Some((id, addr)) = found_rx.recv().await {
+            let id = FriendlyId::from(id);
+            self.resolvers().address_book().add(id.clone(), vec![addr]);
+            // only one side dials, so there are not two connections between nodes
+            if my_id < id && !self.connections.is_connected(&id).await {
+                info!("Connecting to discovered node {} on {}", id, addr);
+                self.supervisor.spawn(
+                    "connect",
+                    connect(self.clone(), vec![addr], my_info.clone(), tx.clone(), Some(id)),
+                );
+            }
+        }
+    }
+
+    #[cfg(not(feature = "discovery"))]
+    async fn discover_peers(&self, _id: RawId, _my_info: PeerInfo, _tx: IncomingSender) {
+        warn!("LAN discovery is not available, library is built without discovery feature")
+    }
+
+    /// Runs node with given listeners - if systemd passes listening sockets (socket activation),
+    /// they are used instead of configured listeners. With no listeners node runs in outbound only
+    /// mode, it just connects to given peers.
+    pub async fn run(&self, config: ClientConfig, id: RawId) -> Result<(), Error> {
+        let ClientConfig {
+            listeners,
+            peers,
+            bootstrap_parallel,
+            bootstrap_target,
+            prewarm_peers,
+            discovery,
+            udp,
+            slow_consumer,
+            idle_policy,
+            idle_timeout,
+            max_handshakes,
+            shed_handshakes,
+            id_mismatch,
+        } = config;
+        let my_id = FriendlyId::from(&id);
+        let (tx, mut rx) = mpsc::channel(1024);
+        self.supervisor.resume();
+        let (stop_tx, stopped) = oneshot::channel();
+        *self.stop.lock().unwrap() = Some(stop_tx);
+        let passed = systemd::take_listen_sockets();
+        let servers = if !passed.is_empty() {
+            let mut servers = Vec::with_capacity(passed.len());
+            for listener in passed {
+                info!(
+                    "Using listening socket {:?} passed by systemd",
+                    listener.local_addr()
+                );
+                servers.push(Listener::from_std(listener)?);
+            }
+            servers
+        } else {
+            let mut servers = Vec::with_capacity(listeners.len());
+            for l in listeners {
+                servers.push(Listener::bind(l).await?);
+            }
+            servers
+        };
+        let udp_socket = match servers.first() {
+            Some(l) if udp => Some(self.connections.udp.bind(l.local_addr()).await?),
+            _ => None,
+        };
+        let my_info = PeerInfo {
+            id: my_id.clone(),
+            addrs: servers.iter().map(Listener::local_addr).collect(),
+            name: None,
+            uses_nat: false,
+            udp_port: udp_socket.as_ref().and(servers.first()).map(|l| l.local_addr().port()),
+        };
+        uptime::node_started();
+        if servers.is_empty() {
+            info!("Started client {} in outbound only mode", my_id);
+        } else {
+            info!("Started client {} listening on {:?}", my_id, my_info.addrs);
+        }
+        self.health.set_outbound_only(servers.is_empty());
+        self.health.set_listening(!servers.is_empty());
+        self.health.set_bootstrap_peers(peers.len());
+        let connections = &self.connections;
+        connections.set_prewarm_peers(prewarm_peers);
+        connections.set_slow_consumer_policy(slow_consumer);
+        connections.set_idle_policy(idle_policy, idle_timeout);
+        connections.set_id_mismatch_policy(id_mismatch);
+        *self.dialer.write().unwrap() = Some((my_info.clone(), tx.clone()));
+
+        let tx2 = tx.clone();
+        let my_info2 = my_info.clone();
+        let handshakes = HandshakeLimiter::new(max_handshakes, shed_handshakes);
+        let server_loop = future::join_all(servers.into_iter().map(|l| {
+            accept_loop(self.clone(), l, my_info.clone(), tx.clone(), handshakes.clone())
+        }));
+
+        let receiving_loop = async {
+            while let Some((msg, peer)) = rx.next().await {
+                debug!("Received message {:#?} from {:?}", msg, peer);
+                if !msg.is_control() {
+                    connections.message_received(&peer).await;
+                }
+                use self::Message::*;
+                match msg {
+                    Hello { .. } => {
+                        error!("should not receive hello here");
+                    }
+                    Ping => {
+                        connections.heartbeat(&peer).await;
+                        connections
+                            .send(peer, Pong)
+                            .await
+                            .unwrap_or_else(|e| error!("Pong send error {}", e))
+                    }
+                    PingSeq { seq } => {
+                        connections.heartbeat(&peer).await;
+                        connections
+                            .send(peer, PongSeq { seq })
+                            .await
+                            .unwrap_or_else(|e| error!("Pong send error {}", e))
+                    }
+                    Pong => connections.pong_received(&peer, None).await,
+                    PongSeq { seq } => connections.pong_received(&peer, Some(seq)).await,
+                    ProtocolError { code, detail } => {
+                        error!("Got protocol error from {}: {} - {}", peer, code, detail);
+                    }
+                    Raw { protocol, data } => self.raw().dispatch(peer, protocol, data),
+                    Signal { session, signal } => {
+                        signaling::dispatch(&self.signaling, &connections.events, peer, session, signal)
+                    }
+                    Chat { from, to, body, ts } => {
+                        // sender must not speak for other peers
+                        if connections.id_of(&peer).await.as_ref() != Some(&from) {
+                            warn!("Dropping chat from {} claiming to be {}", peer, from);
+                        } else if to.as_ref().is_some_and(|to| *to != my_id) {
+                            debug!("Dropping chat from {} for other node {:?}", from, to);
+                        } else {
+                            connections.events.emit(NodeEvent::ChatReceived {
+                                node: my_id.clone(),
+                                peer,
+                                from,
+                                to,
+                                body,
+                                ts,
+                            });
+                        }
+                    }
+                    Terminate => {
+                        info!("Got Terminate");
+                        if let Some(ap) = connections.remove(&peer).await {
+                            ap.close()
+                                .unwrap_or_else(|e| error!("cannot close writer: {}", e));
+                        };
+                    }
+                };
+            }
+        };
+
+        let connect_known = async {
+            if peers.is_empty() {
+                return;
+            }
+            // random order, so first seed is not overloaded
+            let mut peers = peers;
+            shuffle(&mut peers);
+            let mut queue: VecDeque<SocketAddr> = peers.into();
+            let mut ticker = tokio::time::interval(BOOTSTRAP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let mut connected = connections.count().await;
+                // each peer is tried at most once per round, rest is left for next round
+                let mut remaining = queue.len();
+                while connected < bootstrap_target && remaining > 0 {
+                    let mut batch = Vec::with_capacity(bootstrap_parallel);
+                    while batch.len() < bootstrap_parallel.max(1) && remaining > 0 {
+                        remaining -= 1;
+                        let addr = queue.pop_front().unwrap();
+                        queue.push_back(addr);
+                        if !connections.is_connected_to(&addr).await {
+                            batch.push(addr);
+                        }
+                    }
+                    let results = future::join_all(batch.iter().map(|a| {
+                        connect(self.clone(), vec![*a], my_info2.clone(), tx2.clone(), None)
+                    }))
+                    .await;
+                    for (addr, res) in batch.iter().zip(results) {
+                        match res {
+                            Ok(()) => connected += 1,
+                            Err(e) => debug!("Bootstrap peer {} failed: {}", addr, e),
+                        }
+                    }
                 }
+            }
+        };
+
+        let prewarm_loop = async {
+            if prewarm_peers == 0 {
+                return;
+            }
+            let mut ticker = tokio::time::interval(PREWARM_INTERVAL);
+            loop {
+                ticker.tick().await;
+                for (id, addrs) in connections.prewarm_candidates().await {
+                    debug!("Pre-warming connection to {}", id);
+                    let connecting = connect(self.clone(), addrs, my_info.clone(), tx.clone(), Some(id));
+                    self.supervisor.spawn("connect", connecting);
+                }
+            }
+        };
+
+        let keepalive_loop = async {
+            let mut ticker = tokio::time::interval(KEEPALIVE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                connections.keepalive().await;
+                log_protocol_error_summary();
+            }
+        };
+
+        let discovery_loop = async {
+            if discovery {
+                self.discover_peers(id.clone(), my_info.clone(), tx.clone()).await
+            }
+        };
+
+        if let Some(socket) = udp_socket {
+            let (raw, events) = (self.raw().clone(), self.connections.events.clone());
+            let udp = self.connections.udp.clone();
+            self.supervisor.spawn_critical("udp", udp.run(socket, tx.clone(), raw, events));
+        }
+
+        let node = async {
+            join!(
+                server_loop,
+                receiving_loop,
+                connect_known,
+                keepalive_loop,
+                prewarm_loop,
+                discovery_loop
+            );
+            Ok(())
+        };
+        futures::pin_mut!(node);
+        let interrupted = future::select(self.supervisor.fatal_error().boxed(), stopped);
+        let res = match future::select(node, interrupted).await {
+            Either::Left((res, _)) => res,
+            Either::Right((Either::Left((e, _)), _)) => Err(e),
+            Either::Right((Either::Right(_), _)) => {
+                info!("Node {} stopped", my_id);
+                Ok(())
+            }
+        };
+        self.health.set_listening(false);
+        self.connections.udp.stop().await;
+        res
+    }
+}
+
+/// Node used by free functions of this module, it is run by `run_client`
+pub fn default_node() -> Node {
+    DEFAULT_NODE.clone()
+}
+
+/// Our public address, as agreed by peers
+pub fn public_addr() -> Option<PublicAddr> {
+    DEFAULT_NODE.public_addr()
+}
+
+/// Starts connecting to peer on given addresses, if `expected` id is given
+/// connection is accepted only if peer presents this id
+pub fn connect_peer(addrs: Vec<SocketAddr>, expected: Option<FriendlyId>) -> Result<(), Error> {
+    DEFAULT_NODE.connect_peer(addrs, expected)
+}
+
+/// Sends message to peer with given id, if peer is not connected, its addresses are
+/// resolved and it is dialed first
+pub async fn send_to(id: &FriendlyId, msg: Message) -> Result<(), Error> {
+    DEFAULT_NODE.send_to(id, msg).await
+}
+
+/// Sends chat message to peer with given id, dialing it if needed like `send_to`
+pub async fn send_text(to: &FriendlyId, body: String) -> Result<(), Error> {
+    DEFAULT_NODE.send_text(to, body).await
+}
+
+/// Id of this node, None if node is not running
+pub fn my_id() -> Option<FriendlyId> {
+    DEFAULT_NODE.my_id()
+}
+
+/// Invite for this client - our id and addresses where we can be reached,
+/// `None` if client is not running or does not listen on any usable address
+pub fn my_invite(name: Option<String>) -> Option<Invite> {
+    DEFAULT_NODE.my_invite(name)
+}
+
+/// Sets idle policy of peer, overriding default one from ClientConfig, None reverts to default
+pub fn set_peer_idle_policy(id: FriendlyId, policy: Option<IdlePolicy>) {
+    DEFAULT_NODE.set_peer_idle_policy(id, policy)
+}
+
+/// Sets policy for contact presenting other id than `id` when dialed, overriding default one
+/// from ClientConfig, None reverts to default
+pub fn set_contact_id_mismatch_policy(id: FriendlyId, policy: Option<IdMismatchPolicy>) {
+    DEFAULT_NODE.set_contact_id_mismatch_policy(id, policy)
+}
+
+/// Peers, which connections were parked as idle, they can be dialed by id when needed
+pub fn parked_peers() -> Vec<FriendlyId> {
+    DEFAULT_NODE.parked_peers()
+}
+
+/// Lists peers currently connected to this client
+pub async fn list_peers() -> Vec<PeerSummary> {
+    DEFAULT_NODE.list_peers().await
+}
+
+/// Sends message to connected peer
+pub async fn send(to: SocketAddr, msg: Message) -> Result<(), Error> {
+    DEFAULT_NODE.send(to, msg).await
+}
+
+/// Sends messages to peer in one write, it is more efficient than sending them one by one
+pub async fn send_all(to: SocketAddr, msgs: Vec<Message>) -> Result<(), Error> {
+    DEFAULT_NODE.send_all(to, msgs).await
+}
+
+/// Sends message to all connected peers, returns number of peers it was sent to
+pub async fn broadcast(msg: Message) -> usize {
+    DEFAULT_NODE.broadcast(msg, &[], BroadcastScope::All).await
+}
+
+/// Sends message to all connected peers except given ones - e.g. relay must not
+/// echo message back to its origin
+pub async fn broadcast_except(msg: Message, except: &[SocketAddr]) -> usize {
+    DEFAULT_NODE.broadcast(msg, except, BroadcastScope::All).await
+}
+
+/// Sends message only to peers connected directly from local network (e.g. discovered
+/// on LAN), returns number of peers it was sent to. Frame carries no scope, so applications
+/// relaying messages should not relay frames of protocols used for local announcements.
+pub async fn broadcast_local(msg: Message) -> usize {
+    DEFAULT_NODE.broadcast(msg, &[], BroadcastScope::Local).await
+}
+
+/// Sends message to connected peer over UDP transport, if negotiated with peer,
+/// suitable for small latency-sensitive messages. Falls back to TCP connection,
+/// so there is no ordering between messages sent by `send_fast` and `send`.
+pub async fn send_fast(to: SocketAddr, msg: Message) -> Result<(), Error> {
+    DEFAULT_NODE.send_fast(to, msg).await
+}
+
+/// Messages sent to peer by `send_fast`, which were not acknowledged yet. Only UDP
+/// transport tracks messages - `send` over TCP completes, when message is queued for peer's
+/// writer, it is not retransmitted.
+pub async fn pending(peer: SocketAddr) -> Vec<udp::PendingMessage> {
+    DEFAULT_NODE.pending(peer).await
+}
+
+/// Stops retransmitting pending message to peer, returns false if it is not pending anymore
+pub async fn cancel_pending(peer: SocketAddr, seq: u64) -> bool {
+    DEFAULT_NODE.cancel_pending(peer, seq).await
+}
+
+/// Gracefully shuts down default node, should be called before process exits. Its event
+/// listeners, webhooks and bots are stopped too and are not restarted with node.
+pub async fn shutdown() {
+    info!("Shutting down client");
+    DEFAULT_NODE.shutdown().await;
+    uptime::node_stopped();
+}
+
+/// Runs default node, see `Node::run`
+pub async fn run_client(config: ClientConfig, id: RawId) -> Result<(), Error> {
+    DEFAULT_NODE.run(config, id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::codec::MAX_FRAME_SIZE;
+    use tokio::sync::broadcast::RecvError;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    async fn read_line(s: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        s.read_line(&mut line).await.unwrap();
+        line
+    }
+
+    /// Reads rest of data until node closes connection, reset is also fine
+    async fn read_to_close(mut s: BufReader<TcpStream>) -> String {
+        let mut data = vec![];
+        let _ = s.read_to_end(&mut data).await;
+        String::from_utf8_lossy(&data).into_owned()
+    }
+
+    async fn adversary(addr: SocketAddr, data: &[u8]) -> String {
+        let mut s = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        assert!(read_line(&mut s).await.contains("Hello"));
+        // node can close connection before we write everything
+        let _ = s.get_mut().write_all(data).await;
+        read_to_close(s).await
+    }
+
+    #[test]
+    fn test_is_local_addr() {
+        for a in &["192.168.1.10", "10.0.0.1", "172.20.1.1", "169.254.3.3", "127.0.0.1", "::1"] {
+            assert!(is_local_addr(a.parse().unwrap()), "{} is local", a);
+        }
+        for a in &["fd12::1", "fe80::1", "::ffff:192.168.0.1"] {
+            assert!(is_local_addr(a.parse().unwrap()), "{} is local", a);
+        }
+        for a in &["8.8.8.8", "172.32.0.1", "2001:db8::1", "::ffff:1.1.1.1"] {
+            assert!(!is_local_addr(a.parse().unwrap()), "{} is not local", a);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_adversarial_peers() {
+        let mut config = ClientConfig::new(
+            vec![ListenerConfig::new("127.0.0.1:0".parse().unwrap())],
+            vec![],
+        );
+        config.prewarm_peers = 0;
+        tokio::spawn(run_client(config, RawId::random()));
+        let addr = loop {
+            if let Some(invite) = my_invite(None) {
+                break invite.addrs[0];
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        };
+        // virtual time, so handshake timeout can be reached immediately
+        tokio::time::pause();
+
+        let res = adversary(addr, b"{not json\n").await;
+        assert!(res.contains("InvalidHandshake"));
+
+        let res = adversary(addr, b"\"Ping\"\n").await;
+        assert!(res.contains("InvalidHandshake"));
+
+        // connection is closed, node does not buffer whole frame
+        adversary(addr, &vec![b'x'; MAX_FRAME_SIZE + 1024]).await;
+
+        for _ in 0..100 {
+            drop(TcpStream::connect(addr).await.unwrap());
+        }
+
+        // slowloris - trickles bytes, but never finishes Hello
+        let mut s = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        assert!(read_line(&mut s).await.contains("Hello"));
+        s.get_mut().write_all(b"{\"Hel").await.unwrap();
+        tokio::time::advance(HANDSHAKE_TIMEOUT).await;
+        let res = read_to_close(s).await;
+        assert!(res.contains("handshake timeout"));
+
+        // node still works for well behaved peer
+        let mut s = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        assert!(read_line(&mut s).await.contains("Hello"));
+        let hello = Message::Hello {
+            msg: "hi".into(),
+            info: PeerInfo {
+                id: RawId::random().into(),
+                addrs: vec![],
+                name: None,
+                uses_nat: false,
+                udp_port: None,
+            },
+            observed_addr: addr,
+            version: None,
+            timestamp: None,
+        };
+        let mut data = serde_json::to_vec(&hello).unwrap();
+        data.push(b'\n');
+        s.get_mut().write_all(&data).await.unwrap();
+        s.get_mut().write_all(b"\"Ping\"\n").await.unwrap();
+        assert!(read_line(&mut s).await.contains("Pong"));
+        assert_eq!(1, list_peers().await.len());
+    }
+
+    async fn start(node: &Node) -> (Invite, tokio::task::JoinHandle<Result<(), Error>>) {
+        start_with_udp(node, false).await
+    }
+
+    async fn start_with_udp(
+        node: &Node,
+        udp: bool,
+    ) -> (Invite, tokio::task::JoinHandle<Result<(), Error>>) {
+        let mut config = ClientConfig::new(
+            vec![ListenerConfig::new("127.0.0.1:0".parse().unwrap())],
+            vec![],
+        );
+        config.prewarm_peers = 0;
+        config.udp = udp;
+        let n = node.clone();
+        let running = tokio::spawn(async move { n.run(config, RawId::random()).await });
+        loop {
+            if let Some(invite) = node.my_invite(None) {
+                break (invite, running);
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Starts two nodes, b connected to a
+    async fn start_pair() -> (Node, Node, Invite, tokio::task::JoinHandle<Result<(), Error>>) {
+        let (a, b) = (Node::new(), Node::new());
+        let (invite, a_running) = start(&a).await;
+        start(&b).await;
+        b.connect_peer(invite.addrs.clone(), Some(invite.id.clone())).unwrap();
+        while a.list_peers().await.is_empty() || b.list_peers().await.is_empty() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        (a, b, invite, a_running)
+    }
+
+    #[tokio::test]
+    async fn test_udp_per_node() {
+        let (a, b) = (Node::new(), Node::new());
+        let (invite, a_running) = start_with_udp(&a, true).await;
+        start_with_udp(&b, true).await;
+        b.connect_peer(invite.addrs.clone(), Some(invite.id.clone())).unwrap();
+        while a.list_peers().await.is_empty() || b.list_peers().await.is_empty() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        let mut a_raw = a.register_raw_protocol("test_udp").unwrap();
+        let to_a = b.list_peers().await[0].addrs[0];
+        assert!(b.connections.udp_addr(&to_a).await.is_some());
+        let msg = Message::Raw {
+            protocol: "test_udp".into(),
+            data: b"fast".to_vec(),
+        };
+        b.send_fast(to_a, msg).await.unwrap();
+        assert_eq!(b"fast".to_vec(), a_raw.recv().await.unwrap().1);
+
+        // transport is reset with node, so it can be run again
+        a.shutdown().await;
+        assert!(a_running.await.unwrap().is_ok());
+        assert!(!a.connections.udp.is_running());
+        assert!(b.connections.udp.is_running());
+        start_with_udp(&a, true).await;
+        assert!(a.connections.udp.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_late_pong_not_sampled() {
+        let (a, _b, _, _) = start_pair().await;
+        let mut sinks = a.connections.sinks.write().await;
+        let p = sinks.values_mut().next().unwrap();
+        assert!(p.seq_pings);
+        let sent = clock::now() - Duration::from_secs(10);
+        let srtt = p.rtt.srtt();
+
+        p.last_ping_ts = Some(sent);
+        p.ping_seq = 5;
+        p.pong_received(Some(4));
+        assert_eq!(srtt, p.rtt.srtt());
+        assert_eq!(Some(sent), p.last_ping_ts);
+        p.pong_received(Some(5));
+        assert_ne!(srtt, p.rtt.srtt());
+        assert_eq!(None, p.last_ping_ts);
+
+        // Karn's rule for peers without sequence numbers
+        let srtt = p.rtt.srtt();
+        p.last_ping_ts = Some(sent);
+        p.ping_ambiguous = true;
+        p.pong_received(None);
+        assert_eq!(srtt, p.rtt.srtt());
+        assert_eq!(None, p.last_ping_ts);
+    }
+
+    /// Connects to node, does handshake and never reads
+    async fn stuck_peer(node: &Node, invite: &Invite) -> (TcpStream, SocketAddr) {
+        let mut s = BufReader::new(TcpStream::connect(invite.addrs[0]).await.unwrap());
+        assert!(read_line(&mut s).await.contains("Hello"));
+        let id: FriendlyId = RawId::random().into();
+        let hello = Message::Hello {
+            msg: "hi".into(),
+            info: PeerInfo {
+                id: id.clone(),
+                addrs: vec![],
+                name: None,
+                uses_nat: false,
+                udp_port: None,
+            },
+            observed_addr: invite.addrs[0],
+            version: Some(PROTOCOL_VERSION),
+            timestamp: None,
+        };
+        let frame = crate::protocol::frame::encode(&hello).unwrap();
+        s.get_mut().write_all(&frame).await.unwrap();
+        let addr = loop {
+            if let Some(addr) = node.connections.addr_of(&id).await {
+                break addr;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        };
+        (s.into_inner(), addr)
+    }
+
+    #[tokio::test]
+    async fn test_stalled_peer_does_not_block_others() {
+        let (a, b, invite, _) = start_pair().await;
+        a.connections.set_slow_consumer_policy(SlowConsumerPolicy::Wait);
+        let (_stuck, stuck_addr) = stuck_peer(&a, &invite).await;
+        let mut b_raw = b.register_raw_protocol("test_stalled").unwrap();
+        let sender = a.clone();
+        tokio::spawn(async move {
+            for _ in 0..100 {
+                let msg = Message::Raw {
+                    protocol: "test_stalled".into(),
+                    data: vec![0; 1 << 20],
+                };
+                if sender.send(stuck_addr, msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+        // let writes to stuck peer fill socket buffers
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+
+        let others = async {
+            assert_eq!(2, a.list_peers().await.len());
+            let to_b = a.connections.addr_of(&b.my_id().unwrap()).await.unwrap();
+            let msg = Message::Raw {
+                protocol: "test_stalled".into(),
+                data: b"still here".to_vec(),
             };
+            a.send(to_b, msg).await.unwrap();
+            assert_eq!(b"still here".to_vec(), b_raw.recv().await.unwrap().1);
+        };
+        tokio::time::timeout(Duration::from_secs(2), others)
+            .await
+            .expect("stalled peer blocked other peers");
+    }
+
+    #[tokio::test]
+    async fn test_close_peer_with_full_inbox() {
+        let (a, b, _, _) = start_pair().await;
+        let _a_raw = a.register_raw_protocol("test_full_inbox").unwrap();
+        let b_raw = b.register_raw_protocol("test_full_inbox").unwrap();
+        let to_a = b.list_peers().await[0].addrs[0];
+        for _ in 0..=raw::INBOX_SIZE {
+            b_raw.send(to_a, vec![1]).await.unwrap();
         }
-    };
+        // let a's connection block on full inbox
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let from_b = a.list_peers().await[0].addrs[0];
+        a.connections.remove(&from_b).await.unwrap().close().unwrap();
+        // b is sent Terminate and a's connection task ends
+        let closed = async {
+            while !b.list_peers().await.is_empty() || !a.running_tasks().is_empty() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("connection with full inbox was not closed");
+    }
 
-    let connect_known = async {
-        if let Some(peers) = peers {
-            for addr in peers {
-                let tx3 = tx2.clone();
-                tokio::spawn(async move {
-                    match TcpStream::connect(&addr).await {
-                        Ok(socket) => handle_connection(socket, tx3).await,
-                        Err(e) => error!("Connect error {}", e),
-                    }
-                });
+    #[tokio::test]
+    async fn test_nodes_in_one_process() {
+        let (a, b, invite, a_running) = start_pair().await;
+        assert_eq!(invite.id, b.list_peers().await[0].id);
+
+        struct ChatBot(tokio::sync::mpsc::UnboundedSender<(FriendlyId, String)>);
+        impl crate::bot::Bot for ChatBot {
+            fn name(&self) -> &str {
+                "chat"
+            }
+            fn protocols(&self) -> Vec<String> {
+                vec![]
+            }
+            fn receives_chat(&self) -> bool {
+                true
+            }
+            fn on_chat<'a>(
+                &'a self,
+                _ctx: &'a crate::bot::BotContext,
+                from: &'a FriendlyId,
+                body: &'a str,
+            ) -> futures::future::BoxFuture<'a, Result<(), Error>> {
+                let _ = self.0.send((from.clone(), body.into()));
+                Box::pin(future::ok(()))
             }
         }
-    };
+        let (a_tx, mut a_chat) = tokio::sync::mpsc::unbounded_channel();
+        let (b_tx, mut b_chat) = tokio::sync::mpsc::unbounded_channel();
+        crate::bot::add_bot_to(&a, Arc::new(ChatBot(a_tx)), 10).unwrap();
+        crate::bot::add_bot_to(&b, Arc::new(ChatBot(b_tx)), 10).unwrap();
 
-    join!(server_loop, receiving_loop, connect_known);
-    Ok(())
+        let mut events = a.subscribe_events();
+        let mut b_events = b.subscribe_events();
+        b.send_text(&invite.id, "hi a".into()).await.unwrap();
+        loop {
+            // node emits other events too
+            match events.recv().await {
+                Ok(NodeEvent::ChatReceived {
+                    node,
+                    from,
+                    to,
+                    body,
+                    ..
+                }) if body == "hi a" => {
+                    assert_eq!(invite.id, node);
+                    assert_eq!(b.my_id(), Some(from));
+                    assert_eq!(Some(invite.id), to);
+                    break;
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => (),
+                Err(e) => panic!("Events closed: {}", e),
+            }
+        }
+
+        // each node has its own raw protocols
+        let mut a_raw = a.register_raw_protocol("test_nodes").unwrap();
+        let b_raw = b.register_raw_protocol("test_nodes").unwrap();
+        b_raw.send(b.list_peers().await[0].addrs[0], b"raw".to_vec()).await.unwrap();
+        assert_eq!(b"raw".to_vec(), a_raw.recv().await.unwrap().1);
+        drop((a_raw, b_raw));
+
+        // chat goes only to bots and events of receiving node
+        assert_eq!((b.my_id().unwrap(), "hi a".into()), a_chat.recv().await.unwrap());
+        assert!(b_chat.try_recv().is_err());
+        while let Ok(e) = b_events.try_recv() {
+            assert!(!matches!(e, NodeEvent::ChatReceived { .. }), "{:?}", e);
+        }
+
+        // stopped node can be run again, other node is not affected
+        a.shutdown().await;
+        assert!(a_running.await.unwrap().is_ok());
+        assert!(a.running_tasks().is_empty());
+        let (invite, _) = start(&a).await;
+        b.connect_peer(invite.addrs, Some(invite.id.clone())).unwrap();
+        while a.list_peers().await.is_empty() {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
 }
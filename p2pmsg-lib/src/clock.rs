@@ -0,0 +1,13 @@
+//! Single source of time for internal timers. It follows tokio clock, so tests can
+//! pause it and fast-forward with `tokio::time::pause`/`advance` (tokio `test-util` feature).
+
+use std::time::{Duration, Instant};
+
+pub fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+/// Time elapsed since `earlier` according to this clock
+pub fn elapsed(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
@@ -0,0 +1,139 @@
+//! Minimal LAN discovery by UDP multicast beacons - fallback where mDNS is not available.
+//!
+//! Beacon is fixed 41 bytes: magic `P2PM`, version (1 byte), raw peer id (32 bytes),
+//! listening port (2 bytes) and capabilities bit flags (2 bytes), integers in big endian.
+
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::error::Error;
+use crate::protocol::id::RawId;
+
+pub const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 80, 77);
+pub const DISCOVERY_PORT: u16 = 12346;
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(10);
+
+const MAGIC: &[u8; 4] = b"P2PM";
+const VERSION: u8 = 1;
+const BEACON_SIZE: usize = 4 + 1 + 32 + 2 + 2;
+
+/// Node accepts incoming connections on advertised port
+pub const CAP_LISTENING: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Beacon {
+    pub id: RawId,
+    pub port: u16,
+    pub capabilities: u16,
+}
+
+impl Beacon {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(BEACON_SIZE);
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(self.id.as_bytes());
+        buf.extend_from_slice(&self.port.to_be_bytes());
+        buf.extend_from_slice(&self.capabilities.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Beacon> {
+        if data.len() != BEACON_SIZE || &data[..4] != MAGIC || data[4] != VERSION {
+            return None;
+        }
+        let id = RawId::new(data[5..37].try_into().ok()?);
+        let port = u16::from_be_bytes(data[37..39].try_into().ok()?);
+        let capabilities = u16::from_be_bytes(data[39..41].try_into().ok()?);
+        Some(Beacon {
+            id,
+            port,
+            capabilities,
+        })
+    }
+}
+
+fn bind_multicast() -> Result<UdpSocket, Error> {
+    let builder = net2::UdpBuilder::new_v4()?;
+    builder.reuse_address(true)?;
+    #[cfg(unix)]
+    {
+        use net2::unix::UnixUdpBuilderExt;
+        builder.reuse_port(true)?;
+    }
+    let socket = builder.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+    let socket = UdpSocket::from_std(socket)?;
+    socket.join_multicast_v4(MULTICAST_GROUP, Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket)
+}
+
+/// Sends our beacon periodically (if we have one - node is listening) and reports
+/// addresses of other discovered nodes.
+pub async fn run_discovery(
+    my_beacon: Option<Beacon>,
+    mut found: mpsc::Sender<(RawId, SocketAddr)>,
+) -> Result<(), Error> {
+    let socket = bind_multicast()?;
+    let (mut recv, mut send) = socket.split();
+    let my_id = my_beacon.as_ref().map(|b| b.id.clone());
+    info!("LAN discovery running on {}:{}", MULTICAST_GROUP, DISCOVERY_PORT);
+
+    let sending = async move {
+        if let Some(beacon) = my_beacon {
+            let data = beacon.encode();
+            let target = SocketAddr::from((MULTICAST_GROUP, DISCOVERY_PORT));
+            let mut ticker = tokio::time::interval(BEACON_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = send.send_to(&data, &target).await {
+                    error!("Cannot send discovery beacon: {}", e);
+                }
+            }
+        }
+    };
+
+    let receiving = async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (n, from) = recv.recv_from(&mut buf).await?;
+            match Beacon::decode(&buf[..n]) {
+                Some(b) if Some(&b.id) == my_id.as_ref() => (),
+                Some(b) if b.capabilities & CAP_LISTENING != 0 => {
+                    let addr = SocketAddr::new(from.ip(), b.port);
+                    debug!("Discovered node {} on {}", b.id, addr);
+                    if found.send((b.id, addr)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Some(_) => (),
+                None => debug!("Invalid discovery beacon from {}", from),
+            }
+        }
+    };
+
+    let (_, res): ((), Result<(), Error>) = futures::join!(sending, receiving);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacon() {
+        let b = Beacon {
+            id: RawId::random(),
+            port: 12345,
+            capabilities: CAP_LISTENING,
+        };
+        let data = b.encode();
+        assert_eq!(BEACON_SIZE, data.len());
+        assert_eq!(Some(b), Beacon::decode(&data));
+        assert_eq!(None, Beacon::decode(&data[1..]));
+        assert_eq!(None, Beacon::decode(&[0u8; BEACON_SIZE]));
+    }
+}
@@ -0,0 +1,144 @@
+//! Events emitted by the node for integrators
+
+use futures::future::BoxFuture;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, RecvError};
+
+use crate::client;
+use crate::protocol::id::FriendlyId;
+use crate::supervisor::Supervisor;
+use crate::telemetry::DropReason;
+
+const EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub enum NodeEvent {
+    MessageDropped {
+        reason: DropReason,
+        peer: Option<SocketAddr>,
+    },
+    /// Peer stopped reading, our send made no progress for STALL_TIMEOUT, emitted again
+    /// with `disconnected` when wait policy gives up on peer
+    SlowConsumer {
+        peer: SocketAddr,
+        /// Peer is disconnected due to slow consumer policy
+        disconnected: bool,
+    },
+    /// Peer uses different protocol version (None for peers not sending it) or its clock
+    /// differs from ours by more than MAX_CLOCK_SKEW
+    PeerCompatibility {
+        peer: SocketAddr,
+        id: FriendlyId,
+        version: Option<u32>,
+        /// Peer's clock minus ours, when it sent Hello
+        skew_ms: Option<i64>,
+    },
+    /// Dialed peer presented other id than expected one, connection is closed,
+    /// unless id mismatch policy is warn
+    IdMismatch {
+        peer: SocketAddr,
+        expected: FriendlyId,
+        actual: FriendlyId,
+        disconnected: bool,
+    },
+    /// Connection to peer finished handshake
+    PeerConnected { peer: SocketAddr, id: FriendlyId },
+    /// Connection to peer was closed
+    PeerDisconnected { peer: SocketAddr, id: FriendlyId },
+    /// Chat message from connected peer, `to` is None for message to everyone
+    ChatReceived {
+        /// Id of node, which received the message
+        node: FriendlyId,
+        peer: SocketAddr,
+        from: FriendlyId,
+        to: Option<FriendlyId>,
+        body: String,
+        /// Sender's wall clock time, unix milliseconds
+        ts: u64,
+    },
+}
+
+/// Events channel of one node, so events of nodes in same process are not mixed
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<NodeEvent>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Events(broadcast::channel(EVENTS_CAPACITY).0)
+    }
+}
+
+impl Events {
+    /// Subscribes to events, slow subscribers lose oldest events
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        self.0.subscribe()
+    }
+
+    pub(crate) fn emit(&self, event: NodeEvent) {
+        // error just means there are no subscribers
+        let _ = self.0.send(event);
+    }
+}
+
+/// Subscribes to events of default node, slow subscribers lose oldest events
+pub fn subscribe_events() -> broadcast::Receiver<NodeEvent> {
+    client::default_node().subscribe_events()
+}
+
+/// Callback alternative to `subscribe_events` for embedders, which do not poll streams
+pub trait EventListener: Send + Sync {
+    /// Called for each event in order, next event is delivered after returned future completes
+    fn on_event<'a>(&'a self, event: &'a NodeEvent) -> BoxFuture<'a, ()>;
+}
+
+/// Delivers events of default node to listener until node is shut down. Must be called
+/// within runtime. Like other subscribers listener loses oldest events, if it is slow.
+pub fn add_event_listener(listener: Arc<dyn EventListener>) {
+    client::default_node().add_event_listener(listener)
+}
+
+/// Runs listener of events in supervisor's task
+pub(crate) fn listen(supervisor: &Supervisor, events: &Events, listener: Arc<dyn EventListener>) {
+    let mut events = events.subscribe();
+    supervisor.spawn("event listener", async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => listener.on_event(&event).await,
+                Err(RecvError::Lagged(n)) => warn!("Event listener missed {} events", n),
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    struct Forward(mpsc::UnboundedSender<NodeEvent>);
+
+    impl EventListener for Forward {
+        fn on_event<'a>(&'a self, event: &'a NodeEvent) -> BoxFuture<'a, ()> {
+            let _ = self.0.send(event.clone());
+            Box::pin(futures::future::ready(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_listener() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let events = Events::default();
+        listen(&Supervisor::new(), &events, Arc::new(Forward(tx)));
+        let peer: SocketAddr = "10.1.2.3:4567".parse().unwrap();
+        events.emit(NodeEvent::SlowConsumer {
+            peer,
+            disconnected: false,
+        });
+        match rx.recv().await.unwrap() {
+            NodeEvent::SlowConsumer { peer: p, .. } => assert_eq!(peer, p),
+            e => panic!("Unexpected event {:?}", e),
+        }
+    }
+}
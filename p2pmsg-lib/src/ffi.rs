@@ -0,0 +1,325 @@
+//! C API for embedding node in applications written in other languages, see `include/p2pmsg.h`.
+//! Node runs in its own thread with its own runtime, it can be started once per process.
+//! Strings are UTF-8, NUL terminated, owned by caller.
+
+use futures::future::{self, BoxFuture};
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::oneshot;
+
+use crate::client::{self, ClientConfig};
+use crate::events::{EventListener, NodeEvent};
+use crate::identity;
+use crate::listener::ListenerConfig;
+use crate::protocol::message::Message;
+
+pub const P2PMSG_OK: c_int = 0;
+pub const P2PMSG_INVALID_ARGUMENT: c_int = 1;
+pub const P2PMSG_NOT_RUNNING: c_int = 2;
+pub const P2PMSG_ALREADY_STARTED: c_int = 3;
+pub const P2PMSG_FAILED: c_int = 4;
+
+pub const P2PMSG_EVENT_MESSAGE: c_int = 0;
+pub const P2PMSG_EVENT_MESSAGE_DROPPED: c_int = 1;
+pub const P2PMSG_EVENT_SLOW_CONSUMER: c_int = 2;
+pub const P2PMSG_EVENT_PEER_COMPATIBILITY: c_int = 3;
+pub const P2PMSG_EVENT_PEER_CONNECTED: c_int = 4;
+pub const P2PMSG_EVENT_PEER_DISCONNECTED: c_int = 5;
+pub const P2PMSG_EVENT_ID_MISMATCH: c_int = 6;
+pub const P2PMSG_EVENT_CHAT: c_int = 7;
+
+/// Event passed to callback, pointers are valid only during callback
+#[repr(C)]
+pub struct P2pmsgEvent {
+    pub kind: c_int,
+    /// Address of peer, NULL if event is not related to a peer
+    pub peer: *const c_char,
+    /// Protocol of received message, NULL for other events
+    pub protocol: *const c_char,
+    /// Data of received message, NULL for other events
+    pub data: *const u8,
+    pub data_len: usize,
+    /// Event as JSON
+    pub detail: *const c_char,
+}
+
+pub type P2pmsgEventCallback = extern "C" fn(event: *const P2pmsgEvent, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    cb: P2pmsgEventCallback,
+    user_data: *mut c_void,
+}
+
+// user data is opaque for us, caller is responsible for it being usable from node thread
+unsafe impl Send for Callback {}
+
+/// Node run through C API, it is independent of default node of the library
+struct Running {
+    node: client::Node,
+    runtime: tokio::runtime::Handle,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+lazy_static! {
+    static ref NODE: Mutex<Option<Running>> = Mutex::new(None);
+    static ref STARTED: Mutex<bool> = Mutex::new(false);
+    static ref CALLBACK: Mutex<Option<Callback>> = Mutex::new(None);
+}
+
+fn deliver(
+    kind: c_int,
+    peer: Option<SocketAddr>,
+    protocol: Option<&str>,
+    data: &[u8],
+    detail: String,
+) {
+    let callback = match *CALLBACK.lock().unwrap() {
+        Some(c) => c,
+        None => return,
+    };
+    let peer = peer.and_then(|p| CString::new(p.to_string()).ok());
+    let protocol = protocol.and_then(|p| CString::new(p).ok());
+    let detail = CString::new(detail).unwrap_or_default();
+    let event = P2pmsgEvent {
+        kind,
+        peer: peer.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+        protocol: protocol.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+        data: if protocol.is_some() { data.as_ptr() } else { ptr::null() },
+        data_len: data.len(),
+        detail: detail.as_ptr(),
+    };
+    (callback.cb)(&event, callback.user_data)
+}
+
+struct CallbackListener;
+
+impl EventListener for CallbackListener {
+    fn on_event<'a>(&'a self, event: &'a NodeEvent) -> BoxFuture<'a, ()> {
+        let (kind, peer) = match event {
+            NodeEvent::MessageDropped { peer, .. } => (P2PMSG_EVENT_MESSAGE_DROPPED, *peer),
+            NodeEvent::SlowConsumer { peer, .. } => (P2PMSG_EVENT_SLOW_CONSUMER, Some(*peer)),
+            NodeEvent::PeerCompatibility { peer, .. } => {
+                (P2PMSG_EVENT_PEER_COMPATIBILITY, Some(*peer))
+            }
+            NodeEvent::IdMismatch { peer, .. } => (P2PMSG_EVENT_ID_MISMATCH, Some(*peer)),
+            NodeEvent::PeerConnected { peer, .. } => (P2PMSG_EVENT_PEER_CONNECTED, Some(*peer)),
+            NodeEvent::PeerDisconnected { peer, .. } => {
+                (P2PMSG_EVENT_PEER_DISCONNECTED, Some(*peer))
+            }
+            NodeEvent::ChatReceived { peer, .. } => (P2PMSG_EVENT_CHAT, Some(*peer)),
+        };
+        let detail = serde_json::to_string(event).unwrap_or_default();
+        deliver(kind, peer, None, &[], detail);
+        Box::pin(future::ready(()))
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<Option<&'a str>, c_int> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| P2PMSG_INVALID_ARGUMENT)
+}
+
+fn parse_list<T: std::str::FromStr>(s: Option<&str>) -> Result<Vec<T>, c_int> {
+    s.unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().map_err(|_| P2PMSG_INVALID_ARGUMENT))
+        .collect()
+}
+
+fn start(data_dir: &str, listen: Vec<ListenerConfig>, peers: Vec<SocketAddr>) -> c_int {
+    let mut started = STARTED.lock().unwrap();
+    if *started {
+        return P2PMSG_ALREADY_STARTED;
+    }
+    let id = match identity::load_or_create(&Path::new(data_dir).join("identity")) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Cannot load identity: {}", e);
+            return P2PMSG_FAILED;
+        }
+    };
+    let mut runtime = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            error!("Cannot create runtime: {}", e);
+            return P2PMSG_FAILED;
+        }
+    };
+    let handle = runtime.handle().clone();
+    let (stop, stopped) = oneshot::channel();
+    let node = client::Node::new();
+    let running = node.clone();
+    let thread = thread::spawn(move || {
+        runtime.block_on(async move {
+            node.add_event_listener(Arc::new(CallbackListener));
+            let run = node.run(ClientConfig::new(listen, peers), id);
+            futures::pin_mut!(run);
+            match future::select(run, stopped).await {
+                future::Either::Left((Err(e), _)) => error!("Node failed: {}", e),
+                future::Either::Left((Ok(()), _)) => (),
+                future::Either::Right(_) => node.shutdown().await,
+            }
+        })
+    });
+    *started = true;
+    *NODE.lock().unwrap() = Some(Running {
+        node: running,
+        runtime: handle,
+        stop: Some(stop),
+        thread: Some(thread),
+    });
+    P2PMSG_OK
+}
+
+/// Starts node with identity stored in `data_dir`, listening on comma separated `listen`
+/// addresses (NULL for outbound only) and dialing comma separated bootstrap `peers` (can be NULL)
+///
+/// # Safety
+/// Arguments must be NULL or valid NUL terminated strings
+#[no_mangle]
+pub unsafe extern "C" fn p2pmsg_start(
+    data_dir: *const c_char,
+    listen: *const c_char,
+    peers: *const c_char,
+) -> c_int {
+    let args = || -> Result<_, c_int> {
+        let data_dir = str_arg(data_dir)?.ok_or(P2PMSG_INVALID_ARGUMENT)?;
+        Ok((data_dir, parse_list(str_arg(listen)?)?, parse_list(str_arg(peers)?)?))
+    };
+    match args() {
+        Ok((data_dir, listen, peers)) => start(data_dir, listen, peers),
+        Err(code) => code,
+    }
+}
+
+/// Gracefully stops node and waits for its thread to finish
+#[no_mangle]
+pub extern "C" fn p2pmsg_stop() -> c_int {
+    let running = NODE.lock().unwrap().take();
+    match running {
+        Some(mut running) => {
+            if let Some(stop) = running.stop.take() {
+                stop.send(()).ok();
+            }
+            if let Some(thread) = running.thread.take() {
+                if thread.join().is_err() {
+                    return P2PMSG_FAILED;
+                }
+            }
+            P2PMSG_OK
+        }
+        None => P2PMSG_NOT_RUNNING,
+    }
+}
+
+/// Sends `data` as raw frame of `protocol` to connected `peer` (its address).
+/// Blocks until frame is sent, must not be called from event callback.
+///
+/// # Safety
+/// `peer` and `protocol` must be valid NUL terminated strings, `data` must point to `len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn p2pmsg_send(
+    peer: *const c_char,
+    protocol: *const c_char,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    let args = || -> Result<_, c_int> {
+        let peer: SocketAddr = str_arg(peer)?
+            .ok_or(P2PMSG_INVALID_ARGUMENT)?
+            .parse()
+            .map_err(|_| P2PMSG_INVALID_ARGUMENT)?;
+        let protocol = str_arg(protocol)?.ok_or(P2PMSG_INVALID_ARGUMENT)?;
+        if data.is_null() && len > 0 {
+            return Err(P2PMSG_INVALID_ARGUMENT);
+        }
+        let data = if len > 0 {
+            std::slice::from_raw_parts(data, len).to_vec()
+        } else {
+            vec![]
+        };
+        Ok((peer, protocol.to_string(), data))
+    };
+    let (peer, protocol, data) = match args() {
+        Ok(a) => a,
+        Err(code) => return code,
+    };
+    let (node, runtime) = match NODE.lock().unwrap().as_ref() {
+        Some(r) => (r.node.clone(), r.runtime.clone()),
+        None => return P2PMSG_NOT_RUNNING,
+    };
+    let sending =
+        runtime.spawn(async move { node.send(peer, Message::Raw { protocol, data }).await });
+    match futures::executor::block_on(sending) {
+        Ok(Ok(())) => P2PMSG_OK,
+        Ok(Err(e)) => {
+            debug!("Cannot send to {}: {}", peer, e);
+            P2PMSG_FAILED
+        }
+        Err(_) => P2PMSG_FAILED,
+    }
+}
+
+/// Frames received for `protocol` are delivered to event callback as P2PMSG_EVENT_MESSAGE
+///
+/// # Safety
+/// `protocol` must be valid NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn p2pmsg_listen_protocol(protocol: *const c_char) -> c_int {
+    let protocol = match str_arg(protocol) {
+        Ok(Some(p)) => p.to_string(),
+        Ok(None) => return P2PMSG_INVALID_ARGUMENT,
+        Err(code) => return code,
+    };
+    let (node, runtime) = match NODE.lock().unwrap().as_ref() {
+        Some(r) => (r.node.clone(), r.runtime.clone()),
+        None => return P2PMSG_NOT_RUNNING,
+    };
+    let mut raw = match node.register_raw_protocol(&protocol) {
+        Ok(raw) => raw,
+        Err(e) => {
+            error!("{}", e);
+            return P2PMSG_FAILED;
+        }
+    };
+    runtime.spawn(async move {
+        while let Some((peer, data)) = raw.recv().await {
+            let detail = serde_json::json!({ "protocol": raw.name(), "len": data.len() });
+            deliver(
+                P2PMSG_EVENT_MESSAGE,
+                Some(peer),
+                Some(raw.name()),
+                &data,
+                detail.to_string(),
+            );
+        }
+    });
+    P2PMSG_OK
+}
+
+/// Sets callback for node events, NULL removes it. Callback is called from node thread.
+///
+/// # Safety
+/// `user_data` is passed to callback as is, it must be usable from another thread
+#[no_mangle]
+pub unsafe extern "C" fn p2pmsg_set_event_callback(
+    cb: Option<P2pmsgEventCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    *CALLBACK.lock().unwrap() = cb.map(|cb| Callback { cb, user_data });
+    P2PMSG_OK
+}
@@ -0,0 +1,293 @@
+//! Filter expressions for event subscriptions, e.g.
+//! `type == SlowConsumer || (type == MessageDropped && reason != ConnectionClosing)`.
+//!
+//! Fields are `type` (event name) and fields of the event, values are compared as strings.
+//! Operators are `==`, `!=`, `contains`, `&&`, `||`, `!` and parentheses, values can be
+//! quoted. Missing field is not equal to anything and contains nothing. Empty filter matches
+//! all events.
+
+use serde_json::Value;
+use tokio::sync::broadcast::{self, RecvError};
+
+use crate::client;
+use crate::error::Error;
+use crate::events::NodeEvent;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Eq,
+    NotEq,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '=' | '!' | '&' | '|' => {
+                chars.next();
+                let double = chars.peek().copied();
+                let token = match (c, double) {
+                    ('=', Some('=')) => Token::Eq,
+                    ('!', Some('=')) => Token::NotEq,
+                    ('&', Some('&')) => Token::And,
+                    ('|', Some('|')) => Token::Or,
+                    ('!', _) => {
+                        tokens.push(Token::Not);
+                        continue;
+                    }
+                    _ => return Err(format!("Invalid operator {} in filter", c).into()),
+                };
+                chars.next();
+                tokens.push(token);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => return Err("Unterminated string in filter".into()),
+                        },
+                        Some(c) => value.push(c),
+                        None => return Err("Unterminated string in filter".into()),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()=!&|\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Eq(String, String),
+    Contains(String, String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn or(&mut self) -> Result<Expr, Error> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let e = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(e),
+                    _ => Err("Missing ) in filter".into()),
+                }
+            }
+            Some(Token::Word(field)) => {
+                let op = self.next();
+                let value = match self.next() {
+                    Some(Token::Word(v)) | Some(Token::Quoted(v)) => v,
+                    _ => return Err(format!("Missing value for {} in filter", field).into()),
+                };
+                match op {
+                    Some(Token::Eq) => Ok(Expr::Eq(field, value)),
+                    Some(Token::NotEq) => Ok(Expr::Not(Box::new(Expr::Eq(field, value)))),
+                    Some(Token::Word(ref w)) if w == "contains" => Ok(Expr::Contains(field, value)),
+                    _ => Err(format!("Expected ==, != or contains after {} in filter", field).into()),
+                }
+            }
+            t => Err(format!("Unexpected {:?} in filter", t).into()),
+        }
+    }
+}
+
+/// Compiled filter expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventFilter(Option<Expr>);
+
+impl std::str::FromStr for EventFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        if tokens.is_empty() {
+            return Ok(EventFilter(None));
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(EventFilter(Some(expr))),
+            Some(t) => Err(format!("Unexpected {:?} in filter", t).into()),
+        }
+    }
+}
+
+fn field(event: &Value, name: &str) -> Option<String> {
+    let (kind, fields) = match event {
+        Value::Object(o) => o.iter().next()?,
+        Value::String(kind) => return if name == "type" { Some(kind.clone()) } else { None },
+        _ => return None,
+    };
+    if name == "type" {
+        return Some(kind.clone());
+    }
+    match fields.get(name)? {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        v => Some(v.to_string()),
+    }
+}
+
+fn eval(expr: &Expr, event: &Value) -> bool {
+    match expr {
+        Expr::Eq(f, v) => field(event, f).is_some_and(|x| x == *v),
+        Expr::Contains(f, v) => field(event, f).is_some_and(|x| x.contains(v.as_str())),
+        Expr::Not(e) => !eval(e, event),
+        Expr::And(a, b) => eval(a, event) && eval(b, event),
+        Expr::Or(a, b) => eval(a, event) || eval(b, event),
+    }
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &NodeEvent) -> bool {
+        let expr = match self.0 {
+            Some(ref e) => e,
+            None => return true,
+        };
+        match serde_json::to_value(event) {
+            Ok(v) => eval(expr, &v),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Event subscription, which receives only events matching filter
+pub struct FilteredEvents {
+    filter: EventFilter,
+    events: broadcast::Receiver<NodeEvent>,
+}
+
+impl FilteredEvents {
+    /// Next matching event, None when node is gone. Events missed by slow subscriber are skipped.
+    pub async fn recv(&mut self) -> Option<NodeEvent> {
+        loop {
+            match self.events.recv().await {
+                Ok(event) if self.filter.matches(&event) => return Some(event),
+                Ok(_) => (),
+                Err(RecvError::Lagged(n)) => debug!("Filtered subscriber missed {} events", n),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Subscribes to events of default node matching filter expression
+pub fn subscribe_events_filtered(filter: &str) -> Result<FilteredEvents, Error> {
+    client::default_node().subscribe_events_filtered(filter)
+}
+
+pub(crate) fn filtered(
+    events: broadcast::Receiver<NodeEvent>,
+    filter: &str,
+) -> Result<FilteredEvents, Error> {
+    Ok(FilteredEvents {
+        filter: filter.parse()?,
+        events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::DropReason;
+
+    #[test]
+    fn test_filter() {
+        let peer = "127.0.0.1:7701".parse().unwrap();
+        let slow = NodeEvent::SlowConsumer {
+            peer,
+            disconnected: false,
+        };
+        let dropped = NodeEvent::MessageDropped {
+            reason: DropReason::UnknownPeer,
+            peer: None,
+        };
+        let f: EventFilter = "type == SlowConsumer".parse().unwrap();
+        assert!(f.matches(&slow));
+        assert!(!f.matches(&dropped));
+        let f: EventFilter = "peer == \"127.0.0.1:7701\" || !(reason != UnknownPeer)"
+            .parse()
+            .unwrap();
+        assert!(f.matches(&slow));
+        assert!(f.matches(&dropped));
+        let f: EventFilter = "type contains Drop && peer contains 127".parse().unwrap();
+        assert!(!f.matches(&dropped));
+        assert!(!f.matches(&slow));
+        let f: EventFilter = "peer != x".parse().unwrap();
+        assert!(f.matches(&dropped));
+        let f: EventFilter = " ".parse().unwrap();
+        assert!(f.matches(&slow));
+
+        assert!("type ==".parse::<EventFilter>().is_err());
+        assert!("(type == a".parse::<EventFilter>().is_err());
+        assert!("type = a".parse::<EventFilter>().is_err());
+        assert!("type == a b".parse::<EventFilter>().is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Minimal HTTP liveness (`/healthz`) and readiness (`/readyz`) endpoints
+//! for running node under Kubernetes, systemd or similar supervisors.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::client;
+
+#[cfg(feature = "health-server")]
+pub use server::{run_health_server, run_health_server_for};
+
+/// Health state of one node, so stopping a node does not affect readiness of others
+#[derive(Default)]
+pub struct HealthStatus {
+    listening: AtomicBool,
+    /// Listeners, which failed to accept connection since last successful accept
+    failing_listeners: AtomicUsize,
+    outbound_only: AtomicBool,
+    bootstrap_peers: AtomicUsize,
+    storage_dir: Mutex<Option<PathBuf>>,
+}
+
+impl HealthStatus {
+    pub fn set_listening(&self, v: bool) {
+        self.listening.store(v, Ordering::SeqCst)
+    }
+
+    pub fn set_outbound_only(&self, v: bool) {
+        self.outbound_only.store(v, Ordering::SeqCst)
+    }
+
+    pub fn set_bootstrap_peers(&self, n: usize) {
+        self.bootstrap_peers.store(n, Ordering::SeqCst)
+    }
+
+    /// Node is ready only if this directory (where node keeps its data) is writable
+    pub fn set_storage_dir(&self, dir: &Path) {
+        *self.storage_dir.lock().unwrap() = Some(dir.to_owned());
+    }
+}
+
+/// Sets storage directory of default node, see `HealthStatus::set_storage_dir`
+pub fn set_storage_dir(dir: &Path) {
+    client::default_node().health().set_storage_dir(dir)
+}
+
+/// Failure state of one listener - listener is healthy again after successful accept
+pub(crate) struct ListenerHealth {
+    health: Arc<HealthStatus>,
+    failing: bool,
+}
+
+impl ListenerHealth {
+    pub fn new(health: Arc<HealthStatus>) -> Self {
+        ListenerHealth {
+            health,
+            failing: false,
+        }
+    }
+
+    pub fn accepted(&mut self) {
+        if self.failing {
+            self.failing = false;
+            self.health.failing_listeners.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn failed(&mut self) {
+        if !self.failing {
+            self.failing = true;
+            self.health.failing_listeners.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for ListenerHealth {
+    fn drop(&mut self) {
+        self.accepted()
+    }
+}
+
+/// HTTP server, endpoint state is kept also without it, so it is cheap to track
+#[cfg(feature = "health-server")]
+mod server {
+    use std::net::SocketAddr;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::HealthStatus;
+    use crate::client::{self, Node};
+    use crate::error::Error;
+
+    const MAX_REQUEST_SIZE: usize = 4096;
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[derive(Debug, Serialize)]
+    struct Report {
+        ok: bool,
+        listening: bool,
+        outbound_only: bool,
+        bootstrap_peers: usize,
+        connected_peers: usize,
+        /// Storage directory is writable, None if node has no storage directory
+        storage: Option<bool>,
+    }
+
+    /// Tries to write probe file to storage directory, None if no directory is set
+    fn check_storage(health: &HealthStatus) -> Option<Result<(), std::io::Error>> {
+        let dir = health.storage_dir.lock().unwrap().clone()?;
+        let probe = dir.join(".readyz");
+        Some(std::fs::write(&probe, b"ok").and_then(|_| std::fs::remove_file(&probe)))
+    }
+
+    async fn report(node: &Node) -> Report {
+        let health = node.health();
+        let listening = health.listening.load(Ordering::SeqCst)
+            && health.failing_listeners.load(Ordering::SeqCst) == 0;
+        let outbound_only = health.outbound_only.load(Ordering::SeqCst);
+        let bootstrap_peers = health.bootstrap_peers.load(Ordering::SeqCst);
+        let connected_peers = node.list_peers().await.len();
+        let storage = match tokio::task::spawn_blocking(move || check_storage(&health)).await {
+            Ok(Some(Err(e))) => {
+                warn!("Storage is not writable: {}", e);
+                Some(false)
+            }
+            Ok(res) => res.map(|_| true),
+            Err(_) => Some(false),
+        };
+        Report {
+            ok: listening || outbound_only,
+            listening,
+            outbound_only,
+            bootstrap_peers,
+            connected_peers,
+            storage,
+        }
+    }
+
+    async fn respond(node: &Node, path: &str) -> (u16, String) {
+        let mut r = report(node).await;
+        match path {
+            "/healthz" => {}
+            // ready when we have listener, connected to bootstrap network (if any is configured)
+            // and can write to storage
+            "/readyz" => {
+                r.ok = (r.listening || r.outbound_only)
+                    && (r.bootstrap_peers == 0 || r.connected_peers > 0)
+                    && r.storage != Some(false)
+            }
+            _ => return (404, "{\"error\":\"not found\"}".into()),
+        }
+        let status = if r.ok { 200 } else { 503 };
+        (status, serde_json::to_string(&r).unwrap_or_default())
+    }
+
+    async fn read_request_path(socket: &mut TcpStream) -> Result<Option<String>, Error> {
+        let mut buf = Vec::with_capacity(512);
+        let mut chunk = [0u8; 512];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if buf.len() > MAX_REQUEST_SIZE {
+                return Err("request too big".into());
+            }
+        }
+        let req = String::from_utf8_lossy(&buf);
+        let mut parts = req.lines().next().unwrap_or("").split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("GET"), Some(path)) => Ok(Some(path.into())),
+            _ => Ok(None),
+        }
+    }
+
+    async fn handle_request(node: Node, mut socket: TcpStream) -> Result<(), Error> {
+        let (status, body) =
+            match tokio::time::timeout(REQUEST_TIMEOUT, read_request_path(&mut socket)).await? {
+                Ok(Some(path)) => respond(&node, &path).await,
+                Ok(None) => (405, "{\"error\":\"method not allowed\"}".into()),
+                Err(e) => (400, format!("{{\"error\":\"{}\"}}", e)),
+            };
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Service Unavailable",
+        };
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown(std::net::Shutdown::Write)?;
+        Ok(())
+    }
+
+    /// Serves health endpoints of default node on given address until error in listener
+    pub async fn run_health_server(addr: SocketAddr) -> Result<(), Error> {
+        run_health_server_for(client::default_node(), addr).await
+    }
+
+    /// Serves health endpoints of given node on given address until error in listener
+    pub async fn run_health_server_for(node: Node, addr: SocketAddr) -> Result<(), Error> {
+        let mut listener = TcpListener::bind(&addr).await?;
+        info!("Health endpoints available on http://{}", addr);
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let node = node.clone();
+            tokio::spawn(async move {
+                handle_request(node, socket)
+                    .await
+                    .unwrap_or_else(|e| debug!("Health request error: {}", e))
+            });
+        }
+    }
+}
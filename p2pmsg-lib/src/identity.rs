@@ -0,0 +1,33 @@
+//! Persistent identity of this node
+
+use std::fs;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::protocol::id::{FriendlyId, RawId};
+
+/// Loads node id from file, None if file does not exist
+pub fn load(path: &Path) -> Result<Option<RawId>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let id: FriendlyId = fs::read_to_string(path)?.trim().parse()?;
+    id.to_raw()
+        .map(Some)
+        .ok_or_else(|| format!("Invalid id in {:?}", path).into())
+}
+
+/// Loads node id from file, or creates new random one and saves it, if file does not exist
+pub fn load_or_create(path: &Path) -> Result<RawId, Error> {
+    if let Some(id) = load(path)? {
+        Ok(id)
+    } else {
+        let id = RawId::random();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, format!("{}\n", id))?;
+        info!("Created new identity {} in {:?}", id, path);
+        Ok(id)
+    }
+}
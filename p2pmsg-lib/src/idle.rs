@@ -0,0 +1,132 @@
+//! Reaping of idle connections - connections without application traffic for some time
+//! are kept, parked (closed, but peer's addresses stay in address book, so it can be dialed
+//! when needed) or closed.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::protocol::id::FriendlyId;
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IdlePolicy {
+    /// Connection is kept open by keepalives
+    Keep,
+    /// Connection is closed, peer is remembered in address book
+    Park,
+    /// Connection is closed and peer is forgotten
+    Close,
+}
+
+impl std::str::FromStr for IdlePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(IdlePolicy::Keep),
+            "park" => Ok(IdlePolicy::Park),
+            "close" => Ok(IdlePolicy::Close),
+            _ => Err(format!("Invalid idle policy {}", s)),
+        }
+    }
+}
+
+pub struct IdleReaper {
+    policy: IdlePolicy,
+    timeout: Duration,
+    /// Policies of particular peers, overriding default one
+    peers: HashMap<FriendlyId, IdlePolicy>,
+    /// Peers disconnected as idle, until they connect again
+    reaped: HashMap<FriendlyId, IdlePolicy>,
+}
+
+impl IdleReaper {
+    pub fn new(policy: IdlePolicy, timeout: Duration) -> Self {
+        IdleReaper {
+            policy,
+            timeout,
+            peers: HashMap::new(),
+            reaped: HashMap::new(),
+        }
+    }
+
+    /// Sets default policy, keeps policies of particular peers
+    pub fn set_policy(&mut self, policy: IdlePolicy, timeout: Duration) {
+        self.policy = policy;
+        self.timeout = timeout;
+    }
+
+    /// Sets policy of peer, None reverts it to default policy
+    pub fn set_peer_policy(&mut self, id: FriendlyId, policy: Option<IdlePolicy>) {
+        match policy {
+            Some(p) => self.peers.insert(id, p),
+            None => self.peers.remove(&id),
+        };
+    }
+
+    pub fn policy(&self, id: &FriendlyId) -> IdlePolicy {
+        self.peers.get(id).copied().unwrap_or(self.policy)
+    }
+
+    /// Policy to apply to peer without traffic since `last_used`, None if it stays connected
+    pub fn due(&self, id: &FriendlyId, last_used: Instant, now: Instant) -> Option<IdlePolicy> {
+        match self.policy(id) {
+            IdlePolicy::Keep => None,
+            _ if now.saturating_duration_since(last_used) < self.timeout => None,
+            p => Some(p),
+        }
+    }
+
+    pub fn reaped(&mut self, id: FriendlyId, policy: IdlePolicy) {
+        self.reaped.insert(id, policy);
+    }
+
+    pub fn connected(&mut self, id: &FriendlyId) {
+        self.reaped.remove(id);
+    }
+
+    /// Peer was disconnected as idle, so it should not be re-dialed ahead of use
+    pub fn is_reaped(&self, id: &FriendlyId) -> bool {
+        self.reaped.contains_key(id)
+    }
+
+    pub fn parked(&self) -> Vec<FriendlyId> {
+        self.reaped
+            .iter()
+            .filter(|(_, p)| **p == IdlePolicy::Park)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::id::RawId;
+
+    #[test]
+    fn test_reaper() {
+        let a: FriendlyId = RawId::new([1; 32]).into();
+        let b: FriendlyId = RawId::new([2; 32]).into();
+        let timeout = Duration::from_secs(60);
+        let mut r = IdleReaper::new(IdlePolicy::Keep, timeout);
+        let t = Instant::now();
+        assert_eq!(None, r.due(&a, t, t + 2 * timeout));
+        r.set_policy(IdlePolicy::Park, timeout);
+        r.set_peer_policy(b.clone(), Some(IdlePolicy::Close));
+        assert_eq!(None, r.due(&a, t, t + timeout / 2));
+        assert_eq!(Some(IdlePolicy::Park), r.due(&a, t, t + timeout));
+        assert_eq!(Some(IdlePolicy::Close), r.due(&b, t, t + timeout));
+        r.set_peer_policy(b.clone(), None);
+        assert_eq!(IdlePolicy::Park, r.policy(&b));
+
+        r.reaped(a.clone(), IdlePolicy::Park);
+        r.reaped(b.clone(), IdlePolicy::Close);
+        assert!(r.is_reaped(&b));
+        assert_eq!(vec![a.clone()], r.parked());
+        r.connected(&a);
+        assert!(!r.is_reaped(&a));
+        assert!(r.parked().is_empty());
+    }
+}
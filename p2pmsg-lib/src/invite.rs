@@ -0,0 +1,118 @@
+//! Shareable invites - peer id with addresses, where peer can be reached,
+//! in form `p2pmsg:<id>@<addr>[,<addr>...][?name=<name>]`
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use crate::protocol::id::FriendlyId;
+
+const SCHEME: &str = "p2pmsg:";
+const NAME_PARAM: &str = "?name=";
+/// Version tag of DNS TXT record content
+const TXT_VERSION: &str = "v=p2pmsg1 ";
+/// Label under which TXT records of users are published in domain
+const TXT_LABEL: &str = "_p2pmsg";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    pub id: FriendlyId,
+    pub addrs: Vec<SocketAddr>,
+    /// Suggested petname
+    pub name: Option<String>,
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addrs: Vec<String> = self.addrs.iter().map(|a| a.to_string()).collect();
+        write!(f, "{}{}@{}", SCHEME, self.id, addrs.join(","))?;
+        if let Some(ref name) = self.name {
+            write!(f, "{}{}", NAME_PARAM, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Invite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| format!("Invite must start with {}", SCHEME))?;
+        let (rest, name) = match rest.find(NAME_PARAM) {
+            Some(pos) => (&rest[..pos], Some(rest[pos + NAME_PARAM.len()..].to_string())),
+            None => (rest, None),
+        };
+        let mut parts = rest.splitn(2, '@');
+        let id: FriendlyId = parts.next().unwrap_or("").parse()?;
+        let addrs = parts
+            .next()
+            .ok_or("Invite has no addresses")?
+            .split(',')
+            .map(|a| a.parse().map_err(|e| format!("Invalid address {}: {}", a, e)))
+            .collect::<Result<Vec<SocketAddr>, _>>()?;
+        Ok(Invite { id, addrs, name })
+    }
+}
+
+impl Invite {
+    /// Content of DNS TXT record, which publishes this invite
+    pub fn to_txt_record(&self) -> String {
+        format!("{}{}", TXT_VERSION, self)
+    }
+
+    pub fn from_txt_record(s: &str) -> Result<Self, String> {
+        s.strip_prefix(TXT_VERSION)
+            .ok_or_else(|| format!("TXT record must start with {}", TXT_VERSION))?
+            .parse()
+    }
+}
+
+/// DNS name of TXT record for address like user@example.com - user._p2pmsg.example.com
+pub fn txt_record_name(address: &str) -> Result<String, String> {
+    let mut parts = address.splitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(user), Some(domain)) if !user.is_empty() && !domain.is_empty() && !user.contains('.') => {
+            Ok(format!("{}.{}.{}", user, TXT_LABEL, domain))
+        }
+        _ => Err(format!("Invalid address {}, expected user@domain", address)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::id::RawId;
+
+    #[test]
+    fn test_invite() {
+        let invite = Invite {
+            id: RawId::random().into(),
+            addrs: vec!["1.2.3.4:5".parse().unwrap(), "127.0.0.1:12345".parse().unwrap()],
+            name: Some("alice".into()),
+        };
+        let s = invite.to_string();
+        assert_eq!(Ok(invite.clone()), s.parse());
+        let without_name = Invite { name: None, ..invite };
+        assert_eq!(Ok(without_name.clone()), without_name.to_string().parse());
+        assert!("p2pmsg:abc@1.2.3.4:5".parse::<Invite>().is_err());
+        assert!(format!("p2pmsg:{}", without_name.id).parse::<Invite>().is_err());
+    }
+
+    #[test]
+    fn test_txt_record() {
+        let invite = Invite {
+            id: RawId::random().into(),
+            addrs: vec!["1.2.3.4:5".parse().unwrap()],
+            name: None,
+        };
+        assert_eq!(Ok(invite.clone()), Invite::from_txt_record(&invite.to_txt_record()));
+        assert!(Invite::from_txt_record(&invite.to_string()).is_err());
+        assert_eq!(
+            Ok("alice._p2pmsg.example.com".to_string()),
+            txt_record_name("alice@example.com")
+        );
+        assert!(txt_record_name("example.com").is_err());
+    }
+}
@@ -10,6 +10,46 @@ extern crate lazy_static;
 pub mod protocol;
 pub mod error;
 pub mod client;
+pub mod rtt;
+pub mod phi;
+pub mod clock;
+pub mod health;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod systemd;
+pub mod listener;
+pub mod observed;
+pub mod prewarm;
+pub mod idle;
+pub mod raw;
+pub mod signaling;
+pub mod events;
+pub mod filter;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+pub mod supervisor;
+pub mod ratelimit;
+pub mod bot;
+pub mod telemetry;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+pub mod udp;
+pub mod invite;
+pub mod resolver;
+pub mod identity;
+pub mod petnames;
+pub mod uptime;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
-pub use crate::client::run_client;
+pub use crate::client::{
+    broadcast, broadcast_except, broadcast_local, cancel_pending, connect_peer, list_peers, my_id,
+    my_invite, parked_peers, pending, public_addr, run_client, send, send_all, send_fast,
+    send_text, send_to, set_contact_id_mismatch_policy, set_peer_idle_policy, shutdown, Node,
+};
+pub use crate::bot::{add_bot, add_bot_to};
+pub use crate::events::{add_event_listener, subscribe_events};
+pub use crate::filter::subscribe_events_filtered;
+pub use crate::raw::{register_raw_protocol, register_raw_protocol_with_ack};
+pub use crate::signaling::open_signaling;
 
@@ -0,0 +1,199 @@
+//! Listening sockets with per listener accept policies
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::Error;
+use crate::protocol::message::ErrorCode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    /// Maximum number of concurrent connections accepted by this listener
+    pub max_connections: Option<usize>,
+    /// Accept only connections from loopback addresses
+    pub local_only: bool,
+}
+
+impl ListenerConfig {
+    pub fn new(addr: SocketAddr) -> Self {
+        ListenerConfig {
+            addr,
+            max_connections: None,
+            local_only: false,
+        }
+    }
+}
+
+/// Parses `address[,max=N][,local]`, e.g. `0.0.0.0:9000,max=50`
+impl FromStr for ListenerConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|e| format!("Invalid listen address {}: {}", s, e))?;
+        let mut config = ListenerConfig::new(addr);
+        for p in parts {
+            if p == "local" {
+                config.local_only = true
+            } else if let Some(n) = p.strip_prefix("max=") {
+                config.max_connections =
+                    Some(n.parse().map_err(|_| format!("Invalid max connections {}", n))?)
+            } else {
+                return Err(format!("Unknown listener option {}", p));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Holds connection slot of listener, released on drop
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub const DEFAULT_MAX_HANDSHAKES: usize = 64;
+pub const DEFAULT_SHED_HANDSHAKES: usize = 256;
+
+/// Limits handshakes of incoming connections in progress, connections over shed threshold
+/// (including those waiting for handshake) are rejected right after accept
+#[derive(Clone)]
+pub(crate) struct HandshakeLimiter {
+    permits: Arc<Semaphore>,
+    pending: Arc<AtomicUsize>,
+    shed_threshold: usize,
+}
+
+impl HandshakeLimiter {
+    pub fn new(max_handshakes: usize, shed_threshold: usize) -> Self {
+        HandshakeLimiter {
+            permits: Arc::new(Semaphore::new(max_handshakes.max(1))),
+            pending: Arc::new(AtomicUsize::new(0)),
+            shed_threshold,
+        }
+    }
+
+    /// Slot for handshake of new connection, None if connection should be shed
+    pub fn admit(&self) -> Option<HandshakeSlot> {
+        let count = self.pending.fetch_add(1, Ordering::SeqCst);
+        let slot = HandshakeSlot {
+            permits: self.permits.clone(),
+            pending: self.pending.clone(),
+            permit: None,
+        };
+        if count >= self.shed_threshold {
+            None
+        } else {
+            Some(slot)
+        }
+    }
+}
+
+/// Pending handshake, released on drop
+pub(crate) struct HandshakeSlot {
+    permits: Arc<Semaphore>,
+    pending: Arc<AtomicUsize>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl HandshakeSlot {
+    /// Waits until handshake can start
+    pub async fn acquire(&mut self) {
+        if self.permit.is_none() {
+            self.permit = Some(self.permits.clone().acquire_owned().await)
+        }
+    }
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct Listener {
+    config: ListenerConfig,
+    listener: TcpListener,
+    active: Arc<AtomicUsize>,
+}
+
+impl Listener {
+    pub async fn bind(config: ListenerConfig) -> Result<Self, Error> {
+        let listener = TcpListener::bind(&config.addr).await?;
+        Ok(Listener::new(config, listener))
+    }
+
+    pub fn from_std(listener: std::net::TcpListener) -> Result<Self, Error> {
+        let config = ListenerConfig::new(listener.local_addr()?);
+        Ok(Listener::new(config, TcpListener::from_std(listener)?))
+    }
+
+    fn new(config: ListenerConfig, listener: TcpListener) -> Self {
+        Listener {
+            config,
+            listener,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap_or(self.config.addr)
+    }
+
+    pub async fn accept(&mut self) -> Result<(TcpStream, SocketAddr), Error> {
+        Ok(self.listener.accept().await?)
+    }
+
+    /// Applies listener policy to incoming connection
+    pub fn admit(&self, peer: &SocketAddr) -> Result<ConnectionGuard, (ErrorCode, &'static str)> {
+        if self.config.local_only && !peer.ip().is_loopback() {
+            return Err((ErrorCode::NotAllowed, "only local connections allowed"));
+        }
+        let count = self.active.fetch_add(1, Ordering::SeqCst);
+        let guard = ConnectionGuard(self.active.clone());
+        match self.config.max_connections {
+            Some(max) if count >= max => Err((ErrorCode::TooManyConnections, "listener is full")),
+            _ => Ok(guard),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let c: ListenerConfig = "127.0.0.1:9000,max=5,local".parse().unwrap();
+        assert_eq!(SocketAddr::from(([127, 0, 0, 1], 9000)), c.addr);
+        assert_eq!(Some(5), c.max_connections);
+        assert!(c.local_only);
+        assert!("127.0.0.1:9000,foo".parse::<ListenerConfig>().is_err());
+        assert!("localhost".parse::<ListenerConfig>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_limiter() {
+        let limiter = HandshakeLimiter::new(1, 2);
+        let mut a = limiter.admit().unwrap();
+        let mut b = limiter.admit().unwrap();
+        assert!(limiter.admit().is_none());
+        a.acquire().await;
+        assert_eq!(0, limiter.permits.available_permits());
+        drop(a);
+        b.acquire().await;
+        assert!(limiter.admit().is_some());
+    }
+}
@@ -0,0 +1,136 @@
+//! Aggregation of addresses other peers observe for us ("you look like 1.2.3.4:556 to me"),
+//! to find out our public address when behind NAT.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+
+/// Observations older then this are ignored
+pub const OBSERVATION_TTL: Duration = Duration::from_secs(3600);
+/// Minimum number of observer networks, which must agree on address
+pub const MIN_OBSERVERS: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PublicAddr {
+    pub ip: IpAddr,
+    /// Ports seen by observers, same port for all means NAT keeps mapping independent of destination
+    pub ports: Vec<u16>,
+    pub observers: usize,
+    /// Fraction of observers agreeing on this ip
+    pub confidence: f64,
+}
+
+/// Network of observer - /24 for IPv4, /48 for IPv6. Peer ids are not authenticated and
+/// a host can use more addresses from its network, so whole network has one vote.
+fn observer_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(v4) if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                observer_network(IpAddr::V4(v4))
+            }
+            _ => {
+                let s = ip.segments();
+                IpAddr::from([s[0], s[1], s[2], 0, 0, 0, 0, 0])
+            }
+        },
+    }
+}
+
+#[derive(Default)]
+pub struct ObservedAddrs {
+    votes: HashMap<IpAddr, (SocketAddr, Instant)>,
+}
+
+impl ObservedAddrs {
+    pub fn new() -> Self {
+        ObservedAddrs::default()
+    }
+
+    /// Records address as seen by peer connected from `observer` IP, each observer network
+    /// has just one (latest) vote
+    pub fn record(&mut self, observer: IpAddr, addr: SocketAddr) {
+        if addr.ip().is_loopback() || addr.ip().is_unspecified() {
+            return;
+        }
+        self.votes
+            .insert(observer_network(observer), (addr, clock::now()));
+    }
+
+    fn expire(&mut self) {
+        self.votes.retain(|_, (_, ts)| clock::elapsed(*ts) < OBSERVATION_TTL);
+    }
+
+    /// Most agreed public address, if enough peers observed it
+    pub fn public_addr(&mut self) -> Option<PublicAddr> {
+        self.expire();
+        let mut by_ip: HashMap<IpAddr, Vec<u16>> = HashMap::new();
+        for (addr, _) in self.votes.values() {
+            by_ip.entry(addr.ip()).or_default().push(addr.port());
+        }
+        let total = self.votes.len();
+        by_ip
+            .into_iter()
+            .filter(|(_, ports)| ports.len() >= MIN_OBSERVERS)
+            .max_by_key(|(ip, ports)| (ports.len(), *ip))
+            .map(|(ip, mut ports)| {
+                let observers = ports.len();
+                ports.sort_unstable();
+                ports.dedup();
+                PublicAddr {
+                    ip,
+                    ports,
+                    observers,
+                    confidence: observers as f64 / total as f64,
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agreement() {
+        let mut o = ObservedAddrs::new();
+        let a: SocketAddr = "1.2.3.4:556".parse().unwrap();
+        let b: SocketAddr = "5.6.7.8:556".parse().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        o.record(ip("10.0.1.1"), a);
+        o.record(ip("10.0.1.1"), a);
+        o.record(ip("10.0.2.1"), "127.0.0.1:556".parse().unwrap());
+        assert_eq!(None, o.public_addr());
+        // same network is one observer
+        o.record(ip("10.0.1.2"), a);
+        o.record(ip("::ffff:10.0.1.3"), a);
+        assert_eq!(None, o.public_addr());
+
+        o.record(ip("10.0.2.1"), SocketAddr::new(a.ip(), 600));
+        o.record(ip("2001:db8::1"), b);
+        let p = o.public_addr().unwrap();
+        assert_eq!(a.ip(), p.ip);
+        assert_eq!(vec![556, 600], p.ports);
+        assert_eq!(2, p.observers);
+        assert!((p.confidence - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        tokio::time::pause();
+        let mut o = ObservedAddrs::new();
+        let a: SocketAddr = "1.2.3.4:556".parse().unwrap();
+        o.record("10.0.1.1".parse().unwrap(), a);
+        o.record("10.0.2.1".parse().unwrap(), a);
+        assert!(o.public_addr().is_some());
+
+        tokio::time::advance(OBSERVATION_TTL).await;
+        assert_eq!(None, o.public_addr());
+    }
+}
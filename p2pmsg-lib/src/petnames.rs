@@ -0,0 +1,182 @@
+//! Petnames - local, user assigned names for peer ids.
+//!
+//! Petnames are not unique - if same petname is given to several ids, each of them
+//! is displayed with deterministic suffix (shortest unique id prefix), e.g. `alice~3xKq`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::protocol::id::FriendlyId;
+
+const SUFFIX_SEPARATOR: char = '~';
+const MIN_SUFFIX_LEN: usize = 4;
+
+/// First `len` characters of `s`
+fn prefix(s: &str, len: usize) -> &str {
+    s.char_indices().nth(len).map_or(s, |(i, _)| &s[..i])
+}
+
+#[derive(Default)]
+pub struct Petnames {
+    path: Option<PathBuf>,
+    names: BTreeMap<FriendlyId, String>,
+}
+
+impl Petnames {
+    /// In memory only petnames
+    pub fn new() -> Self {
+        Petnames::default()
+    }
+
+    /// Loads petnames from JSON file, changes are then saved back to it
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let names = if path.exists() {
+            serde_json::from_slice(&fs::read(path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Petnames {
+            path: Some(path.to_owned()),
+            names,
+        })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(ref path) = self.path {
+            fs::write(path, serde_json::to_vec_pretty(&self.names)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, id: FriendlyId, name: &str) -> Result<(), Error> {
+        if name.is_empty()
+            || name.contains(char::is_whitespace)
+            || name.contains(SUFFIX_SEPARATOR)
+        {
+            return Err(format!("Invalid petname {:?}", name).into());
+        }
+        if name.parse::<FriendlyId>().is_ok() {
+            return Err("Petname cannot look like peer id".into());
+        }
+        self.names.insert(id, name.into());
+        self.save()
+    }
+
+    /// Removes petname given either by id or (displayed) name
+    pub fn remove(&mut self, name_or_id: &str) -> Result<Option<FriendlyId>, Error> {
+        let removed = self
+            .resolve(name_or_id)
+            .and_then(|id| self.names.remove(&id).map(|_| id));
+        if removed.is_some() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn petname(&self, id: &FriendlyId) -> Option<&str> {
+        self.names.get(id).map(String::as_str)
+    }
+
+    /// Name under which peer should be displayed, None if peer has no petname
+    pub fn display_name(&self, id: &FriendlyId) -> Option<String> {
+        let name = self.names.get(id)?;
+        let same: Vec<&str> = self
+            .names
+            .iter()
+            .filter(|(_, n)| *n == name)
+            .map(|(i, _)| i.as_str())
+            .collect();
+        if same.len() == 1 {
+            return Some(name.clone());
+        }
+        let max_len = same.iter().map(|i| i.len()).max().unwrap_or(0);
+        let mut len = MIN_SUFFIX_LEN;
+        while len < max_len {
+            let mut prefixes: Vec<&str> = same.iter().map(|i| prefix(i, len)).collect();
+            prefixes.sort_unstable();
+            prefixes.dedup();
+            if prefixes.len() == same.len() {
+                break;
+            }
+            len += 1;
+        }
+        Some(format!(
+            "{}{}{}",
+            name,
+            SUFFIX_SEPARATOR,
+            prefix(id.as_str(), len)
+        ))
+    }
+
+    /// All petnames as (display name, id), sorted by name
+    pub fn list(&self) -> Vec<(String, FriendlyId)> {
+        let mut res: Vec<_> = self
+            .names
+            .keys()
+            .filter_map(|id| self.display_name(id).map(|n| (n, id.clone())))
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// Display names starting with given prefix
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        self.list()
+            .into_iter()
+            .map(|(n, _)| n)
+            .filter(|n| n.starts_with(prefix))
+            .collect()
+    }
+
+    /// Resolves id, display name, non conflicting petname or its unique prefix to id
+    pub fn resolve(&self, s: &str) -> Option<FriendlyId> {
+        if let Ok(id) = s.parse::<FriendlyId>() {
+            return Some(id);
+        }
+        let list = self.list();
+        if let Some((_, id)) = list.iter().find(|(n, _)| n == s) {
+            return Some(id.clone());
+        }
+        let mut matching = list.iter().filter(|(n, _)| n.starts_with(s));
+        match (matching.next(), matching.next()) {
+            (Some((_, id)), None) => Some(id.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::id::RawId;
+
+    fn id(b: u8) -> FriendlyId {
+        RawId::new([b; 32]).into()
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let mut p = Petnames::new();
+        p.set(id(1), "alice").unwrap();
+        p.set(id(3), "bob").unwrap();
+        assert!(p.set(id(4), "bad name").is_err());
+        assert_eq!(Some("alice".to_string()), p.display_name(&id(1)));
+        assert_eq!(Some(id(1)), p.resolve("alice"));
+        assert_eq!(Some(id(1)), p.resolve("al"));
+
+        p.set(id(2), "alice").unwrap();
+        let n1 = p.display_name(&id(1)).unwrap();
+        let n2 = p.display_name(&id(2)).unwrap();
+        assert_ne!(n1, n2);
+        assert!(n1.starts_with("alice~"));
+        assert_eq!(Some(id(2)), p.resolve(&n2));
+        assert_eq!(None, p.resolve("alice"));
+        assert_eq!(vec![n1, n2], p.complete("al"));
+
+        assert_eq!(Some(id(3)), p.remove("bob").unwrap());
+        assert_eq!(None, p.resolve("bob"));
+        assert_eq!(Some(id(3)), p.resolve(id(3).as_str()));
+    }
+}
@@ -0,0 +1,97 @@
+//! Phi accrual failure detector (Hayashibara et al.) fed by arrival times of peer's
+//! keepalive pings - instead of alive/dead gives suspicion level, which grows
+//! continuously while heartbeat is late.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Number of last heartbeat intervals used for estimation
+const WINDOW: usize = 100;
+/// Lower bound of standard deviation, so very regular heartbeats do not make detector too eager
+const MIN_STD_DEV: f64 = 0.5;
+
+/// Suspicion level, from which connection is considered unstable
+pub const PHI_UNSTABLE: f64 = 3.0;
+/// Suspicion level, from which peer is considered unresponsive
+pub const PHI_DEAD: f64 = 8.0;
+
+#[derive(Debug, Clone)]
+pub struct PhiAccrual {
+    intervals: VecDeque<f64>,
+    last: Option<Instant>,
+}
+
+impl PhiAccrual {
+    /// Starts with expected heartbeat interval, so suspicion is meaningful from first heartbeat
+    pub fn new(expected: Duration) -> Self {
+        let e = expected.as_secs_f64();
+        let mut intervals = VecDeque::with_capacity(WINDOW);
+        // two samples giving mean = expected and std dev = expected / 4
+        intervals.push_back(e - e / 4.0);
+        intervals.push_back(e + e / 4.0);
+        PhiAccrual {
+            intervals,
+            last: None,
+        }
+    }
+
+    pub fn heartbeat(&mut self, now: Instant) {
+        if let Some(last) = self.last {
+            if self.intervals.len() >= WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals
+                .push_back(now.saturating_duration_since(last).as_secs_f64());
+        }
+        self.last = Some(now);
+    }
+
+    /// Suspicion level - phi 1 means 10% chance of mistake when declaring peer dead now,
+    /// phi 2 1%, phi 3 0.1% etc. Zero until first heartbeat.
+    pub fn phi(&self, now: Instant) -> f64 {
+        let last = match self.last {
+            Some(l) => l,
+            None => return 0.0,
+        };
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self.intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / n;
+        let std_dev = variance.sqrt().max(MIN_STD_DEV);
+        let elapsed = now.saturating_duration_since(last).as_secs_f64();
+        // logistic approximation of normal CDF, as used in Akka
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phi() {
+        let interval = Duration::from_secs(10);
+        let mut d = PhiAccrual::new(interval);
+        let start = Instant::now();
+        assert_eq!(0.0, d.phi(start));
+        let mut t = start;
+        for _ in 0..20 {
+            d.heartbeat(t);
+            t += interval;
+        }
+        let last = t - interval;
+        assert!(d.phi(last + Duration::from_secs(5)) < 0.5);
+        let on_time = d.phi(last + interval);
+        let late = d.phi(last + interval * 2);
+        let very_late = d.phi(last + interval * 3);
+        assert!(on_time < PHI_UNSTABLE);
+        assert!(late > on_time);
+        assert!(late >= PHI_UNSTABLE);
+        assert!(very_late >= PHI_DEAD);
+    }
+}
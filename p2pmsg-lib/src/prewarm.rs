@@ -0,0 +1,107 @@
+//! Tracks how often we exchange messages with peers, so connections to most used
+//! peers can be kept warm (re-dialed ahead of use).
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::protocol::id::FriendlyId;
+
+/// Usage score halves each hour
+const HALF_LIFE: Duration = Duration::from_secs(3600);
+/// Peers with lower score are forgotten
+const MIN_SCORE: f64 = 0.01;
+
+struct Usage {
+    score: f64,
+    updated: Instant,
+    addrs: Vec<SocketAddr>,
+}
+
+impl Usage {
+    fn score_at(&self, now: Instant) -> f64 {
+        let age = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.score * 0.5f64.powf(age / HALF_LIFE.as_secs_f64())
+    }
+}
+
+pub struct Prewarmer {
+    limit: usize,
+    peers: HashMap<FriendlyId, Usage>,
+}
+
+impl Prewarmer {
+    /// Keeps at most `limit` peers warm, 0 disables pre-warming
+    pub fn new(limit: usize) -> Self {
+        Prewarmer {
+            limit,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Records message exchanged with peer, addrs are addresses where peer can be dialed
+    pub fn record_use(&mut self, id: &FriendlyId, addrs: Vec<SocketAddr>) {
+        if self.limit == 0 {
+            return;
+        }
+        let now = clock::now();
+        let usage = self.peers.entry(id.clone()).or_insert(Usage {
+            score: 0.0,
+            updated: now,
+            addrs: vec![],
+        });
+        usage.score = usage.score_at(now) + 1.0;
+        usage.updated = now;
+        usage.addrs = addrs;
+    }
+
+    /// Most used peers (up to limit), which are not connected, with their addresses
+    pub fn candidates(&mut self, connected: &HashSet<FriendlyId>) -> Vec<(FriendlyId, Vec<SocketAddr>)> {
+        let now = clock::now();
+        self.peers.retain(|_, u| u.score_at(now) >= MIN_SCORE);
+        let mut ranked: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(id, u)| (u.score_at(now), id))
+            .collect();
+        ranked.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        ranked
+            .into_iter()
+            .take(self.limit)
+            .filter(|(_, id)| !connected.contains(*id))
+            .map(|(_, id)| (id.clone(), self.peers[id].addrs.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::id::RawId;
+
+    #[test]
+    fn test_candidates() {
+        let id = |n| FriendlyId::from(RawId::new([n; 32]));
+        let addr: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let mut p = Prewarmer::new(2);
+        for _ in 0..3 {
+            p.record_use(&id(1), vec![addr]);
+        }
+        p.record_use(&id(2), vec![addr]);
+        p.record_use(&id(2), vec![addr]);
+        p.record_use(&id(3), vec![addr]);
+
+        let mut connected = HashSet::new();
+        let c: Vec<_> = p.candidates(&connected).into_iter().map(|c| c.0).collect();
+        assert_eq!(vec![id(1), id(2)], c);
+
+        connected.insert(id(1));
+        let c: Vec<_> = p.candidates(&connected).into_iter().map(|c| c.0).collect();
+        assert_eq!(vec![id(2)], c);
+
+        let mut disabled = Prewarmer::new(0);
+        disabled.record_use(&id(1), vec![addr]);
+        assert!(disabled.candidates(&HashSet::new()).is_empty());
+    }
+}
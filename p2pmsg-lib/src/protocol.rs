@@ -1,3 +1,4 @@
-pub mod message;
+//! Wire protocol types are in p2pmsg-proto crate, re-exported here, codec binds them to tokio
+
+pub use p2pmsg_proto::{frame, id, message, state};
 pub mod codec;
-pub mod id;
\ No newline at end of file
@@ -1,41 +1,127 @@
-use bytes::BufMut;
+use bytes::{BufMut, BytesMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio_util::codec::{Decoder, Encoder};
 
+use super::frame;
 use super::message::Message;
 use crate::error::Error;
+use crate::telemetry::{record_frame_size, Direction};
+
+/// Counts bytes passed through codec, shared with connection owner
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    read_buffer_max: AtomicU64,
+}
+
+impl TrafficStats {
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// High-water mark of read buffer capacity
+    pub fn read_buffer_max(&self) -> u64 {
+        self.read_buffer_max.load(Ordering::Relaxed)
+    }
+}
+
+pub use super::frame::MAX_FRAME_SIZE;
+/// Same as initial capacity of read buffer in Framed
+const INITIAL_READ_BUFFER: usize = 8 * 1024;
+/// Drained read buffer bigger than this is released, so connection does not keep memory
+/// needed for its biggest frame forever
+const READ_BUFFER_KEEP: usize = 64 * 1024;
+/// When read buffers of all connections retain more memory, drained buffers are released
+/// down to initial size
+const READ_BUFFERS_CAP: u64 = 64 * 1024 * 1024;
+
+static READ_BUFFERS: AtomicU64 = AtomicU64::new(0);
+
+/// Memory retained by read buffers of all connections
+pub fn read_buffers_size() -> u64 {
+    READ_BUFFERS.load(Ordering::Relaxed)
+}
 
 pub struct MsgCodec {
     next_pos: usize,
+    /// Skipping rest of too long frame
+    discarding: bool,
+    stats: Arc<TrafficStats>,
+    /// Biggest capacity of read buffer since it was last released, decoded frames are split
+    /// from buffer, so its current capacity does not show memory it keeps
+    read_buffer: usize,
 }
 
 impl MsgCodec {
     pub fn new() -> Self {
-        MsgCodec { next_pos: 0 }
+        MsgCodec {
+            next_pos: 0,
+            discarding: false,
+            stats: Arc::new(TrafficStats::default()),
+            read_buffer: 0,
+        }
     }
-}
 
-impl Encoder<Message> for MsgCodec {
-    type Error = Error;
+    pub fn stats(&self) -> Arc<TrafficStats> {
+        self.stats.clone()
+    }
 
-    fn encode(&mut self, item: Message, buf: &mut bytes::BytesMut) -> Result<(), Self::Error> {
-        match serde_json::to_string(&item) {
-            Err(e) => Err(Box::new(e)),
-            Ok(data) => {
-                buf.reserve(data.len() + 1);
-                buf.put(data.as_bytes());
-                buf.put_u8(b'\n');
-                Ok(())
+    /// Releases drained read buffer if it grew too big, updates its accounting
+    fn track_read_buffer(&mut self, buf: &mut BytesMut, capacity: usize) {
+        let mut size = self.read_buffer.max(capacity);
+        self.stats
+            .read_buffer_max
+            .fetch_max(size as u64, Ordering::Relaxed);
+        if buf.is_empty() {
+            let keep = if READ_BUFFERS.load(Ordering::Relaxed) > READ_BUFFERS_CAP {
+                INITIAL_READ_BUFFER
+            } else {
+                READ_BUFFER_KEEP
+            };
+            if size > keep {
+                *buf = BytesMut::with_capacity(INITIAL_READ_BUFFER);
+                size = buf.capacity();
             }
         }
+        if size > self.read_buffer {
+            READ_BUFFERS.fetch_add((size - self.read_buffer) as u64, Ordering::Relaxed);
+        } else {
+            READ_BUFFERS.fetch_sub((self.read_buffer - size) as u64, Ordering::Relaxed);
+        }
+        self.read_buffer = size;
     }
-}
 
-impl Decoder for MsgCodec {
-    type Item = Message;
-    type Error = Error;
-
-    fn decode(&mut self, buf: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<Message>, Error> {
+        if self.discarding {
+            let skip = match buf.iter().position(|b| *b == b'\n') {
+                None => buf.len(),
+                Some(pos) => {
+                    self.discarding = false;
+                    pos + 1
+                }
+            };
+            let _ = buf.split_to(skip);
+            self.stats.bytes_in.fetch_add(skip as u64, Ordering::Relaxed);
+            if self.discarding {
+                return Ok(None);
+            }
+        }
         match buf[self.next_pos..].iter().position(|b| *b == b'\n') {
+            None if buf.len() > MAX_FRAME_SIZE => {
+                self.stats
+                    .bytes_in
+                    .fetch_add(buf.len() as u64, Ordering::Relaxed);
+                buf.clear();
+                self.next_pos = 0;
+                self.discarding = true;
+                Err(format!("Frame longer than {} bytes", MAX_FRAME_SIZE).into())
+            }
             None => {
                 self.next_pos = buf.len();
                 Ok(None)
@@ -44,24 +130,71 @@ impl Decoder for MsgCodec {
                 let pos = self.next_pos+pos;
                 self.next_pos = 0;
                 let data = buf.split_to(pos + 1);
-                Ok(Some(serde_json::from_slice(&data[..pos])
-                .map_err(|e| {
-                    error!("Serde error {}, data {:?}, pos {}, whole data {:?}", e, &data[..pos], pos, &data);
-                    e
-                })?))
+                self.stats
+                    .bytes_in
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+                // logged by connection owner, rate limited per peer
+                let msg: Message = frame::decode(&data[..pos])?;
+                record_frame_size(msg.kind(), Direction::In, data.len());
+                Ok(Some(msg))
             }
         }
     }
 }
 
+impl Encoder<Message> for MsgCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, buf: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        let data = frame::encode(&item)?;
+        buf.reserve(data.len());
+        buf.put(&data[..]);
+        self.stats
+            .bytes_out
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        record_frame_size(item.kind(), Direction::Out, data.len());
+        Ok(())
+    }
+}
+
+impl Decoder for MsgCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let capacity = buf.capacity();
+        let res = self.decode_frame(buf);
+        self.track_read_buffer(buf, capacity);
+        res
+    }
+}
+
+impl Drop for MsgCodec {
+    fn drop(&mut self) {
+        READ_BUFFERS.fetch_sub(self.read_buffer as u64, Ordering::Relaxed);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::id::RawId;
+    use crate::protocol::message::PeerInfo;
 
     #[test]
     fn test_json() {
         let m = Message::Hello {
             msg: "Hello world".into(),
+            info: PeerInfo {
+                id: RawId::random().into(),
+                addrs: vec![],
+                name: None,
+                uses_nat: false,
+                udp_port: None,
+            },
+            observed_addr: "127.0.0.1:12345".parse().unwrap(),
+            version: None,
+            timestamp: None,
         };
 
         let txt = serde_json::to_string(&m).unwrap();
@@ -81,9 +214,47 @@ mod tests {
         assert_eq!(0, buf.len());
 
         match (m, res) {
-            (Message::Hello { msg: m1 }, Some(Message::Hello { msg: m2 })) => assert_eq!(m1, m2),
+            (
+                Message::Hello { msg: m1, info: i1, .. },
+                Some(Message::Hello { msg: m2, info: i2, .. }),
+            ) => {
+                assert_eq!(m1, m2);
+                assert_eq!(i1.id, i2.id)
+            }
             _ => panic!("Not equal"),
         }
     }
-}
 
+    #[test]
+    fn test_too_long_frame() {
+        let mut codec = MsgCodec::new();
+        let mut buf = bytes::BytesMut::new();
+        buf.put(&vec![b'x'; MAX_FRAME_SIZE + 1][..]);
+        assert!(codec.decode(&mut buf).is_err());
+        assert_eq!(0, buf.len());
+
+        // rest of long frame is skipped, next frame is decoded
+        buf.put(&b"xxxx\n\"Ping\"\n"[..]);
+        match codec.decode(&mut buf).unwrap() {
+            Some(Message::Ping) => (),
+            m => panic!("Unexpected {:?}", m),
+        }
+        assert_eq!(0, buf.len());
+    }
+
+    #[test]
+    fn test_read_buffer_release() {
+        let mut codec = MsgCodec::new();
+        let mut buf = BytesMut::new();
+        buf.put(&vec![b' '; 2 * READ_BUFFER_KEEP][..]);
+        buf.put(&b"\"Ping\"\n\"Pong\""[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        // not drained yet
+        assert!(codec.read_buffer > 2 * READ_BUFFER_KEEP);
+        buf.put_u8(b'\n');
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert_eq!(INITIAL_READ_BUFFER, buf.capacity());
+        assert_eq!(INITIAL_READ_BUFFER, codec.read_buffer);
+        assert!(codec.stats().read_buffer_max() > 2 * READ_BUFFER_KEEP as u64);
+    }
+}
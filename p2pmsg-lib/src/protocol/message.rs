@@ -1,7 +0,0 @@
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum Message {
-    Hello { msg: String },
-    Ping,
-    Pong,
-    Terminate
-}
\ No newline at end of file
@@ -0,0 +1,52 @@
+//! Token bucket rate limiting, used for limiting requests of API keys and sends of bots
+
+use std::time::Instant;
+
+/// Allows bursts up to `rate`, refilled continuously to `rate` tokens per minute
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate_per_minute: u32, now: Instant) -> Self {
+        let rate = f64::from(rate_per_minute);
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    /// Takes token if available
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let refill = now.saturating_duration_since(self.last).as_secs_f64() * self.rate / 60.0;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let t = Instant::now();
+        let mut b = TokenBucket::new(2, t);
+        assert!(b.allow(t));
+        assert!(b.allow(t));
+        assert!(!b.allow(t));
+        assert!(b.allow(t + Duration::from_secs(30)));
+        assert!(!b.allow(t + Duration::from_secs(31)));
+    }
+}
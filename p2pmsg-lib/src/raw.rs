@@ -0,0 +1,243 @@
+//! Raw frames for extension protocols - opaque data multiplexed over existing connections,
+//! so experimental protocols can be prototyped outside of this crate.
+//!
+//! Each peer has bounded inbox of frames not yet acknowledged by consumers. When it is full,
+//! reading from that peer's connection stops, so slow consumer slows down the peer via TCP
+//! flow control, rather than frames being dropped or buffered without limit.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::client::{self, BroadcastScope, Node};
+use crate::error::Error;
+use crate::events::Events;
+use crate::protocol::message::Message;
+use crate::telemetry::{record_drop, DropReason};
+
+/// Max. number of raw frames from one peer (for all protocols), which are not yet acknowledged
+pub const INBOX_SIZE: usize = 64;
+
+// channel is bounded by inboxes
+type RawSender = mpsc::UnboundedSender<(SocketAddr, Vec<u8>)>;
+
+/// Registered protocols and inboxes of connected peers of one node
+#[derive(Clone)]
+pub(crate) struct RawRouter {
+    protocols: Arc<Mutex<HashMap<String, RawSender>>>,
+    inboxes: Arc<Mutex<HashMap<SocketAddr, Arc<Semaphore>>>>,
+    events: Events,
+}
+
+impl RawRouter {
+    pub(crate) fn new(events: Events) -> Self {
+        RawRouter {
+            protocols: Arc::new(Mutex::new(HashMap::new())),
+            inboxes: Arc::new(Mutex::new(HashMap::new())),
+            events,
+        }
+    }
+
+    pub(crate) fn open_inbox(&self, peer: SocketAddr) {
+        self.inboxes
+            .lock()
+            .unwrap()
+            .insert(peer, Arc::new(Semaphore::new(INBOX_SIZE)));
+    }
+
+    pub(crate) fn close_inbox(&self, peer: &SocketAddr) {
+        // wakes connection waiting for free slot, there is at most one
+        if let Some(inbox) = self.inboxes.lock().unwrap().remove(peer) {
+            inbox.add_permits(INBOX_SIZE)
+        }
+    }
+
+    fn inbox(&self, peer: &SocketAddr) -> Option<Arc<Semaphore>> {
+        self.inboxes.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Waits for free slot in peer's inbox, must be called before raw frame is dispatched
+    pub(crate) async fn reserve(&self, peer: SocketAddr) {
+        if let Some(inbox) = self.inbox(&peer) {
+            inbox.acquire().await.forget()
+        }
+    }
+
+    /// Takes free slot in peer's inbox if there is one, for transports which cannot wait
+    pub(crate) fn try_reserve(&self, peer: SocketAddr) -> bool {
+        match self.inbox(&peer) {
+            Some(inbox) => inbox.try_acquire().map(|p| p.forget()).is_ok(),
+            None => true,
+        }
+    }
+
+    fn release(&self, peer: SocketAddr, n: usize) {
+        if let Some(inbox) = self.inbox(&peer) {
+            let n = n.min(INBOX_SIZE - inbox.available_permits());
+            inbox.add_permits(n)
+        }
+    }
+
+    /// Passes received raw frame, for which inbox slot was reserved, to its protocol handle,
+    /// frame is dropped if handle is not registered
+    pub(crate) fn dispatch(&self, from: SocketAddr, protocol: String, data: Vec<u8>) {
+        let protocols = self.protocols.lock().unwrap();
+        let delivered = match protocols.get(&protocol) {
+            Some(tx) => tx.send((from, data)).is_ok(),
+            None => {
+                debug!("No handler for raw protocol {} from {}", protocol, from);
+                record_drop(&self.events, DropReason::UnknownProtocol, Some(from));
+                false
+            }
+        };
+        drop(protocols);
+        if !delivered {
+            self.release(from, 1)
+        }
+    }
+}
+
+/// Handle of registered raw protocol, protocol is unregistered when handle is dropped
+pub struct RawProtocol {
+    name: String,
+    node: Node,
+    rx: mpsc::UnboundedReceiver<(SocketAddr, Vec<u8>)>,
+    /// Received frames not yet acknowledged by consumer, None in auto-ack mode
+    unacked: Option<HashMap<SocketAddr, usize>>,
+}
+
+pub(crate) fn register(node: &Node, name: &str, auto_ack: bool) -> Result<RawProtocol, Error> {
+    let mut protocols = node.raw().protocols.lock().unwrap();
+    if protocols.contains_key(name) {
+        return Err(format!("Raw protocol {} is already registered", name).into());
+    }
+    let (tx, rx) = mpsc::unbounded_channel();
+    protocols.insert(name.into(), tx);
+    Ok(RawProtocol {
+        name: name.into(),
+        node: node.clone(),
+        rx,
+        unacked: if auto_ack { None } else { Some(HashMap::new()) },
+    })
+}
+
+/// Registers raw protocol with given name on default node, there can be only one handle
+/// for a name. Frames are acknowledged when they are received from handle.
+pub fn register_raw_protocol(name: &str) -> Result<RawProtocol, Error> {
+    register(&client::default_node(), name, true)
+}
+
+/// Registers raw protocol on default node, which consumer acknowledges each processed frame
+/// with `RawProtocol::ack`, so peer is slowed down until consumer is done with its frames
+pub fn register_raw_protocol_with_ack(name: &str) -> Result<RawProtocol, Error> {
+    register(&client::default_node(), name, false)
+}
+
+impl RawProtocol {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn message(&self, data: Vec<u8>) -> Message {
+        Message::Raw {
+            protocol: self.name.clone(),
+            data,
+        }
+    }
+
+    pub async fn send(&self, to: SocketAddr, data: Vec<u8>) -> Result<(), Error> {
+        self.node.send(to, self.message(data)).await
+    }
+
+    /// Sends small latency-sensitive frame over UDP transport if peer supports it
+    pub async fn send_fast(&self, to: SocketAddr, data: Vec<u8>) -> Result<(), Error> {
+        self.node.send_fast(to, self.message(data)).await
+    }
+
+    /// Sends frame to all connected peers except given ones, returns number of peers
+    pub async fn broadcast_except(&self, data: Vec<u8>, except: &[SocketAddr]) -> usize {
+        let msg = self.message(data);
+        self.node.broadcast(msg, except, BroadcastScope::All).await
+    }
+
+    /// Sends frame only to peers connected from local network, returns number of peers
+    pub async fn broadcast_local(&self, data: Vec<u8>) -> usize {
+        let msg = self.message(data);
+        self.node.broadcast(msg, &[], BroadcastScope::Local).await
+    }
+
+    /// Next frame received for this protocol with its sender
+    pub async fn recv(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        let frame = self.rx.recv().await;
+        if let Some((from, _)) = frame {
+            match self.unacked {
+                Some(ref mut unacked) => *unacked.entry(from).or_default() += 1,
+                None => self.node.raw().release(from, 1),
+            }
+        }
+        frame
+    }
+
+    /// Acknowledges processed frame from peer, only needed for handle registered with
+    /// `register_raw_protocol_with_ack`
+    pub fn ack(&mut self, from: SocketAddr) {
+        if let Some(ref mut unacked) = self.unacked {
+            match unacked.get_mut(&from) {
+                Some(n) if *n > 1 => *n -= 1,
+                Some(_) => {
+                    unacked.remove(&from);
+                }
+                None => return,
+            }
+            self.node.raw().release(from, 1)
+        }
+    }
+}
+
+impl Drop for RawProtocol {
+    fn drop(&mut self) {
+        let raw = self.node.raw();
+        raw.protocols.lock().unwrap().remove(&self.name);
+        self.rx.close();
+        while let Ok((from, _)) = self.rx.try_recv() {
+            raw.release(from, 1)
+        }
+        if let Some(ref mut unacked) = self.unacked {
+            for (from, n) in unacked.drain() {
+                raw.release(from, n)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inbox() {
+        let peer: SocketAddr = "127.0.0.1:7801".parse().unwrap();
+        let node = Node::new();
+        let raw = node.raw().clone();
+        raw.open_inbox(peer);
+        let mut proto = node.register_raw_protocol_with_ack("test_inbox").unwrap();
+        for _ in 0..INBOX_SIZE {
+            raw.reserve(peer).await;
+            raw.dispatch(peer, "test_inbox".into(), vec![1]);
+        }
+        assert!(!raw.try_reserve(peer));
+        let (from, _) = proto.recv().await.unwrap();
+        assert!(!raw.try_reserve(peer));
+        proto.ack(from);
+        assert!(raw.try_reserve(peer));
+        // frame for unknown protocol frees its slot
+        raw.dispatch(peer, "test_inbox_unknown".into(), vec![1]);
+        assert!(raw.try_reserve(peer));
+        raw.dispatch(peer, "test_inbox".into(), vec![1]);
+        // unacknowledged frames are released with handle
+        drop(proto);
+        assert_eq!(INBOX_SIZE, raw.inbox(&peer).unwrap().available_permits());
+        raw.close_inbox(&peer);
+    }
+}
@@ -0,0 +1,129 @@
+//! Resolution of peer ids to addresses, where peer can be dialed. Resolvers are tried
+//! in order of registration, first one returning some addresses wins.
+
+use futures::future::{self, BoxFuture};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::client;
+use crate::protocol::id::FriendlyId;
+
+/// Maximum number of addresses remembered for one peer
+const MAX_ADDRS: usize = 8;
+
+pub trait Resolver: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn resolve<'a>(&'a self, id: &'a FriendlyId) -> BoxFuture<'a, Vec<SocketAddr>>;
+}
+
+/// Addresses learned during this run - from connected peers, invites and LAN discovery
+#[derive(Default)]
+pub struct AddressBook {
+    addrs: RwLock<HashMap<FriendlyId, Vec<SocketAddr>>>,
+}
+
+impl AddressBook {
+    /// Adds addresses of peer, latest added are tried first
+    pub fn add(&self, id: FriendlyId, addrs: Vec<SocketAddr>) {
+        let mut book = self.addrs.write().unwrap();
+        let known = book.entry(id).or_default();
+        known.retain(|a| !addrs.contains(a));
+        let mut merged = addrs;
+        merged.append(known);
+        merged.truncate(MAX_ADDRS);
+        *known = merged;
+    }
+
+    pub fn remove(&self, id: &FriendlyId) {
+        self.addrs.write().unwrap().remove(id);
+    }
+
+    pub fn get(&self, id: &FriendlyId) -> Vec<SocketAddr> {
+        self.addrs
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Resolver for AddressBook {
+    fn name(&self) -> &str {
+        "address book"
+    }
+
+    fn resolve<'a>(&'a self, id: &'a FriendlyId) -> BoxFuture<'a, Vec<SocketAddr>> {
+        Box::pin(future::ready(self.get(id)))
+    }
+}
+
+/// Resolvers of one node, its address book is always the first one
+pub struct Resolvers {
+    book: Arc<AddressBook>,
+    resolvers: RwLock<Vec<Arc<dyn Resolver>>>,
+}
+
+impl Resolvers {
+    pub fn new() -> Self {
+        let book = Arc::new(AddressBook::default());
+        Resolvers {
+            resolvers: RwLock::new(vec![book.clone() as Arc<dyn Resolver>]),
+            book,
+        }
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.book
+    }
+
+    /// Adds resolver after already registered ones
+    pub fn add(&self, resolver: Arc<dyn Resolver>) {
+        self.resolvers.write().unwrap().push(resolver)
+    }
+
+    /// Addresses of peer from first resolver, which knows it
+    pub async fn resolve(&self, id: &FriendlyId) -> Vec<SocketAddr> {
+        let resolvers = self.resolvers.read().unwrap().clone();
+        for r in resolvers {
+            let addrs = r.resolve(id).await;
+            if !addrs.is_empty() {
+                debug!("Peer {} resolved by {} to {:?}", id, r.name(), addrs);
+                return addrs;
+            }
+        }
+        vec![]
+    }
+}
+
+/// Adds resolver to default node
+pub fn add_resolver(resolver: Arc<dyn Resolver>) {
+    client::default_node().resolvers().add(resolver)
+}
+
+/// Addresses of peer known to default node
+pub async fn resolve(id: &FriendlyId) -> Vec<SocketAddr> {
+    client::default_node().resolvers().resolve(id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::id::RawId;
+
+    #[test]
+    fn test_address_book() {
+        let book = AddressBook::default();
+        let id = FriendlyId::from(RawId::new([1; 32]));
+        let a: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let b: SocketAddr = "1.2.3.4:6".parse().unwrap();
+        book.add(id.clone(), vec![a]);
+        book.add(id.clone(), vec![b, a]);
+        assert_eq!(vec![b, a], book.get(&id));
+        book.add(id.clone(), vec![a]);
+        assert_eq!(vec![a, b], book.get(&id));
+        assert!(book.get(&RawId::new([2; 32]).into()).is_empty());
+    }
+}
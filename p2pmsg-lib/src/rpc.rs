@@ -0,0 +1,472 @@
+//! HTTP endpoint for external systems to send messages through running node.
+//!
+//! `POST /send` with `Authorization: Bearer <api key>` and JSON body
+//! `{"peer": <address or id>, "protocol": <raw protocol>, "data": <text or array of bytes>}`
+//! sends raw frame to the peer. `GET /peers` returns connected peers as JSON array.
+//! Each API key is rate limited. Send requests with `Idempotency-Key` header are
+//! executed once, repeated request gets the same response, or 409 Conflict while
+//! the first one is still in progress.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::client::{self, Node};
+use crate::clock;
+use crate::error::Error;
+use crate::protocol::id::FriendlyId;
+use crate::protocol::message::Message;
+use crate::ratelimit::TokenBucket;
+
+const MAX_HEAD_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 3600);
+/// Max remembered idempotency keys, oldest are forgotten first
+const IDEMPOTENCY_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    pub addr: SocketAddr,
+    pub api_keys: Vec<String>,
+    /// Requests per minute per API key
+    pub rate_limit: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Deserialize)]
+struct SendRequest {
+    peer: String,
+    protocol: String,
+    data: Payload,
+}
+
+/// Token bucket per API key
+struct RateLimiter {
+    rate: u32,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    fn allow(&mut self, key: &str, now: Instant) -> bool {
+        let rate = self.rate;
+        self.buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .allow(now)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Execution {
+    InProgress,
+    Done(Response),
+}
+
+/// Executions of requests by (API key, Idempotency-Key)
+struct IdempotencyCache {
+    responses: HashMap<(String, String), (Instant, Execution)>,
+}
+
+impl IdempotencyCache {
+    fn new() -> Self {
+        IdempotencyCache {
+            responses: HashMap::new(),
+        }
+    }
+
+    fn get(&self, key: &(String, String), now: Instant) -> Option<Execution> {
+        self.responses
+            .get(key)
+            .filter(|(at, _)| now.saturating_duration_since(*at) < IDEMPOTENCY_TTL)
+            .map(|(_, r)| r.clone())
+    }
+
+    fn remove(&mut self, key: &(String, String)) {
+        self.responses.remove(key);
+    }
+
+    fn insert(&mut self, key: (String, String), execution: Execution, now: Instant) {
+        self.responses
+            .retain(|_, (at, _)| now.saturating_duration_since(*at) < IDEMPOTENCY_TTL);
+        if self.responses.len() >= IDEMPOTENCY_CAPACITY {
+            let oldest = self
+                .responses
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(k, _)| k.clone());
+            if let Some(k) = oldest {
+                self.responses.remove(&k);
+            }
+        }
+        self.responses.insert(key, (now, execution));
+    }
+}
+
+type Response = (u16, String);
+
+fn error_response(status: u16, msg: &str) -> Response {
+    (status, serde_json::json!({ "error": msg }).to_string())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    fn api_key(&self) -> Option<&str> {
+        self.header("authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+    }
+}
+
+/// Compares secrets in time independent of position of first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn parse_head(head: &str) -> Result<(String, String, HashMap<String, String>), Error> {
+    let mut lines = head.lines();
+    let mut parts = lines.next().unwrap_or("").split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(m), Some(p)) => (m.to_string(), p.to_string()),
+        _ => return Err("invalid request line".into()),
+    };
+    let headers = lines
+        .filter_map(|l| {
+            let i = l.find(':')?;
+            Some((
+                l[..i].trim().to_ascii_lowercase(),
+                l[i + 1..].trim().to_string(),
+            ))
+        })
+        .collect();
+    Ok((method, path, headers))
+}
+
+async fn read_request(socket: &mut TcpStream) -> Result<Request, Error> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err("request head too big".into());
+        }
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("incomplete request".into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let (method, path, headers) = parse_head(&String::from_utf8_lossy(&buf[..head_end]))?;
+    let len: usize = match headers.get("content-length") {
+        Some(l) => l.parse().map_err(|_| "invalid Content-Length")?,
+        None => 0,
+    };
+    if len > MAX_BODY_SIZE {
+        return Err("request body too big".into());
+    }
+    let mut body = buf.split_off(head_end + 4);
+    while body.len() < len {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("incomplete request body".into());
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(len);
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+async fn send_message(node: &Node, body: &[u8]) -> Response {
+    let req: SendRequest = match serde_json::from_slice(body) {
+        Ok(r) => r,
+        Err(e) => return error_response(400, &format!("invalid body: {}", e)),
+    };
+    let data = match req.data {
+        Payload::Text(s) => s.into_bytes(),
+        Payload::Bytes(b) => b,
+    };
+    let msg = Message::Raw {
+        protocol: req.protocol,
+        data,
+    };
+    let res = if let Ok(addr) = req.peer.parse::<SocketAddr>() {
+        node.send(addr, msg).await
+    } else if let Ok(id) = req.peer.parse::<FriendlyId>() {
+        node.send_to(&id, msg).await
+    } else {
+        return error_response(400, "peer must be address or id");
+    };
+    match res {
+        Ok(()) => (200, "{\"ok\":true}".into()),
+        Err(e) => error_response(502, &e.to_string()),
+    }
+}
+
+async fn peers(node: &Node) -> Response {
+    match serde_json::to_string(&node.list_peers().await) {
+        Ok(body) => (200, body),
+        Err(e) => error_response(500, &e.to_string()),
+    }
+}
+
+struct RpcServer {
+    node: Node,
+    api_keys: Vec<String>,
+    limiter: Mutex<RateLimiter>,
+    idempotency: Mutex<IdempotencyCache>,
+}
+
+impl RpcServer {
+    async fn respond(&self, req: &Request) -> Response {
+        let is_send = match (req.method.as_str(), req.path.as_str()) {
+            ("POST", "/send") => true,
+            ("GET", "/peers") => false,
+            _ => return error_response(404, "not found"),
+        };
+        let key = match req.api_key() {
+            // all keys are compared, so timing does not tell which one matched
+            Some(k) if self
+                .api_keys
+                .iter()
+                .fold(false, |found, a| found | constant_time_eq(a.as_bytes(), k.as_bytes())) =>
+            {
+                k.to_string()
+            }
+            _ => return error_response(401, "invalid API key"),
+        };
+        let idempotency_key = req
+            .header("idempotency-key")
+            .filter(|_| is_send)
+            .map(|k| (key.clone(), k.into()));
+        {
+            let mut idempotency = self.idempotency.lock().unwrap();
+            let now = clock::now();
+            if let Some(ref k) = idempotency_key {
+                match idempotency.get(k, now) {
+                    Some(Execution::Done(r)) => return r,
+                    Some(Execution::InProgress) => {
+                        return error_response(409, "request with this Idempotency-Key is in progress")
+                    }
+                    None => (),
+                }
+            }
+            if !self.limiter.lock().unwrap().allow(&key, now) {
+                return error_response(429, "rate limit exceeded");
+            }
+            // concurrent request with same key must not send again
+            if let Some(ref k) = idempotency_key {
+                idempotency.insert(k.clone(), Execution::InProgress, now);
+            }
+        }
+        if !is_send {
+            return peers(&self.node).await;
+        }
+        let response = send_message(&self.node, &req.body).await;
+        if let Some(k) = idempotency_key {
+            let mut idempotency = self.idempotency.lock().unwrap();
+            // failed sends can be retried with same key
+            if response.0 < 500 {
+                idempotency.insert(k, Execution::Done(response.clone()), clock::now());
+            } else {
+                idempotency.remove(&k);
+            }
+        }
+        response
+    }
+
+    async fn handle_request(&self, mut socket: TcpStream) -> Result<(), Error> {
+        let (status, body) =
+            match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut socket)).await? {
+                Ok(req) => self.respond(&req).await,
+                Err(e) => error_response(400, &e.to_string()),
+            };
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            409 => "Conflict",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            _ => "Bad Gateway",
+        };
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown(std::net::Shutdown::Write)?;
+        Ok(())
+    }
+}
+
+/// Serves RPC endpoint of default node until error in listener
+pub async fn run_rpc_server(config: RpcConfig) -> Result<(), Error> {
+    run_rpc_server_for(client::default_node(), config).await
+}
+
+/// Serves RPC endpoint of given node until error in listener
+pub async fn run_rpc_server_for(node: Node, config: RpcConfig) -> Result<(), Error> {
+    if config.api_keys.is_empty() {
+        return Err("RPC endpoint requires at least one API key".into());
+    }
+    let mut listener = TcpListener::bind(&config.addr).await?;
+    info!("RPC endpoint available on http://{}", config.addr);
+    let server = std::sync::Arc::new(RpcServer {
+        node,
+        api_keys: config.api_keys,
+        limiter: Mutex::new(RateLimiter::new(config.rate_limit)),
+        idempotency: Mutex::new(IdempotencyCache::new()),
+    });
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            server
+                .handle_request(socket)
+                .await
+                .unwrap_or_else(|e| debug!("RPC request error: {}", e))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut l = RateLimiter::new(2);
+        let t = Instant::now();
+        assert!(l.allow("a", t));
+        assert!(l.allow("a", t));
+        assert!(!l.allow("a", t));
+        assert!(l.allow("b", t));
+    }
+
+    #[test]
+    fn test_idempotency_cache() {
+        let mut c = IdempotencyCache::new();
+        let t = Instant::now();
+        let k = ("key".to_string(), "req-1".to_string());
+        assert_eq!(None, c.get(&k, t));
+        c.insert(k.clone(), Execution::InProgress, t);
+        assert_eq!(Some(Execution::InProgress), c.get(&k, t));
+        c.insert(k.clone(), Execution::Done((200, "{}".into())), t);
+        assert_eq!(
+            Some(Execution::Done((200, "{}".into()))),
+            c.get(&k, t + Duration::from_secs(1))
+        );
+        assert_eq!(None, c.get(&("other".into(), "req-1".into()), t));
+        assert_eq!(None, c.get(&k, t + IDEMPOTENCY_TTL));
+    }
+
+    #[test]
+    fn test_parse_head() {
+        let (method, path, headers) =
+            parse_head("POST /send HTTP/1.1\r\nAuthorization: Bearer s3cret\r\nContent-Length: 2")
+                .unwrap();
+        assert_eq!(("POST", "/send"), (&method[..], &path[..]));
+        let req = Request {
+            method,
+            path,
+            headers,
+            body: vec![],
+        };
+        assert_eq!(Some("s3cret"), req.api_key());
+        assert_eq!(Some("2"), req.header("content-length"));
+        assert!(parse_head("garbage").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_in_progress() {
+        let server = RpcServer {
+            node: Node::new(),
+            api_keys: vec!["key".into()],
+            limiter: Mutex::new(RateLimiter::new(60)),
+            idempotency: Mutex::new(IdempotencyCache::new()),
+        };
+        let k = ("key".to_string(), "req-1".to_string());
+        server
+            .idempotency
+            .lock()
+            .unwrap()
+            .insert(k, Execution::InProgress, clock::now());
+        let mut headers = HashMap::new();
+        headers.insert("authorization".to_string(), "Bearer key".to_string());
+        headers.insert("idempotency-key".to_string(), "req-1".to_string());
+        let req = Request {
+            method: "POST".into(),
+            path: "/send".into(),
+            headers,
+            body: b"{}".to_vec(),
+        };
+        assert_eq!(409, server.respond(&req).await.0);
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let server = RpcServer {
+            node: Node::new(),
+            api_keys: vec!["key".into()],
+            limiter: Mutex::new(RateLimiter::new(60)),
+            idempotency: Mutex::new(IdempotencyCache::new()),
+        };
+        let request = |method: &str, path: &str, key: &str| {
+            let mut headers = HashMap::new();
+            headers.insert("authorization".to_string(), format!("Bearer {}", key));
+            Request {
+                method: method.into(),
+                path: path.into(),
+                headers,
+                body: vec![],
+            }
+        };
+        assert_eq!(200, server.respond(&request("GET", "/peers", "key")).await.0);
+        assert_eq!(401, server.respond(&request("GET", "/peers", "nope")).await.0);
+        assert_eq!(404, server.respond(&request("GET", "/send", "key")).await.0);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+    }
+}
@@ -0,0 +1,119 @@
+//! Round trip time estimation (Jacobson/Karels, as in RFC 6298)
+//! fed from keepalive ping/pong exchanges.
+
+use std::time::Duration;
+
+const ALPHA: f64 = 1.0 / 8.0;
+const BETA: f64 = 1.0 / 4.0;
+const K: f64 = 4.0;
+const LOSS_GAIN: f64 = 1.0 / 8.0;
+
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+    srtt: Option<f64>,
+    rttvar: f64,
+    rto: Duration,
+    loss: f64,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: 0.0,
+            rto: INITIAL_RTO,
+            loss: 0.0,
+        }
+    }
+
+    /// Records successfully measured round trip
+    pub fn on_sample(&mut self, rtt: Duration) {
+        let r = rtt.as_secs_f64();
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2.0;
+            }
+            Some(srtt) => {
+                self.rttvar = (1.0 - BETA) * self.rttvar + BETA * (srtt - r).abs();
+                self.srtt = Some((1.0 - ALPHA) * srtt + ALPHA * r);
+            }
+        }
+        self.loss *= 1.0 - LOSS_GAIN;
+        self.rto = self.compute_rto();
+    }
+
+    /// Records probe, which was not answered within current RTO - backs off timer
+    pub fn on_timeout(&mut self) {
+        self.loss = (1.0 - LOSS_GAIN) * self.loss + LOSS_GAIN;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+
+    fn compute_rto(&self) -> Duration {
+        let srtt = self.srtt.unwrap_or(0.0);
+        let rto = Duration::from_secs_f64(srtt + K * self.rttvar);
+        rto.max(MIN_RTO).min(MAX_RTO)
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt.map(Duration::from_secs_f64)
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        Duration::from_secs_f64(self.rttvar)
+    }
+
+    /// Current retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Smoothed probe loss rate in range 0.0 - 1.0
+    pub fn loss(&self) -> f64 {
+        self.loss
+    }
+
+    /// Connection quality in range 0.0 (useless) - 1.0 (perfect), penalizes losses and jitter
+    pub fn quality(&self) -> f64 {
+        let jitter_penalty = match self.srtt {
+            Some(srtt) if srtt > 0.0 => 1.0 / (1.0 + self.rttvar / srtt),
+            _ => 1.0,
+        };
+        (1.0 - self.loss) * jitter_penalty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rto() {
+        let mut e = RttEstimator::new();
+        assert_eq!(INITIAL_RTO, e.rto());
+
+        e.on_sample(Duration::from_millis(100));
+        assert_eq!(Some(Duration::from_millis(100)), e.srtt());
+        // 100ms + 4 * 50ms
+        assert_eq!(Duration::from_millis(300), e.rto());
+
+        for _ in 0..50 {
+            e.on_sample(Duration::from_millis(100));
+        }
+        assert_eq!(MIN_RTO, e.rto());
+        assert!(e.quality() > 0.99);
+
+        e.on_timeout();
+        assert_eq!(MIN_RTO * 2, e.rto());
+        assert!(e.loss() > 0.0);
+        for _ in 0..20 {
+            e.on_timeout();
+        }
+        assert_eq!(MAX_RTO, e.rto());
+        assert!(e.quality() < 0.5);
+    }
+}
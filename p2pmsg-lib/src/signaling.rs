@@ -0,0 +1,104 @@
+//! Signaling channel for realtime streams - peers exchange offers, answers and candidates
+//! over existing connections, actual media is handed off to external realtime stack.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use crate::client::{self, Node};
+use crate::error::Error;
+use crate::events::Events;
+use crate::protocol::message::{Message, Signal};
+use crate::telemetry::{record_drop, DropReason};
+
+const SIGNAL_QUEUE_SIZE: usize = 64;
+
+type SignalSender = mpsc::Sender<(SocketAddr, String, Signal)>;
+
+/// Signaling channel of one node, if open
+pub(crate) type SignalingSlot = Arc<Mutex<Option<SignalSender>>>;
+
+/// Handle of signaling channel, there can be only one at a time per node,
+/// channel is closed when handle is dropped
+pub struct Signaling {
+    node: Node,
+    rx: mpsc::Receiver<(SocketAddr, String, Signal)>,
+}
+
+pub(crate) fn open(node: &Node) -> Result<Signaling, Error> {
+    let mut signaling = node.signaling().lock().unwrap();
+    if signaling.is_some() {
+        return Err("Signaling channel is already open".into());
+    }
+    let (tx, rx) = mpsc::channel(SIGNAL_QUEUE_SIZE);
+    *signaling = Some(tx);
+    Ok(Signaling {
+        node: node.clone(),
+        rx,
+    })
+}
+
+/// Opens signaling channel of default node, signals received while it is not open are dropped
+pub fn open_signaling() -> Result<Signaling, Error> {
+    open(&client::default_node())
+}
+
+impl Signaling {
+    pub async fn send(&self, to: SocketAddr, session: &str, signal: Signal) -> Result<(), Error> {
+        let msg = Message::Signal {
+            session: session.into(),
+            signal,
+        };
+        self.node.send(to, msg).await
+    }
+
+    pub async fn offer(&self, to: SocketAddr, session: &str, description: String) -> Result<(), Error> {
+        self.send(to, session, Signal::Offer { description }).await
+    }
+
+    pub async fn answer(&self, to: SocketAddr, session: &str, description: String) -> Result<(), Error> {
+        self.send(to, session, Signal::Answer { description }).await
+    }
+
+    pub async fn candidate(&self, to: SocketAddr, session: &str, candidate: String) -> Result<(), Error> {
+        self.send(to, session, Signal::Candidate { candidate }).await
+    }
+
+    pub async fn hangup(&self, to: SocketAddr, session: &str) -> Result<(), Error> {
+        self.send(to, session, Signal::Hangup).await
+    }
+
+    /// Next signal received with its sender and session, to be passed to realtime stack
+    pub async fn recv(&mut self) -> Option<(SocketAddr, String, Signal)> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Signaling {
+    fn drop(&mut self) {
+        self.node.signaling().lock().unwrap().take();
+    }
+}
+
+/// Passes received signal to open signaling channel
+pub(crate) fn dispatch(
+    slot: &SignalingSlot,
+    events: &Events,
+    from: SocketAddr,
+    session: String,
+    signal: Signal,
+) {
+    let mut signaling = slot.lock().unwrap();
+    match signaling.as_mut() {
+        Some(tx) => {
+            if tx.try_send((from, session, signal)).is_err() {
+                warn!("Dropping signal from {}", from);
+                record_drop(events, DropReason::QueueOverflow, Some(from));
+            }
+        }
+        None => {
+            debug!("No signaling channel open for signal from {}", from);
+            record_drop(events, DropReason::UnknownProtocol, Some(from));
+        }
+    }
+}
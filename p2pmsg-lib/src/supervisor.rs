@@ -0,0 +1,207 @@
+//! Supervision of node tasks - all spawned tasks are tracked, so failures are logged
+//! and tasks are stopped on shutdown. Restartable tasks are restarted with backoff,
+//! failure of critical task is fatal for the node.
+
+use futures::future::{abortable, AbortHandle};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::clock;
+use crate::error::Error;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Task running longer than this before failure is restarted with initial backoff
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+struct Tasks {
+    next_id: u64,
+    running: HashMap<u64, (&'static str, AbortHandle)>,
+    stopped: bool,
+}
+
+type FatalSender = mpsc::UnboundedSender<(&'static str, Error)>;
+type FatalReceiver = mpsc::UnboundedReceiver<(&'static str, Error)>;
+
+struct Inner {
+    tasks: Mutex<Tasks>,
+    fatal_tx: FatalSender,
+    fatal_rx: tokio::sync::Mutex<FatalReceiver>,
+}
+
+/// Tasks of one node, each `Node` has its own, free functions use the one of default node
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    inner: Arc<Inner>,
+}
+
+lazy_static! {
+    static ref SUPERVISOR: Supervisor = Supervisor::new();
+}
+
+/// Supervisor of default node
+pub(crate) fn global() -> Supervisor {
+    SUPERVISOR.clone()
+}
+
+impl Supervisor {
+    pub(crate) fn new() -> Self {
+        let (fatal_tx, fatal_rx) = mpsc::unbounded_channel();
+        Supervisor {
+            inner: Arc::new(Inner {
+                tasks: Mutex::new(Tasks {
+                    next_id: 0,
+                    running: HashMap::new(),
+                    stopped: false,
+                }),
+                fatal_tx,
+                fatal_rx: tokio::sync::Mutex::new(fatal_rx),
+            }),
+        }
+    }
+
+    fn spawn_tracked<F>(&self, name: &'static str, fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (fut, handle) = abortable(fut);
+        let id = {
+            let mut tasks = self.inner.tasks.lock().unwrap();
+            if tasks.stopped {
+                debug!("Not starting task {}, node is shutting down", name);
+                return;
+            }
+            let id = tasks.next_id;
+            tasks.next_id += 1;
+            tasks.running.insert(id, (name, handle));
+            id
+        };
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            if fut.await.is_err() {
+                debug!("Task {} aborted", name);
+            }
+            inner.tasks.lock().unwrap().running.remove(&id);
+        });
+    }
+
+    /// Spawns task, its failure is just logged
+    pub(crate) fn spawn<F>(&self, name: &'static str, fut: F)
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.spawn_tracked(name, async move {
+            if let Err(e) = fut.await {
+                error!("Task {} failed: {}", name, e)
+            }
+        })
+    }
+
+    /// Spawns task, which is restarted with exponential backoff, when it fails,
+    /// successful finish ends it
+    #[cfg_attr(not(feature = "discovery"), allow(dead_code))]
+    pub(crate) fn spawn_restartable<F, Fut>(&self, name: &'static str, factory: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.spawn_tracked(name, async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let started = clock::now();
+                match factory().await {
+                    Ok(()) => return,
+                    Err(e) => {
+                        if clock::elapsed(started) >= HEALTHY_RUN {
+                            backoff = INITIAL_BACKOFF;
+                        }
+                        error!("Task {} failed: {}, restarting in {:?}", name, e, backoff);
+                        tokio::time::delay_for(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Spawns task, which node cannot work without, its failure is reported by `fatal_error`
+    pub(crate) fn spawn_critical<F>(&self, name: &'static str, fut: F)
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let fatal = self.inner.fatal_tx.clone();
+        self.spawn_tracked(name, async move {
+            if let Err(e) = fut.await {
+                error!("Critical task {} failed: {}", name, e);
+                fatal.send((name, e)).ok();
+            }
+        })
+    }
+
+    /// Resolves with first failure of critical task, concurrent callers wait in turn
+    pub(crate) async fn fatal_error(&self) -> Error {
+        match self.inner.fatal_rx.lock().await.recv().await {
+            Some((name, e)) => format!("Task {} failed: {}", name, e).into(),
+            // sender is kept in self
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Number of running tasks by name
+    pub(crate) fn running_tasks(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: HashMap<&'static str, usize> = HashMap::new();
+        for (name, _) in self.inner.tasks.lock().unwrap().running.values() {
+            *counts.entry(name).or_default() += 1;
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort();
+        counts
+    }
+
+    /// Aborts all running tasks, no new tasks are started until `resume`
+    pub(crate) fn stop_all(&self) {
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        tasks.stopped = true;
+        for (_, (name, handle)) in tasks.running.drain() {
+            debug!("Stopping task {}", name);
+            handle.abort();
+        }
+    }
+
+    /// Allows starting tasks again after `stop_all`, failures of stopped tasks are forgotten
+    pub(crate) fn resume(&self) {
+        self.inner.tasks.lock().unwrap().stopped = false;
+        if let Ok(mut rx) = self.inner.fatal_rx.try_lock() {
+            while rx.try_recv().is_ok() {}
+        }
+    }
+}
+
+/// Number of running tasks of default node by name
+pub fn running_tasks() -> Vec<(&'static str, usize)> {
+    SUPERVISOR.running_tasks()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_supervisors_are_independent() {
+        let (a, b) = (Supervisor::new(), Supervisor::new());
+        a.spawn_critical("failing", async { Err("boom".into()) });
+        b.spawn("pending", futures::future::pending());
+        let e = a.fatal_error().await;
+        assert!(e.to_string().contains("boom"));
+        a.stop_all();
+        assert_eq!(vec![("pending", 1)], b.running_tasks());
+        b.stop_all();
+        b.resume();
+        b.spawn("pending", futures::future::pending());
+        assert_eq!(vec![("pending", 1)], b.running_tasks());
+        b.stop_all();
+    }
+}
@@ -0,0 +1,75 @@
+//! Systemd socket activation - see sd_listen_fds(3).
+//!
+//! With socket activation systemd binds listening socket (possibly on privileged port)
+//! and passes it to our process as file descriptor 3, announcing it in
+//! `LISTEN_PID` and `LISTEN_FDS` environment variables. More sockets are passed as
+//! consecutive descriptors 4, 5, ...
+
+use std::env;
+use std::sync::Mutex;
+
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+lazy_static! {
+    /// Sockets passed by systemd and not taken yet, None until environment is read
+    static ref PASSED_FDS: Mutex<Option<usize>> = Mutex::new(None);
+}
+
+/// Number of sockets passed to this process by systemd
+fn read_listen_fds() -> usize {
+    let pid_matches = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok())
+        .map(|p| p == std::process::id())
+        .unwrap_or(false);
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(0);
+    if pid_matches {
+        fds
+    } else {
+        0
+    }
+}
+
+/// Reads systemd environment variables and clears them, so sockets are not inherited by
+/// child processes. Environment can be safely changed only while process has one thread,
+/// so call this at start of main, before tokio runtime is built. Without it the variables
+/// are just read, when node is run.
+pub fn init() {
+    let fds = read_listen_fds();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    *PASSED_FDS.lock().unwrap() = Some(fds);
+}
+
+/// Passed sockets can be taken just once, by first node run in process
+fn take_listen_fds() -> usize {
+    let mut passed = PASSED_FDS.lock().unwrap();
+    let fds = passed.unwrap_or_else(read_listen_fds);
+    *passed = Some(0);
+    fds
+}
+
+/// Takes listening TCP sockets passed by systemd, empty if there are none
+#[cfg(unix)]
+pub fn take_listen_sockets() -> Vec<std::net::TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let n = take_listen_fds() as i32;
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n)
+        // safe as systemd guarantees fds from 3 to 3 + LISTEN_FDS are open sockets owned by us
+        .map(|fd| unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn take_listen_sockets() -> Vec<std::net::TcpListener> {
+    if take_listen_fds() > 0 {
+        warn!("Socket activation is supported only on unix");
+    }
+    Vec::new()
+}
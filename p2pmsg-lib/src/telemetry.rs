@@ -0,0 +1,379 @@
+//! Counters of dropped messages by reason, so silent data loss can be detected,
+//! frame sizes and protocol errors caused by peers
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::events::{Events, NodeEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DropReason {
+    /// Internal queue was full
+    QueueOverflow,
+    /// Message to peer, which is not connected
+    UnknownPeer,
+    /// Raw frame for protocol without registered handler
+    UnknownProtocol,
+    /// Frame could not be decoded
+    Malformed,
+    /// Message received after connection started to terminate
+    ConnectionClosing,
+    /// Message not allowed in current connection state
+    ProtocolViolation,
+    /// Datagram was not acknowledged after all retransmits
+    Unacknowledged,
+    /// Application message to peer, which stopped reading
+    SlowConsumer,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 8] = [
+        DropReason::QueueOverflow,
+        DropReason::UnknownPeer,
+        DropReason::UnknownProtocol,
+        DropReason::Malformed,
+        DropReason::ConnectionClosing,
+        DropReason::ProtocolViolation,
+        DropReason::Unacknowledged,
+        DropReason::SlowConsumer,
+    ];
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            DropReason::QueueOverflow => "queue_overflow",
+            DropReason::UnknownPeer => "unknown_peer",
+            DropReason::UnknownProtocol => "unknown_protocol",
+            DropReason::Malformed => "malformed",
+            DropReason::ConnectionClosing => "connection_closing",
+            DropReason::ProtocolViolation => "protocol_violation",
+            DropReason::Unacknowledged => "unacknowledged",
+            DropReason::SlowConsumer => "slow_consumer",
+        };
+        f.pad(s)
+    }
+}
+
+/// Upper bounds of frame size histogram buckets, last bucket is for larger frames
+pub const SIZE_BUCKETS: [usize; 8] = [64, 128, 256, 512, 1024, 4096, 16384, 65536];
+/// Frames bigger than this are logged, as they are likely pathological
+const LARGE_FRAME: usize = 1024 * 1024;
+
+/// Writes of frames to connections - each write is flushed, it can contain several frames
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FlushStats {
+    pub flushes: u64,
+    pub frames: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+impl FlushStats {
+    /// Mean time of write including flush in microseconds
+    pub fn mean_us(&self) -> f64 {
+        if self.flushes == 0 {
+            0.0
+        } else {
+            self.total_us as f64 / self.flushes as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Distribution of encoded frame sizes
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SizeHistogram {
+    pub count: u64,
+    pub total: u64,
+    pub max: usize,
+    /// Counts of frames in buckets given by SIZE_BUCKETS, plus one for larger
+    pub buckets: [u64; SIZE_BUCKETS.len() + 1],
+}
+
+impl SizeHistogram {
+    fn record(&mut self, size: usize) {
+        self.count += 1;
+        self.total += size as u64;
+        self.max = self.max.max(size);
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|b| size <= *b)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count as f64
+        }
+    }
+}
+
+/// Protocol errors caused by peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub enum ProtocolErrorKind {
+    HandshakeTimeout,
+    InvalidHandshake,
+    /// Peer has different id than we dialed
+    UnexpectedId,
+    /// Message not allowed in connection state
+    Violation,
+    /// Frame could not be decoded
+    Malformed,
+}
+
+impl fmt::Display for ProtocolErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ProtocolErrorKind::HandshakeTimeout => "handshake_timeout",
+            ProtocolErrorKind::InvalidHandshake => "invalid_handshake",
+            ProtocolErrorKind::UnexpectedId => "unexpected_id",
+            ProtocolErrorKind::Violation => "violation",
+            ProtocolErrorKind::Malformed => "malformed",
+        };
+        f.pad(s)
+    }
+}
+
+/// How many protocol errors are logged per peer address in ERROR_LOG_WINDOW, rest is only counted
+const ERROR_LOG_BURST: u32 = 5;
+const ERROR_LOG_WINDOW: Duration = Duration::from_secs(60);
+/// Minimal interval between summaries of suppressed errors
+const ERROR_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+/// Max. tracked peer addresses, errors from other addresses are not logged when it is reached
+const ERROR_LOG_MAX_PEERS: usize = 10_000;
+
+/// Rate limiting of protocol error logging, so misbehaving peers cannot flood log
+struct ErrorLog {
+    totals: BTreeMap<ProtocolErrorKind, u64>,
+    /// Start of log window and number of errors logged in it by peer address
+    logged: HashMap<IpAddr, (Instant, u32)>,
+    suppressed: BTreeMap<ProtocolErrorKind, u64>,
+    last_summary: Instant,
+}
+
+impl ErrorLog {
+    fn new(now: Instant) -> Self {
+        ErrorLog {
+            totals: BTreeMap::new(),
+            logged: HashMap::new(),
+            suppressed: BTreeMap::new(),
+            last_summary: now,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.logged
+            .retain(|_, (start, _)| now.saturating_duration_since(*start) < ERROR_LOG_WINDOW);
+    }
+
+    /// Counts error, returns true if it should be logged
+    fn record(&mut self, kind: ProtocolErrorKind, ip: IpAddr, now: Instant) -> bool {
+        *self.totals.entry(kind).or_default() += 1;
+        // expired addresses are forgotten by periodic summary
+        if !self.logged.contains_key(&ip) && self.logged.len() >= ERROR_LOG_MAX_PEERS {
+            *self.suppressed.entry(kind).or_default() += 1;
+            return false;
+        }
+        let (start, count) = self.logged.entry(ip).or_insert((now, 0));
+        if now.saturating_duration_since(*start) >= ERROR_LOG_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        if *count < ERROR_LOG_BURST {
+            *count += 1;
+            true
+        } else {
+            *self.suppressed.entry(kind).or_default() += 1;
+            false
+        }
+    }
+
+    /// Summary of errors suppressed since last summary, at most once per ERROR_SUMMARY_INTERVAL,
+    /// also forgets expired peer addresses, so it should be called periodically
+    fn summary(&mut self, now: Instant) -> Option<String> {
+        if self.suppressed.is_empty()
+            || now.saturating_duration_since(self.last_summary) < ERROR_SUMMARY_INTERVAL
+        {
+            self.expire(now);
+            return None;
+        }
+        self.last_summary = now;
+        let limited = self
+            .logged
+            .values()
+            .filter(|(_, count)| *count >= ERROR_LOG_BURST)
+            .count();
+        self.expire(now);
+        let kinds: Vec<String> = std::mem::take(&mut self.suppressed)
+            .into_iter()
+            .map(|(kind, n)| format!("{}={}", kind, n))
+            .collect();
+        Some(format!(
+            "protocol_errors_suppressed peers={} {}",
+            limited,
+            kinds.join(" ")
+        ))
+    }
+}
+
+lazy_static! {
+    static ref ERROR_LOG: Mutex<ErrorLog> = Mutex::new(ErrorLog::new(clock::now()));
+    static ref DROPPED: Vec<AtomicU64> = DropReason::ALL.iter().map(|_| AtomicU64::new(0)).collect();
+    static ref FRAME_SIZES: Mutex<HashMap<(&'static str, Direction), SizeHistogram>> =
+        Mutex::new(HashMap::new());
+    static ref FLUSHES: Mutex<FlushStats> = Mutex::new(FlushStats::default());
+}
+
+static SHED: AtomicU64 = AtomicU64::new(0);
+
+/// Counts dropped message and emits MessageDropped event of node, which dropped it
+pub(crate) fn record_drop(events: &Events, reason: DropReason, peer: Option<SocketAddr>) {
+    DROPPED[reason as usize].fetch_add(1, Ordering::Relaxed);
+    events.emit(NodeEvent::MessageDropped { reason, peer });
+}
+
+/// Counts protocol error caused by peer and logs it, unless peer's address exceeded its log rate
+pub(crate) fn record_protocol_error(
+    kind: ProtocolErrorKind,
+    peer: SocketAddr,
+    detail: &dyn fmt::Display,
+) {
+    if ERROR_LOG.lock().unwrap().record(kind, peer.ip(), clock::now()) {
+        warn!("protocol_error kind={} peer={} detail=\"{}\"", kind, peer, detail);
+    }
+}
+
+/// Logs summary of suppressed protocol errors, if it is due
+pub(crate) fn log_protocol_error_summary() {
+    if let Some(summary) = ERROR_LOG.lock().unwrap().summary(clock::now()) {
+        warn!("{}", summary);
+    }
+}
+
+/// Number of protocol errors caused by peers by kind
+pub fn protocol_error_counts() -> Vec<(ProtocolErrorKind, u64)> {
+    ERROR_LOG
+        .lock()
+        .unwrap()
+        .totals
+        .iter()
+        .map(|(k, n)| (*k, *n))
+        .collect()
+}
+
+/// Counts incoming connection rejected as busy, because of too many pending handshakes
+pub(crate) fn record_shed() {
+    SHED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of incoming connections rejected as busy
+pub fn shed_connections() -> u64 {
+    SHED.load(Ordering::Relaxed)
+}
+
+/// Records size of encoded frame of given message kind
+pub(crate) fn record_frame_size(kind: &'static str, direction: Direction, size: usize) {
+    if size > LARGE_FRAME {
+        warn!("Large {:?} frame {} of {} bytes", direction, kind, size);
+    }
+    FRAME_SIZES
+        .lock()
+        .unwrap()
+        .entry((kind, direction))
+        .or_default()
+        .record(size)
+}
+
+/// Records write of frames to connection, which took `elapsed` until flushed
+pub(crate) fn record_flush(frames: usize, elapsed: Duration) {
+    let us = elapsed.as_micros() as u64;
+    let mut f = FLUSHES.lock().unwrap();
+    f.flushes += 1;
+    f.frames += frames as u64;
+    f.total_us += us;
+    f.max_us = f.max_us.max(us);
+}
+
+pub fn flush_stats() -> FlushStats {
+    FLUSHES.lock().unwrap().clone()
+}
+
+/// Frame size histograms by message kind and direction
+pub fn frame_sizes() -> Vec<(&'static str, Direction, SizeHistogram)> {
+    let mut sizes: Vec<_> = FRAME_SIZES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((kind, dir), h)| (*kind, *dir, h.clone()))
+        .collect();
+    sizes.sort_by_key(|(kind, dir, _)| (*kind, *dir));
+    sizes
+}
+
+/// Number of dropped messages for each reason
+pub fn dropped_counts() -> Vec<(DropReason, u64)> {
+    DropReason::ALL
+        .iter()
+        .map(|r| (*r, DROPPED[*r as usize].load(Ordering::Relaxed)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_log() {
+        let now = Instant::now();
+        let mut log = ErrorLog::new(now);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..ERROR_LOG_BURST {
+            assert!(log.record(ProtocolErrorKind::Malformed, a, now));
+        }
+        assert!(!log.record(ProtocolErrorKind::Malformed, a, now));
+        assert!(!log.record(ProtocolErrorKind::Violation, a, now));
+        assert!(log.record(ProtocolErrorKind::Malformed, b, now));
+        assert_eq!(None, log.summary(now));
+
+        let later = now + ERROR_SUMMARY_INTERVAL;
+        assert_eq!(
+            Some("protocol_errors_suppressed peers=1 violation=1 malformed=1".to_string()),
+            log.summary(later)
+        );
+        assert_eq!(None, log.summary(later + ERROR_SUMMARY_INTERVAL));
+        assert!(log.record(ProtocolErrorKind::Malformed, a, later));
+        assert_eq!(Some(&(ERROR_LOG_BURST as u64 + 3)), log.totals.get(&ProtocolErrorKind::Malformed));
+    }
+
+    #[test]
+    fn test_error_log_bounded() {
+        let now = Instant::now();
+        let mut log = ErrorLog::new(now);
+        for i in 0..ERROR_LOG_MAX_PEERS as u32 {
+            assert!(log.record(ProtocolErrorKind::Malformed, IpAddr::from(i.to_be_bytes()), now));
+        }
+        let other: IpAddr = "10.255.255.255".parse().unwrap();
+        assert!(!log.record(ProtocolErrorKind::Malformed, other, now));
+        assert_eq!(ERROR_LOG_MAX_PEERS, log.logged.len());
+        // expired addresses are forgotten on summary tick, even with nothing suppressed
+        log.suppressed.clear();
+        assert_eq!(None, log.summary(now + ERROR_LOG_WINDOW));
+        assert!(log.logged.is_empty());
+        assert!(log.record(ProtocolErrorKind::Malformed, other, now + ERROR_LOG_WINDOW));
+    }
+}
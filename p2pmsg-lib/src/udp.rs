@@ -0,0 +1,564 @@
+//! Optional UDP transport for small latency-sensitive messages (presence, game state ...)
+//! with thin reliability layer - sequence numbers, acks with bitfield of previous
+//! datagrams, retransmits and in order delivery. Peers advertise UDP port in Hello,
+//! it is used only when both sides support it, otherwise messages go over TCP.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::clock;
+use crate::error::Error;
+use crate::events::Events;
+use crate::protocol::message::Message;
+use crate::raw::RawRouter;
+use crate::rtt::RttEstimator;
+use crate::telemetry::{record_drop, DropReason};
+
+/// Larger messages should go over TCP
+pub const MAX_DATAGRAM: usize = 1200;
+/// Space for sequence and ack fields in datagram
+const HEADER_RESERVE: usize = 100;
+/// How often retransmits and pending acks are checked
+const TICK: Duration = Duration::from_millis(50);
+const MAX_RETRIES: u8 = 5;
+/// When more datagrams are in flight peer is not reachable over UDP
+const MAX_UNACKED: usize = 256;
+const ACK_BITS: u64 = 32;
+/// How many datagrams can wait for missing one, then we give up on it, even if sender
+/// did not tell us to skip it
+const REORDER_WINDOW: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Datagram {
+    pub seq: u64,
+    /// Highest sequence received from other side
+    pub ack: Option<u64>,
+    /// Bit i set if datagram `ack - 1 - i` was received
+    pub ack_bits: u32,
+    /// All datagrams below this sequence were delivered or skipped by receiver, so also
+    /// duplicates older than `ack_bits` cover are acknowledged
+    #[serde(default)]
+    pub delivered: u64,
+    /// Sender does not retransmit datagrams below this sequence (they were acknowledged,
+    /// given up or cancelled), so receiver does not wait for them
+    #[serde(default)]
+    pub base: u64,
+    /// None for pure ack
+    pub msg: Option<Message>,
+}
+
+struct Unacked {
+    first_sent: Instant,
+    sent: Instant,
+    retries: u8,
+    msg: Message,
+}
+
+/// Message sent over UDP, which was not acknowledged by peer yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingMessage {
+    pub seq: u64,
+    /// Message type
+    pub kind: String,
+    /// Time since message was first sent
+    pub age: Duration,
+    pub retries: u8,
+}
+
+/// Reliability state of UDP channel with one peer
+pub struct ReliableChannel {
+    next_seq: u64,
+    unacked: BTreeMap<u64, Unacked>,
+    next_expected: u64,
+    out_of_order: BTreeMap<u64, Message>,
+    highest: Option<u64>,
+    ack_pending: bool,
+    /// Base moved because of given up or cancelled datagram, peer should learn it
+    base_pending: bool,
+    /// Retransmission timeout is derived from acks of datagrams, which were not retransmitted
+    rtt: RttEstimator,
+}
+
+impl ReliableChannel {
+    pub fn new() -> Self {
+        ReliableChannel {
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+            next_expected: 0,
+            out_of_order: BTreeMap::new(),
+            highest: None,
+            ack_pending: false,
+            base_pending: false,
+            rtt: RttEstimator::new(),
+        }
+    }
+
+    fn is_received(&self, seq: u64) -> bool {
+        seq < self.next_expected || self.out_of_order.contains_key(&seq)
+    }
+
+    fn ack_fields(&self) -> (Option<u64>, u32) {
+        let highest = match self.highest {
+            Some(h) => h,
+            None => return (None, 0),
+        };
+        let mut bits = 0;
+        for i in 0..ACK_BITS.min(highest) {
+            if self.is_received(highest - 1 - i) {
+                bits |= 1 << i;
+            }
+        }
+        (Some(highest), bits)
+    }
+
+    /// Lowest sequence, which we still retransmit
+    fn base(&self) -> u64 {
+        self.unacked.keys().next().copied().unwrap_or(self.next_seq)
+    }
+
+    fn datagram(&mut self, seq: u64, msg: Option<Message>) -> Datagram {
+        let (ack, ack_bits) = self.ack_fields();
+        self.ack_pending = false;
+        self.base_pending = false;
+        Datagram {
+            seq,
+            ack,
+            ack_bits,
+            delivered: self.next_expected,
+            base: self.base(),
+            msg,
+        }
+    }
+
+    /// Datagram to send with new message, fails if too many datagrams are not acknowledged
+    pub fn send(&mut self, msg: Message, now: Instant) -> Result<Datagram, Error> {
+        if self.unacked.len() >= MAX_UNACKED {
+            return Err("Too many unacknowledged datagrams".into());
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.unacked.insert(
+            seq,
+            Unacked {
+                first_sent: now,
+                sent: now,
+                retries: 0,
+                msg: msg.clone(),
+            },
+        );
+        Ok(self.datagram(seq, Some(msg)))
+    }
+
+    /// Processes received datagram, returns messages, which can be delivered in order
+    pub fn on_datagram(&mut self, d: Datagram, now: Instant) -> Vec<Message> {
+        if let Some(ack) = d.ack {
+            let mut sample = None;
+            let acked = std::iter::once(ack).chain(
+                (0..ACK_BITS.min(ack))
+                    .filter(|i| d.ack_bits & (1 << i) != 0)
+                    .map(|i| ack - 1 - i),
+            );
+            for seq in acked {
+                if let Some(u) = self.unacked.remove(&seq) {
+                    // Karn's rule - ack of retransmitted datagram is ambiguous
+                    if u.retries == 0 && sample.is_none() {
+                        sample = Some(now.saturating_duration_since(u.first_sent));
+                    }
+                }
+            }
+            if let Some(rtt) = sample {
+                self.rtt.on_sample(rtt);
+            }
+        }
+        self.unacked = self.unacked.split_off(&d.delivered);
+        let mut delivered = vec![];
+        if d.base > self.next_expected {
+            // missing datagrams were given up by sender, received ones after them are delivered
+            let waiting = self.out_of_order.split_off(&d.base);
+            delivered.extend(std::mem::replace(&mut self.out_of_order, waiting).into_values());
+            self.next_expected = d.base;
+        }
+        if let Some(msg) = d.msg {
+            let seq = d.seq;
+            self.ack_pending = true;
+            self.highest = Some(self.highest.map_or(seq, |h| h.max(seq)));
+            // otherwise it is duplicate - our ack was lost
+            if seq >= self.next_expected {
+                self.out_of_order.insert(seq, msg);
+            }
+        }
+        if self.out_of_order.len() > REORDER_WINDOW {
+            // buffer is bounded, missing datagram is skipped
+            if let Some(&first) = self.out_of_order.keys().next() {
+                self.next_expected = first;
+            }
+        }
+        while let Some(m) = self.out_of_order.remove(&self.next_expected) {
+            delivered.push(m);
+            self.next_expected += 1;
+        }
+        delivered
+    }
+
+    /// Messages waiting for acknowledgement, oldest first
+    pub fn pending(&self, now: Instant) -> Vec<PendingMessage> {
+        self.unacked
+            .iter()
+            .map(|(seq, u)| PendingMessage {
+                seq: *seq,
+                kind: u.msg.kind().into(),
+                age: now.saturating_duration_since(u.first_sent),
+                retries: u.retries,
+            })
+            .collect()
+    }
+
+    /// Stops retransmitting message, peer is told to skip it.
+    /// Returns false if message is not pending (already acknowledged or given up)
+    pub fn cancel(&mut self, seq: u64) -> bool {
+        let cancelled = self.unacked.remove(&seq).is_some();
+        self.base_pending |= cancelled;
+        cancelled
+    }
+
+    /// Datagrams to be sent now - retransmits of unacknowledged ones or pure ack,
+    /// and number of messages given up after too many retries
+    pub fn poll(&mut self, now: Instant) -> (Vec<Datagram>, usize) {
+        let mut resend = vec![];
+        let mut lost = 0;
+        let rto = self.rtt.rto();
+        self.unacked.retain(|seq, u| {
+            if now.duration_since(u.sent) < rto {
+                return true;
+            }
+            if u.retries >= MAX_RETRIES {
+                lost += 1;
+                return false;
+            }
+            u.retries += 1;
+            u.sent = now;
+            resend.push((*seq, u.msg.clone()));
+            true
+        });
+        if !resend.is_empty() {
+            self.rtt.on_timeout();
+        }
+        self.base_pending |= lost > 0;
+        let mut datagrams: Vec<_> = resend
+            .into_iter()
+            .map(|(seq, msg)| self.datagram(seq, Some(msg)))
+            .collect();
+        if datagrams.is_empty() && (self.ack_pending || self.base_pending) {
+            datagrams.push(self.datagram(self.next_seq, None));
+        }
+        (datagrams, lost)
+    }
+}
+
+struct Channel {
+    /// Address of peer's TCP connection, under which its messages are delivered
+    peer: SocketAddr,
+    reliable: ReliableChannel,
+}
+
+/// UDP transport of one node, it is reset, when node stops
+#[derive(Clone, Default)]
+pub(crate) struct UdpTransport {
+    running: Arc<AtomicBool>,
+    socket: Arc<tokio::sync::Mutex<Option<SendHalf>>>,
+    /// Channels by peer's UDP address
+    channels: Arc<Mutex<HashMap<SocketAddr, Channel>>>,
+}
+
+impl UdpTransport {
+    async fn send_datagram(&self, to: SocketAddr, d: &Datagram) -> Result<(), Error> {
+        let data = serde_json::to_vec(d)?;
+        match self.socket.lock().await.as_mut() {
+            Some(socket) => {
+                socket.send_to(&data, &to).await?;
+                Ok(())
+            }
+            None => Err("UDP transport is not running".into()),
+        }
+    }
+
+    /// Starts using UDP with peer connected from `peer`, which listens for datagrams on `udp_addr`
+    pub(crate) fn add_peer(&self, peer: SocketAddr, udp_addr: SocketAddr) {
+        debug!("Using UDP transport with {} on {}", peer, udp_addr);
+        self.channels.lock().unwrap().insert(
+            udp_addr,
+            Channel {
+                peer,
+                reliable: ReliableChannel::new(),
+            },
+        );
+    }
+
+    pub(crate) fn remove_peer(&self, udp_addr: &SocketAddr) {
+        self.channels.lock().unwrap().remove(udp_addr);
+    }
+
+    /// Sends message to peer's UDP address, fails if message is too big or peer does not acknowledge
+    pub(crate) async fn send(&self, udp_addr: SocketAddr, msg: Message) -> Result<(), Error> {
+        if serde_json::to_vec(&msg)?.len() + HEADER_RESERVE > MAX_DATAGRAM {
+            return Err("Message too big for UDP".into());
+        }
+        let d = match self.channels.lock().unwrap().get_mut(&udp_addr) {
+            Some(c) => c.reliable.send(msg, clock::now())?,
+            None => return Err(format!("No UDP channel to {}", udp_addr).into()),
+        };
+        self.send_datagram(udp_addr, &d).await
+    }
+
+    pub(crate) fn pending(&self, udp_addr: &SocketAddr) -> Vec<PendingMessage> {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(udp_addr)
+            .map(|c| c.reliable.pending(clock::now()))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn cancel_pending(&self, udp_addr: &SocketAddr, seq: u64) -> bool {
+        self.channels
+            .lock()
+            .unwrap()
+            .get_mut(udp_addr)
+            .map(|c| c.reliable.cancel(seq))
+            .unwrap_or(false)
+    }
+
+    pub(crate) async fn bind(&self, addr: SocketAddr) -> Result<tokio::net::udp::RecvHalf, Error> {
+        let socket = UdpSocket::bind(addr).await?;
+        info!("UDP transport listening on {}", socket.local_addr()?);
+        let (recv, send) = socket.split();
+        *self.socket.lock().await = Some(send);
+        self.running.store(true, Ordering::Relaxed);
+        Ok(recv)
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Closes socket and forgets channels, so transport can be bound again when node is rerun
+    pub(crate) async fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.socket.lock().await.take();
+        self.channels.lock().unwrap().clear();
+    }
+
+    /// Receives datagrams from known peers and passes delivered messages to `tx`,
+    /// periodically retransmits and acknowledges
+    pub(crate) async fn run(
+        self,
+        mut recv: tokio::net::udp::RecvHalf,
+        mut tx: mpsc::Sender<(Message, SocketAddr)>,
+        raw: RawRouter,
+        events: Events,
+    ) -> Result<(), Error> {
+        let events = &events;
+        let channels = &self.channels;
+        let receiving = async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM * 2];
+            loop {
+                let (n, from) = recv.recv_from(&mut buf).await?;
+                let d: Datagram = match serde_json::from_slice(&buf[..n]) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        debug!("Invalid datagram from {}: {}", from, e);
+                        record_drop(events, DropReason::Malformed, None);
+                        continue;
+                    }
+                };
+                let delivered = match channels.lock().unwrap().get_mut(&from) {
+                    Some(c) => (c.peer, c.reliable.on_datagram(d, clock::now())),
+                    None => {
+                        debug!("Datagram from unknown peer {}", from);
+                        record_drop(events, DropReason::UnknownPeer, None);
+                        continue;
+                    }
+                };
+                let (peer, msgs) = delivered;
+                for m in msgs {
+                    if m.is_control() {
+                        // connection is controlled only over TCP
+                        record_drop(events, DropReason::ProtocolViolation, Some(peer));
+                    } else if matches!(m, Message::Raw { .. }) && !raw.try_reserve(peer) {
+                        // datagram loop is shared by all peers, so it cannot wait for one
+                        record_drop(events, DropReason::QueueOverflow, Some(peer));
+                    } else if tx.send((m, peer)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        let ticking = async {
+            let mut ticker = tokio::time::interval(TICK);
+            loop {
+                ticker.tick().await;
+                let mut to_send = vec![];
+                {
+                    let now = clock::now();
+                    let mut channels = channels.lock().unwrap();
+                    for (addr, c) in channels.iter_mut() {
+                        let (datagrams, lost) = c.reliable.poll(now);
+                        for _ in 0..lost {
+                            record_drop(events, DropReason::Unacknowledged, Some(c.peer));
+                        }
+                        to_send.extend(datagrams.into_iter().map(|d| (*addr, d)));
+                    }
+                }
+                for (addr, d) in to_send {
+                    self.send_datagram(addr, &d)
+                        .await
+                        .unwrap_or_else(|e| debug!("Cannot send datagram to {}: {}", addr, e));
+                }
+            }
+        };
+
+        let (res, _): (Result<(), Error>, ()) = futures::join!(receiving, ticking);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(n: u8) -> Message {
+        Message::Raw {
+            protocol: "test".into(),
+            data: vec![n],
+        }
+    }
+
+    fn data(m: &Message) -> u8 {
+        match m {
+            Message::Raw { data, .. } => data[0],
+            _ => panic!("unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_in_order_delivery() {
+        let now = Instant::now();
+        let mut a = ReliableChannel::new();
+        let mut b = ReliableChannel::new();
+        let d0 = a.send(msg(0), now).unwrap();
+        let d1 = a.send(msg(1), now).unwrap();
+        let d2 = a.send(msg(2), now).unwrap();
+        assert!(b.on_datagram(d1, now).is_empty());
+        let got: Vec<_> = b.on_datagram(d2, now).iter().map(data).collect();
+        assert!(got.is_empty());
+        let got: Vec<_> = b.on_datagram(d0.clone(), now).iter().map(data).collect();
+        assert_eq!(vec![0, 1, 2], got);
+        // duplicate is ignored
+        assert!(b.on_datagram(d0, now).is_empty());
+
+        // b acknowledges all in pure ack
+        let (datagrams, _) = b.poll(now);
+        assert_eq!(1, datagrams.len());
+        assert_eq!(Some(2), datagrams[0].ack);
+        assert_eq!(0b11, datagrams[0].ack_bits);
+        a.on_datagram(datagrams[0].clone(), now + Duration::from_millis(100));
+        assert!(a.unacked.is_empty());
+        // 100ms + 4 * 50ms
+        assert_eq!(Duration::from_millis(300), a.rtt.rto());
+    }
+
+    #[test]
+    fn test_retransmit() {
+        let now = Instant::now();
+        let mut a = ReliableChannel::new();
+        a.send(msg(0), now).unwrap();
+        assert!(a.poll(now).0.is_empty());
+        let mut t = now;
+        for _ in 0..MAX_RETRIES {
+            let rto = a.rtt.rto();
+            assert!(a.poll(t + rto - Duration::from_millis(1)).0.is_empty());
+            t += rto;
+            let (datagrams, lost) = a.poll(t);
+            assert_eq!(1, datagrams.len());
+            assert_eq!(0, datagrams[0].seq);
+            assert_eq!(0, lost);
+            // timer backs off
+            assert_eq!((rto * 2).min(crate::rtt::MAX_RTO), a.rtt.rto());
+        }
+        t += a.rtt.rto();
+        let (datagrams, lost) = a.poll(t);
+        assert_eq!(1, lost);
+        // peer is told to skip given up datagram
+        assert_eq!(1, datagrams.len());
+        assert!(datagrams[0].msg.is_none());
+        assert_eq!(1, datagrams[0].base);
+    }
+
+    #[test]
+    fn test_ack_old_duplicate() {
+        let now = Instant::now();
+        let mut a = ReliableChannel::new();
+        let mut b = ReliableChannel::new();
+        for i in 0..40 {
+            let d = a.send(msg(i), now).unwrap();
+            assert_eq!(1, b.on_datagram(d, now).len());
+        }
+        // acks of b are lost, a retransmits all, b acknowledges them
+        let (datagrams, _) = a.poll(now + a.rtt.rto());
+        assert_eq!(40, datagrams.len());
+        assert!(b.on_datagram(datagrams[0].clone(), now).is_empty());
+        let (acks, _) = b.poll(now);
+        assert_eq!(1, acks.len());
+        assert_eq!(Some(39), acks[0].ack);
+        a.on_datagram(acks[0].clone(), now);
+        assert!(a.unacked.is_empty());
+    }
+
+    #[test]
+    fn test_skip_given_up() {
+        let now = Instant::now();
+        let mut a = ReliableChannel::new();
+        let mut b = ReliableChannel::new();
+        a.send(msg(0), now).unwrap();
+        let d1 = a.send(msg(1), now).unwrap();
+        let d2 = a.send(msg(2), now).unwrap();
+        assert!(b.on_datagram(d1, now).is_empty());
+        assert!(b.on_datagram(d2, now).is_empty());
+        // b's ack is lost, a stops sending 0 - it tells b to skip it
+        assert!(a.cancel(0));
+        let (datagrams, _) = a.poll(now);
+        assert_eq!(1, datagrams.len());
+        assert_eq!(1, datagrams[0].base);
+        let got: Vec<_> = b.on_datagram(datagrams[0].clone(), now).iter().map(data).collect();
+        assert_eq!(vec![1, 2], got);
+    }
+
+    #[test]
+    fn test_pending() {
+        let now = Instant::now();
+        let mut a = ReliableChannel::new();
+        a.send(msg(0), now).unwrap();
+        a.send(msg(1), now).unwrap();
+        let rto = a.rtt.rto();
+        let t = now + rto;
+        a.poll(t);
+        let pending = a.pending(t);
+        assert_eq!(2, pending.len());
+        assert_eq!(0, pending[0].seq);
+        assert_eq!("Raw", pending[0].kind);
+        assert_eq!(rto, pending[0].age);
+        assert_eq!(1, pending[0].retries);
+        assert!(a.cancel(0));
+        assert!(!a.cancel(0));
+        assert_eq!(1, a.pending(t).len());
+        let (datagrams, _) = a.poll(t + a.rtt.rto());
+        assert_eq!(vec![1], datagrams.iter().map(|d| d.seq).collect::<Vec<_>>());
+    }
+}
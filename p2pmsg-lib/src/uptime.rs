@@ -0,0 +1,250 @@
+//! Availability of peers - when they were connected, relative to time this node was running,
+//! so reliable peers can be chosen e.g. as relays. History is kept for a week.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::protocol::id::FriendlyId;
+
+pub const DAY: u64 = 24 * 3600;
+pub const WEEK: u64 = 7 * DAY;
+
+/// Closed interval of availability, unix timestamps in seconds
+type Session = (u64, u64);
+
+#[derive(Default, Serialize, Deserialize)]
+struct History {
+    node: Vec<Session>,
+    peers: BTreeMap<FriendlyId, Vec<Session>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UptimeSummary {
+    pub id: FriendlyId,
+    /// Fraction of time in last day, when this node was running and peer was connected
+    pub day: f64,
+    pub week: f64,
+}
+
+#[derive(Default)]
+pub struct Uptime {
+    path: Option<PathBuf>,
+    history: History,
+    node_since: Option<u64>,
+    /// Start of current session and number of connections of connected peers
+    connected: BTreeMap<FriendlyId, (u64, usize)>,
+}
+
+fn covered(sessions: &[Session], open: Option<u64>, from: u64, now: u64) -> u64 {
+    sessions
+        .iter()
+        .cloned()
+        .chain(open.map(|start| (start, now)))
+        .map(|(start, end)| end.min(now).saturating_sub(start.max(from)))
+        .sum()
+}
+
+impl Uptime {
+    /// Loads history from JSON file, it is saved back when peers disconnect
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let history = if path.exists() {
+            serde_json::from_slice(&fs::read(path)?)?
+        } else {
+            History::default()
+        };
+        Ok(Uptime {
+            path: Some(path.to_owned()),
+            history,
+            ..Default::default()
+        })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(ref path) = self.path {
+            fs::write(path, serde_json::to_vec(&self.history)?)?;
+        }
+        Ok(())
+    }
+
+    fn expire(&mut self, now: u64) {
+        let from = now.saturating_sub(WEEK);
+        self.history.node.retain(|s| s.1 > from);
+        for sessions in self.history.peers.values_mut() {
+            sessions.retain(|s| s.1 > from);
+        }
+        self.history.peers.retain(|_, sessions| !sessions.is_empty());
+    }
+
+    pub fn node_started(&mut self, now: u64) {
+        self.node_since.get_or_insert(now);
+    }
+
+    pub fn node_stopped(&mut self, now: u64) -> Result<(), Error> {
+        let ids: Vec<_> = self.connected.keys().cloned().collect();
+        for id in ids {
+            if let Some((start, _)) = self.connected.remove(&id) {
+                self.history.peers.entry(id).or_default().push((start, now));
+            }
+        }
+        if let Some(start) = self.node_since.take() {
+            self.history.node.push((start, now));
+        }
+        self.expire(now);
+        self.save()
+    }
+
+    pub fn connected(&mut self, id: FriendlyId, now: u64) {
+        self.connected.entry(id).or_insert((now, 0)).1 += 1;
+    }
+
+    /// Peer's session ends with its last connection
+    pub fn disconnected(&mut self, id: &FriendlyId, now: u64) -> Result<(), Error> {
+        let start = match self.connected.get_mut(id) {
+            Some((start, count)) if *count <= 1 => *start,
+            Some((_, count)) => {
+                *count -= 1;
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        self.connected.remove(id);
+        self.history
+            .peers
+            .entry(id.clone())
+            .or_default()
+            .push((start, now));
+        self.expire(now);
+        self.save()
+    }
+
+    /// Fraction of time this node was running in last `period` seconds, when peer was connected
+    pub fn uptime(&self, id: &FriendlyId, period: u64, now: u64) -> f64 {
+        let from = now.saturating_sub(period);
+        let node = covered(&self.history.node, self.node_since, from, now);
+        if node == 0 {
+            return 0.0;
+        }
+        let peer = covered(
+            self.history.peers.get(id).map_or(&[][..], |s| &s[..]),
+            self.connected.get(id).map(|c| c.0),
+            from,
+            now,
+        );
+        (peer as f64 / node as f64).min(1.0)
+    }
+
+    /// Peers seen in last week, most available first
+    pub fn summaries(&self, now: u64) -> Vec<UptimeSummary> {
+        let mut ids: Vec<&FriendlyId> = self.history.peers.keys().collect();
+        ids.extend(self.connected.keys().filter(|id| !self.history.peers.contains_key(id)));
+        let mut summaries: Vec<_> = ids
+            .into_iter()
+            .map(|id| UptimeSummary {
+                id: id.clone(),
+                day: self.uptime(id, DAY, now),
+                week: self.uptime(id, WEEK, now),
+            })
+            .collect();
+        summaries.sort_by(|a, b| b.week.partial_cmp(&a.week).unwrap());
+        summaries
+    }
+}
+
+lazy_static! {
+    static ref UPTIME: Mutex<Uptime> = Mutex::new(Uptime::default());
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Loads persisted history, without it history is kept only in memory
+pub fn load_uptime(path: &Path) -> Result<(), Error> {
+    let loaded = Uptime::load(path)?;
+    let mut uptime = UPTIME.lock().unwrap();
+    let Uptime {
+        node_since,
+        connected,
+        ..
+    } = std::mem::take(&mut *uptime);
+    *uptime = Uptime {
+        node_since,
+        connected,
+        ..loaded
+    };
+    Ok(())
+}
+
+/// Uptime of peers seen in last week
+pub fn uptime_summaries() -> Vec<UptimeSummary> {
+    UPTIME.lock().unwrap().summaries(unix_now())
+}
+
+pub(crate) fn node_started() {
+    UPTIME.lock().unwrap().node_started(unix_now())
+}
+
+pub(crate) fn node_stopped() {
+    UPTIME
+        .lock()
+        .unwrap()
+        .node_stopped(unix_now())
+        .unwrap_or_else(|e| error!("Cannot save uptime: {}", e))
+}
+
+pub(crate) fn peer_connected(id: FriendlyId) {
+    UPTIME.lock().unwrap().connected(id, unix_now())
+}
+
+pub(crate) fn peer_disconnected(id: &FriendlyId) {
+    UPTIME
+        .lock()
+        .unwrap()
+        .disconnected(id, unix_now())
+        .unwrap_or_else(|e| error!("Cannot save uptime: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::id::RawId;
+
+    #[test]
+    fn test_uptime() {
+        let a: FriendlyId = RawId::new([1; 32]).into();
+        let b: FriendlyId = RawId::new([2; 32]).into();
+        let mut u = Uptime::default();
+        let t = 10 * WEEK;
+        u.node_started(t);
+        u.connected(a.clone(), t);
+        u.connected(b.clone(), t + 100);
+        // second connection to same peer
+        u.connected(a.clone(), t + 200);
+        u.disconnected(&a, t + 300).unwrap();
+        u.disconnected(&b, t + 300).unwrap();
+        assert_eq!(1.0, u.uptime(&a, DAY, t + 400));
+        assert_eq!(0.5, u.uptime(&b, DAY, t + 400));
+        u.disconnected(&a, t + 400).unwrap();
+        assert_eq!(0.5, u.uptime(&a, DAY, t + 800));
+
+        u.node_stopped(t + 800).unwrap();
+        // node was not running, so peer availability is not affected
+        let t = t + DAY;
+        u.node_started(t);
+        assert_eq!(0.25, u.uptime(&a, WEEK, t + 800));
+        assert_eq!(0.0, u.uptime(&a, DAY, t + 800));
+        let summaries = u.summaries(t + 800);
+        assert_eq!(vec![a.clone(), b], summaries.iter().map(|s| s.id.clone()).collect::<Vec<_>>());
+
+        u.node_stopped(t + 800).unwrap();
+        u.expire(t + WEEK);
+        assert_eq!(0.0, u.uptime(&a, WEEK, t + WEEK));
+    }
+}
@@ -0,0 +1,386 @@
+//! Webhooks - node events and frames of selected raw protocols are POSTed as JSON to HTTP
+//! endpoint, so simple automations do not need to embed the node.
+//!
+//! Body is `{"node": <our id>, "timestamp": <unix ms>, "event": <NodeEvent>}` or
+//! `{"node": .., "timestamp": .., "message": {"peer": .., "protocol": .., "data": [..]}}`.
+//! If secret is configured, body is signed with HMAC-SHA256 and signature is sent in
+//! `X-P2pmsg-Signature: sha256=<hex>` header. Failed deliveries are retried with exponential
+//! backoff, undeliverable posts are dropped.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::client::{self, Node};
+use crate::error::Error;
+
+const QUEUE_SIZE: usize = 256;
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE_HEAD: usize = 4096;
+pub const SIGNATURE_HEADER: &str = "X-P2pmsg-Signature";
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Endpoint, only plain `http://host[:port][/path]` is supported
+    pub url: String,
+    /// Filter expression (see `filter` module) of posted events, None posts no events
+    pub events: Option<String>,
+    /// Frames of these raw protocols are posted, handlers are registered for them
+    pub protocols: Vec<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    /// `host[:port]` as in URL, for Host header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Webhook URL {} must start with http://", s))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority.ends_with(']') => (
+                &authority[..i],
+                authority[i + 1..]
+                    .parse()
+                    .map_err(|_| format!("Invalid port in webhook URL {}", s))?,
+            ),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in webhook URL {}", s).into());
+        }
+        Ok(Endpoint {
+            authority: authority.into(),
+            host: host.trim_start_matches('[').trim_end_matches(']').into(),
+            port,
+            path: path.into(),
+        })
+    }
+}
+
+impl Endpoint {
+    fn request(&self, body: &str, secret: Option<&str>) -> String {
+        let signature = secret
+            .map(|k| {
+                format!(
+                    "{}: sha256={}\r\n",
+                    SIGNATURE_HEADER,
+                    sign(k.as_bytes(), body.as_bytes())
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            self.path,
+            self.authority,
+            body.len(),
+            signature,
+            body
+        )
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of data
+pub fn sign(key: &[u8], data: &[u8]) -> String {
+    sha256::hmac(key, data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// SHA-256 and HMAC (RFC 2104) - we need just these two, so no crypto crate is pulled in
+mod sha256 {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    const BLOCK: usize = 64;
+
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, c) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([c[0], c[1], c[2], c[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+
+    pub fn digest(data: &[u8]) -> [u8; 32] {
+        let mut state: [u32; 8] = [
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+            0x5be0cd19,
+        ];
+        let mut msg = data.to_vec();
+        msg.push(0x80);
+        while msg.len() % BLOCK != BLOCK - 8 {
+            msg.push(0);
+        }
+        msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+        for block in msg.chunks(BLOCK) {
+            compress(&mut state, block);
+        }
+        let mut out = [0u8; 32];
+        for (o, s) in out.chunks_mut(4).zip(state.iter()) {
+            o.copy_from_slice(&s.to_be_bytes());
+        }
+        out
+    }
+
+    pub fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut k = [0u8; BLOCK];
+        if key.len() > BLOCK {
+            k[..32].copy_from_slice(&digest(key));
+        } else {
+            k[..key.len()].copy_from_slice(key);
+        }
+        let mut inner: Vec<u8> = k.iter().map(|b| b ^ 0x36).collect();
+        inner.extend_from_slice(data);
+        let mut outer: Vec<u8> = k.iter().map(|b| b ^ 0x5c).collect();
+        outer.extend_from_slice(&digest(&inner));
+        digest(&outer)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Delivered,
+    Retry(String),
+    Rejected(u16),
+}
+
+fn status_outcome(status: u16) -> Outcome {
+    match status {
+        200..=299 => Outcome::Delivered,
+        408 | 429 | 500..=599 => Outcome::Retry(format!("status {}", status)),
+        _ => Outcome::Rejected(status),
+    }
+}
+
+async fn post(endpoint: &Endpoint, request: &str) -> Result<u16, Error> {
+    let mut socket = TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await?;
+    socket.write_all(request.as_bytes()).await?;
+    let mut head = Vec::with_capacity(512);
+    let mut chunk = [0u8; 512];
+    while !head.contains(&b'\n') {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..n]);
+        if head.len() > MAX_RESPONSE_HEAD {
+            return Err("response status line too long".into());
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    match (parts.next(), parts.next().and_then(|s| s.parse().ok())) {
+        (Some(v), Some(status)) if v.starts_with("HTTP/") => Ok(status),
+        _ => Err("invalid HTTP response".into()),
+    }
+}
+
+async fn deliver(endpoint: &Endpoint, secret: Option<&str>, body: String) {
+    let request = endpoint.request(&body, secret);
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let outcome = match tokio::time::timeout(REQUEST_TIMEOUT, post(endpoint, &request)).await {
+            Ok(Ok(status)) => status_outcome(status),
+            Ok(Err(e)) => Outcome::Retry(e.to_string()),
+            Err(_) => Outcome::Retry("timeout".into()),
+        };
+        match outcome {
+            Outcome::Delivered => return,
+            Outcome::Rejected(status) => {
+                warn!(
+                    "Webhook {}:{} rejected post with status {}",
+                    endpoint.host, endpoint.port, status
+                );
+                return;
+            }
+            Outcome::Retry(e) if attempt < MAX_ATTEMPTS => {
+                debug!("Webhook post failed ({}), retrying in {:?}", e, backoff);
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+            Outcome::Retry(e) => warn!(
+                "Webhook post to {}:{} dropped after {} attempts: {}",
+                endpoint.host, endpoint.port, MAX_ATTEMPTS, e
+            ),
+        }
+    }
+}
+
+fn envelope(node: &Node, key: &str, value: serde_json::Value) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let mut body = serde_json::json!({
+        "node": node.my_id().map(|id| id.to_string()),
+        "timestamp": timestamp,
+    });
+    body[key] = value;
+    body.to_string()
+}
+
+fn enqueue(queue: &mut mpsc::Sender<String>, body: String) {
+    if queue.try_send(body).is_err() {
+        warn!("Webhook queue is full, post dropped");
+    }
+}
+
+/// Starts posting events and messages of default node to webhook. Must be called within runtime.
+pub fn add_webhook(config: WebhookConfig) -> Result<(), Error> {
+    add_webhook_to(&client::default_node(), config)
+}
+
+/// Starts posting events and messages of given node to webhook, it is stopped with the node
+pub fn add_webhook_to(node: &Node, config: WebhookConfig) -> Result<(), Error> {
+    let endpoint: Endpoint = config.url.parse()?;
+    let events = match config.events {
+        Some(ref f) => Some(node.subscribe_events_filtered(f)?),
+        None => None,
+    };
+    let protocols = config
+        .protocols
+        .iter()
+        .map(|p| node.register_raw_protocol(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (queue, mut posts) = mpsc::channel::<String>(QUEUE_SIZE);
+    let secret = config.secret;
+    let supervisor = node.supervisor();
+    supervisor.spawn("webhook", async move {
+        while let Some(body) = posts.recv().await {
+            deliver(&endpoint, secret.as_deref(), body).await
+        }
+        Ok(())
+    });
+    if let Some(mut events) = events {
+        let (mut queue, node) = (queue.clone(), node.clone());
+        supervisor.spawn("webhook events", async move {
+            while let Some(event) = events.recv().await {
+                match serde_json::to_value(&event) {
+                    Ok(v) => enqueue(&mut queue, envelope(&node, "event", v)),
+                    Err(e) => error!("Cannot serialize event: {}", e),
+                }
+            }
+            Ok(())
+        });
+    }
+    for mut raw in protocols {
+        let (mut queue, node) = (queue.clone(), node.clone());
+        supervisor.spawn("webhook messages", async move {
+            while let Some((peer, data)) = raw.recv().await {
+                let msg = serde_json::json!({
+                    "peer": peer,
+                    "protocol": raw.name(),
+                    "data": data,
+                });
+                enqueue(&mut queue, envelope(&node, "message", msg));
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            sign(b"Jefe", b"what do ya want for nothing?")
+        );
+        // key longer than block is hashed first, RFC 4231 test case 6
+        assert_eq!(
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            sign(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )
+        );
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let e: Endpoint = "http://hooks.local:8080/p2p/in".parse().unwrap();
+        assert_eq!(
+            ("hooks.local", 8080, "/p2p/in"),
+            (&e.host[..], e.port, &e.path[..])
+        );
+        let e: Endpoint = "http://[::1]".parse().unwrap();
+        assert_eq!(("::1", 80, "/"), (&e.host[..], e.port, &e.path[..]));
+        assert!("https://example.com".parse::<Endpoint>().is_err());
+        assert!("http://:80/".parse::<Endpoint>().is_err());
+
+        let req = e.request("{}", Some("key"));
+        assert!(req.starts_with("POST / HTTP/1.1\r\n"));
+        assert!(req.contains(&format!(
+            "{}: sha256={}\r\n",
+            SIGNATURE_HEADER,
+            sign(b"key", b"{}")
+        )));
+        assert!(req.ends_with("\r\n\r\n{}"));
+        assert_eq!(Outcome::Delivered, status_outcome(204));
+        assert_eq!(Outcome::Rejected(404), status_outcome(404));
+        assert!(matches!(status_outcome(503), Outcome::Retry(_)));
+    }
+}
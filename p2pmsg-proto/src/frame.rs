@@ -0,0 +1,32 @@
+//! Framing - each message is one JSON document terminated by newline
+
+use crate::message::Message;
+
+/// Longer frames should be discarded, so peer cannot make us buffer unlimited data
+pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// Encodes message as frame including terminating newline
+pub fn encode(msg: &Message) -> Result<Vec<u8>, serde_json::Error> {
+    let mut frame = serde_json::to_vec(msg)?;
+    frame.push(b'\n');
+    Ok(frame)
+}
+
+/// Decodes frame, terminating newline is optional
+pub fn decode(frame: &[u8]) -> Result<Message, serde_json::Error> {
+    serde_json::from_slice(frame.strip_suffix(b"\n").unwrap_or(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        let frame = encode(&Message::Ping).unwrap();
+        assert_eq!(b"\"Ping\"\n", &frame[..]);
+        assert!(matches!(decode(&frame), Ok(Message::Ping)));
+        assert!(matches!(decode(b"\"Pong\""), Ok(Message::Pong)));
+        assert!(decode(b"{\"Ping\"\n").is_err());
+    }
+}
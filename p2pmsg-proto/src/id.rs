@@ -0,0 +1,186 @@
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+
+const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct RawId([u8; 32]);
+
+/// Human readable (base58) form of RawId, used on the wire and in user interfaces,
+/// deserialized only from valid id
+#[derive(Clone, Eq, PartialEq, Hash, Debug, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct FriendlyId(String);
+
+impl RawId {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        RawId(bytes)
+    }
+
+    /// Generates new random id - from OS random source if available
+    pub fn random() -> Self {
+        let mut bytes = [0u8; 32];
+        if !fill_from_os(&mut bytes) {
+            for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+                let mut h = RandomState::new().build_hasher();
+                h.write_usize(i);
+                h.write_u128(
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_nanos())
+                        .unwrap_or(0),
+                );
+                h.write_u32(std::process::id());
+                chunk.copy_from_slice(&h.finish().to_le_bytes());
+            }
+        }
+        RawId(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+#[cfg(unix)]
+fn fill_from_os(buf: &mut [u8]) -> bool {
+    use std::io::Read;
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(buf))
+        .is_ok()
+}
+
+#[cfg(not(unix))]
+fn fill_from_os(_buf: &mut [u8]) -> bool {
+    false
+}
+
+fn base58_encode(data: &[u8]) -> String {
+    let mut digits: Vec<u8> = Vec::with_capacity(data.len() * 138 / 100 + 1);
+    for &byte in data {
+        let mut carry = byte as u32;
+        for d in digits.iter_mut() {
+            carry += (*d as u32) << 8;
+            *d = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = data.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat_n(ALPHABET[0], zeros)
+        .chain(digits.iter().rev().map(|d| ALPHABET[*d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn base58_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(s.len());
+    for c in s.bytes() {
+        let mut carry = ALPHABET.iter().position(|a| *a == c)? as u32;
+        for b in bytes.iter_mut() {
+            carry += (*b as u32) * 58;
+            *b = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = s.bytes().take_while(|c| *c == ALPHABET[0]).count();
+    bytes.extend(std::iter::repeat_n(0, zeros));
+    bytes.reverse();
+    Some(bytes)
+}
+
+impl From<RawId> for FriendlyId {
+    fn from(id: RawId) -> Self {
+        FriendlyId(base58_encode(&id.0))
+    }
+}
+
+impl From<&RawId> for FriendlyId {
+    fn from(id: &RawId) -> Self {
+        FriendlyId(base58_encode(&id.0))
+    }
+}
+
+impl FriendlyId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn to_raw(&self) -> Option<RawId> {
+        let bytes = base58_decode(&self.0)?;
+        if bytes.len() != 32 {
+            return None;
+        }
+        let mut raw = [0u8; 32];
+        raw.copy_from_slice(&bytes);
+        Some(RawId(raw))
+    }
+}
+
+impl fmt::Display for FriendlyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for RawId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&base58_encode(&self.0))
+    }
+}
+
+impl FromStr for FriendlyId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = FriendlyId(s.to_string());
+        match id.to_raw() {
+            Some(_) => Ok(id),
+            None => Err(format!("Invalid id {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for FriendlyId {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base58() {
+        assert_eq!("", base58_encode(&[]));
+        assert_eq!("1112", base58_encode(&[0, 0, 0, 1]));
+        assert_eq!("StV1DL6CwTryKyV", base58_encode(b"hello world"));
+        assert_eq!(
+            b"hello world".to_vec(),
+            base58_decode("StV1DL6CwTryKyV").unwrap()
+        );
+        assert!(base58_decode("0OIl").is_none());
+
+        let id = RawId::random();
+        let fid: FriendlyId = id.clone().into();
+        assert_eq!(Some(id), fid.to_raw());
+        assert!("abc".parse::<FriendlyId>().is_err());
+        let json = serde_json::to_string(&fid).unwrap();
+        assert_eq!(fid, serde_json::from_str(&json).unwrap());
+        assert!(serde_json::from_str::<FriendlyId>("\"ééé\"").is_err());
+    }
+}
@@ -0,0 +1,10 @@
+//! Wire protocol of p2pmsg - message types, peer ids, connection state machine and framing.
+//! It does not depend on any async runtime, so other implementations can share it.
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod id;
+pub mod message;
+pub mod state;
+pub mod frame;
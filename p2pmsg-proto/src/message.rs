@@ -0,0 +1,154 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::id::FriendlyId;
+
+/// Version of wire protocol, sent in Hello
+pub const PROTOCOL_VERSION: u32 = 2;
+/// First protocol version understanding `PingSeq`
+pub const PING_SEQ_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub enum ErrorCode {
+    InvalidHandshake,
+    TooManyConnections,
+    NotAllowed,
+    ProtocolViolation,
+    /// Node is overloaded by incoming connections, try later
+    Busy,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ErrorCode::InvalidHandshake => "invalid handshake",
+            ErrorCode::TooManyConnections => "too many connections",
+            ErrorCode::NotAllowed => "connection not allowed",
+            ErrorCode::ProtocolViolation => "protocol violation",
+            ErrorCode::Busy => "busy",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Information peer advertises about itself in Hello
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct PeerInfo {
+    pub id: FriendlyId,
+    /// Addresses peer is listening on
+    pub addrs: Vec<SocketAddr>,
+    pub name: Option<String>,
+    pub uses_nat: bool,
+    /// Port on which peer accepts datagrams, if it supports UDP transport
+    #[serde(default)]
+    pub udp_port: Option<u16>,
+}
+
+/// Negotiation of realtime stream (audio, video, game state ...) carried by external stack,
+/// descriptions and candidates are opaque for us (e.g. SDP)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub enum Signal {
+    Offer { description: String },
+    Answer { description: String },
+    Candidate { candidate: String },
+    /// Session ended or offer declined
+    Hangup,
+}
+
+/// Message exchanged over connection, encoded as one JSON document per line
+// JSON Schema for other implementations is generated to schema/message.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub enum Message {
+    Hello {
+        msg: String,
+        info: PeerInfo,
+        /// Address from which we see the other side of connection
+        observed_addr: SocketAddr,
+        /// Not sent by peers older than protocol version 1
+        #[serde(default)]
+        version: Option<u32>,
+        /// Sender's wall clock time, unix milliseconds
+        #[serde(default)]
+        timestamp: Option<u64>,
+    },
+    Ping,
+    Pong,
+    /// Keepalive answered by `PongSeq` with same `seq`, so late answer is not taken for
+    /// answer of newer ping. Peers older than `PING_SEQ_VERSION` are sent `Ping`
+    PingSeq { seq: u32 },
+    PongSeq { seq: u32 },
+    Terminate,
+    /// Sent just before closing connection, so other side knows why it was rejected
+    ProtocolError { code: ErrorCode, detail: String },
+    /// Opaque data of extension protocol
+    Raw { protocol: String, data: Vec<u8> },
+    /// Realtime stream signaling, session is chosen by offering side
+    Signal { session: String, signal: Signal },
+    /// Text message, `to` is None for message to everyone, who receives it
+    Chat {
+        from: FriendlyId,
+        to: Option<FriendlyId>,
+        body: String,
+        /// Sender's wall clock time, unix milliseconds
+        ts: u64,
+    },
+}
+
+impl Message {
+    /// Name of message variant, e.g. for statistics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "Hello",
+            Message::Ping => "Ping",
+            Message::Pong => "Pong",
+            Message::PingSeq { .. } => "PingSeq",
+            Message::PongSeq { .. } => "PongSeq",
+            Message::Terminate => "Terminate",
+            Message::ProtocolError { .. } => "ProtocolError",
+            Message::Raw { .. } => "Raw",
+            Message::Signal { .. } => "Signal",
+            Message::Chat { .. } => "Chat",
+        }
+    }
+
+    /// Control messages just maintain connection, others carry application data
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Message::Hello { .. }
+                | Message::Ping
+                | Message::Pong
+                | Message::PingSeq { .. }
+                | Message::PongSeq { .. }
+                | Message::Terminate
+                | Message::ProtocolError { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/schema/message.json");
+
+    /// Published schema must match messages, run with UPDATE_SCHEMA=1 to regenerate it
+    #[test]
+    fn test_schema() {
+        let schema = schemars::schema_for!(Message);
+        let generated = serde_json::to_string_pretty(&schema).unwrap() + "\n";
+        if std::env::var_os("UPDATE_SCHEMA").is_some() {
+            std::fs::write(SCHEMA_PATH, &generated).unwrap();
+        }
+        let published = std::fs::read_to_string(SCHEMA_PATH).unwrap_or_default();
+        assert!(
+            published == generated,
+            "{} is out of date, run tests with UPDATE_SCHEMA=1 and review the change",
+            SCHEMA_PATH
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Explicit connection state machine - which messages are legal in which state.
+
+use std::fmt;
+
+use crate::message::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// We sent our Hello and wait for peer's one
+    AwaitingHello,
+    Established,
+    /// Terminate was sent or received, remaining messages are ignored
+    Terminating,
+    Closed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolViolation {
+    pub state: ConnectionState,
+    pub detail: &'static str,
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in state {:?}", self.detail, self.state)
+    }
+}
+
+impl std::error::Error for ProtocolViolation {}
+
+impl ConnectionState {
+    fn violation(self, detail: &'static str) -> Result<Self, ProtocolViolation> {
+        Err(ProtocolViolation {
+            state: self,
+            detail,
+        })
+    }
+
+    /// New state after receiving message from peer
+    pub fn on_received(self, msg: &Message) -> Result<Self, ProtocolViolation> {
+        use ConnectionState::*;
+        match (self, msg) {
+            (AwaitingHello, Message::Hello { .. }) => Ok(Established),
+            (AwaitingHello, Message::ProtocolError { .. }) => Ok(Closed),
+            (AwaitingHello, _) => self.violation("expected Hello"),
+            (Established, Message::Hello { .. }) => self.violation("duplicate Hello"),
+            (Established, Message::Terminate) => Ok(Terminating),
+            (Established, Message::ProtocolError { .. }) => Ok(Terminating),
+            (Established, _) => Ok(Established),
+            (Terminating, _) => Ok(Terminating),
+            (Closed, _) => self.violation("message on closed connection"),
+        }
+    }
+
+    /// New state when we decide to close connection
+    pub fn on_local_close(self) -> Self {
+        use ConnectionState::*;
+        match self {
+            AwaitingHello | Closed => Closed,
+            Established | Terminating => Terminating,
+        }
+    }
+
+    /// New state when peer closed its side of connection
+    pub fn on_stream_end(self) -> Self {
+        ConnectionState::Closed
+    }
+
+    /// If messages received in this state should be passed to application
+    pub fn delivers_messages(self) -> bool {
+        self == ConnectionState::Established
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionState::*;
+    use super::*;
+    use crate::id::RawId;
+    use crate::message::{ErrorCode, PeerInfo};
+
+    fn hello() -> Message {
+        Message::Hello {
+            msg: "hi".into(),
+            info: PeerInfo {
+                id: RawId::random().into(),
+                addrs: vec![],
+                name: None,
+                uses_nat: false,
+                udp_port: None,
+            },
+            observed_addr: "127.0.0.1:1".parse().unwrap(),
+            version: None,
+            timestamp: None,
+        }
+    }
+
+    fn error() -> Message {
+        Message::ProtocolError {
+            code: ErrorCode::NotAllowed,
+            detail: "go away".into(),
+        }
+    }
+
+    #[test]
+    fn test_awaiting_hello() {
+        assert_eq!(Ok(Established), AwaitingHello.on_received(&hello()));
+        assert_eq!(Ok(Closed), AwaitingHello.on_received(&error()));
+        assert!(AwaitingHello.on_received(&Message::Ping).is_err());
+        assert!(AwaitingHello.on_received(&Message::Terminate).is_err());
+        assert_eq!(Closed, AwaitingHello.on_local_close());
+        assert_eq!(Closed, AwaitingHello.on_stream_end());
+        assert!(!AwaitingHello.delivers_messages());
+    }
+
+    #[test]
+    fn test_established() {
+        assert!(Established.on_received(&hello()).is_err());
+        assert_eq!(Ok(Established), Established.on_received(&Message::Ping));
+        assert_eq!(Ok(Established), Established.on_received(&Message::Pong));
+        assert_eq!(Ok(Terminating), Established.on_received(&Message::Terminate));
+        assert_eq!(Ok(Terminating), Established.on_received(&error()));
+        assert_eq!(Terminating, Established.on_local_close());
+        assert_eq!(Closed, Established.on_stream_end());
+        assert!(Established.delivers_messages());
+    }
+
+    #[test]
+    fn test_terminating_and_closed() {
+        assert_eq!(Ok(Terminating), Terminating.on_received(&Message::Ping));
+        assert_eq!(Ok(Terminating), Terminating.on_received(&hello()));
+        assert_eq!(Terminating, Terminating.on_local_close());
+        assert_eq!(Closed, Terminating.on_stream_end());
+        assert!(!Terminating.delivers_messages());
+
+        assert!(Closed.on_received(&Message::Ping).is_err());
+        assert_eq!(Closed, Closed.on_local_close());
+        assert_eq!(Closed, Closed.on_stream_end());
+        assert!(!Closed.delivers_messages());
+    }
+}